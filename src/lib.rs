@@ -0,0 +1,85 @@
+//! WaterRower Library
+//!
+//! Communication with the S4 performance monitor of WaterRower rowing machines
+//! and recording of workout sessions to CSV files.
+//!
+//! - [`wr_transport`] opens the connection to an S4 (serial device, network
+//!   serial bridge or recorded trace).
+//! - [`wr_discovery`] finds the serial device an S4 is connected to.
+//! - [`wr_protocol`] and [`wr_codec`] parse and encode the S4's ASCII messages.
+//! - [`wr_session`] is the protocol state machine of a workout session. It does
+//!   no I/O itself, so it can be driven by blocking I/O, an async runtime or
+//!   tests with a fake clock.
+//! - [`wr_utils`] drives a workout session and holds the workout data model
+//!   ([`InstantWorkoutValues`], [`GlobalWorkoutValues`]) and its CSV writers.
+//! - `wr_async` (feature `async`) drives a workout session on tokio and
+//!   delivers its events as a stream.
+//! - [`wr_registers`] is the map of the S4 registers and their decoding, bundled
+//!   as TOML and extensible by user maps.
+//! - [`wr_columns`] defines the selectable columns of the workout data file and
+//!   the registers each of them is polled from.
+//! - [`wr_program`] programs distance, duration and interval workouts onto the
+//!   S4 and follows the progress through them.
+//! - [`wr_plan`] expands workout plans of warm-up, work and rest blocks and
+//!   cool-down, and sums up every interval rowed.
+//! - [`wr_segments`] detects work and rest segments in the samples of a
+//!   free-form workout.
+//! - [`wr_capture`] captures the communication of a workout session and replays
+//!   it with its original timing.
+//! - [`wr_recorder`] records a complete workout, or one after another as a
+//!   daemon, as done by the command line tool, and reports its progress to
+//!   [`WorkoutObserver`]s.
+//!
+//! A minimal recording loop looks like this:
+//!
+//! ```no_run
+//! use waterrower::{wr_transport, wr_utils};
+//! use std::time::Duration;
+//!
+//! # fn main() -> Result<(), waterrower::WaterRowerError> {
+//! let port = wr_transport::transport_open("/dev/ttyACM0")?;
+//! let mut ctx = wr_utils::workout_context_init(port, Duration::from_secs(2), None);
+//! wr_utils::start(&mut ctx)?;
+//! let mut gwv = wr_utils::global_workout_values_init(&mut ctx)?;
+//! wr_utils::wait_for_first_stroke(&mut ctx)?;
+//! loop {
+//!     let mut iwv = wr_utils::instant_workout_values_init();
+//!     wr_utils::workout_values_update(&mut ctx, &mut iwv, &mut gwv)?;
+//!     if let wr_utils::WorkoutState::Finished = ctx.state {
+//!         break;
+//!     }
+//!     println!("{} m", iwv.distance_in_meters);
+//! }
+//! wr_utils::stop(&mut ctx)?;
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(feature = "async")]
+pub mod wr_async;
+pub mod wr_capture;
+pub mod wr_codec;
+pub mod wr_columns;
+pub mod wr_discovery;
+pub mod wr_error;
+pub mod wr_plan;
+pub mod wr_program;
+pub mod wr_protocol;
+pub mod wr_pulses;
+pub mod wr_recorder;
+pub mod wr_registers;
+pub mod wr_segments;
+pub mod wr_session;
+#[cfg(unix)]
+pub mod wr_simulator;
+pub mod wr_strokes;
+pub mod wr_transport;
+pub mod wr_utils;
+
+pub use crate::{
+    wr_error::WaterRowerError,
+    wr_protocol::{S4Command, S4Message},
+    wr_recorder::WorkoutObserver,
+    wr_transport::Transport,
+    wr_utils::{DataFileWriter, GlobalWorkoutValues, InstantWorkoutValues, WorkoutContext},
+};
@@ -1,138 +1,606 @@
 //! WaterRower Command Line Tool
 
-mod wr_utils;
-
-use std::{fs, path::PathBuf, str};
+use std::{
+    error::Error,
+    path::PathBuf,
+    process, str,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time,
+};
 use structopt::StructOpt;
 
-use crate::wr_utils::InstantWorkoutValues;
+#[cfg(unix)]
+use waterrower::wr_simulator;
+use waterrower::{
+    wr_columns, wr_discovery, wr_plan, wr_program, wr_recorder, wr_registers,
+    wr_segments::{SegmentKind, WorkoutSegment},
+    wr_session, wr_utils, GlobalWorkoutValues, InstantWorkoutValues, WaterRowerError,
+    WorkoutObserver,
+};
 
 const DEFAULT_WORKOUT_DIR: &str = "./workouts";
+// Separate from recorded workouts, as a replay would overwrite the original
+const DEFAULT_REPLAY_DIR: &str = "./replays";
+const DEFAULT_INTERVAL: &str = "2";
+const DEFAULT_COLUMNS: &str = "default";
+const DEFAULT_PROGRAM_COLUMNS: &str = "default,interval,remaining,resting";
+// Shorter intervals leave the S4 no time to answer all requests
+const MIN_INTERVAL_IN_SECONDS: f64 = 0.2;
+#[cfg(unix)]
+const DEFAULT_SIMULATOR_PROFILE: &str = "600:120:24";
 
 #[derive(StructOpt)]
 #[structopt(name = "waterrower", about = "WaterRower Command Line Tool")]
 enum WaterRower {
     Record {
         /// Serial device for WaterRower communication
+        /// (or tcp://<host>:<port> for a network serial bridge, trace://<file> for a recorded trace,
+        /// auto to search for the WaterRower)
+        #[structopt(short, long)]
+        serial_dev: String,
+        /// Directory to store workouts' data
+        #[structopt(short, long, parse(from_os_str), default_value = DEFAULT_WORKOUT_DIR)]
+        workout_dir: PathBuf,
+        /// Seconds between two recorded datapoints (slowly changing values are polled less often)
+        #[structopt(short, long, parse(try_from_str = interval_parse), default_value = DEFAULT_INTERVAL)]
+        interval: time::Duration,
+        /// Records paddle pulses and the boat speed derived from them to pulses.csv
+        #[structopt(long)]
+        pulses: bool,
+        /// Captures the communication with the WaterRower to a file for replay
+        #[structopt(long, parse(from_os_str))]
+        capture: Option<PathBuf>,
+        /// Comma separated columns of workout_data.csv ("default", "all" or column keys)
+        #[structopt(long, default_value = DEFAULT_COLUMNS)]
+        columns: String,
+        /// Register map (TOML or .json) merged on top of the bundled one
+        #[structopt(long, parse(from_os_str))]
+        registers: Option<PathBuf>,
+        /// Workout plan (TOML or .json) to follow, summed up per interval in intervals.csv
+        #[structopt(long, parse(from_os_str))]
+        plan: Option<PathBuf>,
+        #[structopt(flatten)]
+        finish: FinishOptions,
+        /// Prints debug information during runtime
+        #[structopt(short, long)]
+        debug: bool,
+    },
+    /// Programs a workout onto the WaterRower and records it until completed
+    Program {
+        /// Workout, e.g. 2000m, 5km, 300strokes, 30:00, 4x500m/1:00 or 2000m/3:00,1000m/2:00
+        #[structopt(parse(try_from_str = wr_program::workout_program_parse))]
+        workout: wr_program::WorkoutProgram,
+        /// Serial device for WaterRower communication
+        /// (or tcp://<host>:<port> for a network serial bridge, trace://<file> for a recorded trace,
+        /// auto to search for the WaterRower)
+        #[structopt(short, long)]
+        serial_dev: String,
+        /// Directory to store workouts' data
+        #[structopt(short, long, parse(from_os_str), default_value = DEFAULT_WORKOUT_DIR)]
+        workout_dir: PathBuf,
+        /// Seconds between two recorded datapoints (slowly changing values are polled less often)
+        #[structopt(short, long, parse(try_from_str = interval_parse), default_value = DEFAULT_INTERVAL)]
+        interval: time::Duration,
+        /// Records paddle pulses and the boat speed derived from them to pulses.csv
+        #[structopt(long)]
+        pulses: bool,
+        /// Captures the communication with the WaterRower to a file for replay
+        #[structopt(long, parse(from_os_str))]
+        capture: Option<PathBuf>,
+        /// Comma separated columns of workout_data.csv ("default", "all" or column keys)
+        #[structopt(long, default_value = DEFAULT_PROGRAM_COLUMNS)]
+        columns: String,
+        /// Register map (TOML or .json) merged on top of the bundled one
+        #[structopt(long, parse(from_os_str))]
+        registers: Option<PathBuf>,
+        #[structopt(flatten)]
+        finish: FinishOptions,
+        /// Prints debug information during runtime
+        #[structopt(short, long)]
+        debug: bool,
+    },
+    /// Records one workout after another, waiting for the first stroke of each, until stopped
+    Daemon {
+        /// Serial device for WaterRower communication
+        /// (or tcp://<host>:<port> for a network serial bridge, auto to search for the WaterRower)
         #[structopt(short, long)]
         serial_dev: String,
         /// Directory to store workouts' data
         #[structopt(short, long, parse(from_os_str), default_value = DEFAULT_WORKOUT_DIR)]
         workout_dir: PathBuf,
+        /// Seconds between two recorded datapoints (slowly changing values are polled less often)
+        #[structopt(short, long, parse(try_from_str = interval_parse), default_value = DEFAULT_INTERVAL)]
+        interval: time::Duration,
+        /// Records paddle pulses and the boat speed derived from them to pulses.csv
+        #[structopt(long)]
+        pulses: bool,
+        /// Comma separated columns of workout_data.csv ("default", "all" or column keys)
+        #[structopt(long, default_value = DEFAULT_COLUMNS)]
+        columns: String,
+        /// Register map (TOML or .json) merged on top of the bundled one
+        #[structopt(long, parse(from_os_str))]
+        registers: Option<PathBuf>,
+        /// Workout plan (TOML or .json) every workout follows
+        #[structopt(long, parse(from_os_str))]
+        plan: Option<PathBuf>,
+        #[structopt(flatten)]
+        finish: FinishOptions,
+        /// Prints debug information during runtime
+        #[structopt(short, long)]
+        debug: bool,
+    },
+    /// Replays a captured recording, reproducing its workout data offline
+    Replay {
+        /// Capture file written by record --capture
+        #[structopt(parse(from_os_str))]
+        capture_file: PathBuf,
+        /// Directory to store the replayed workout's data
+        #[structopt(short, long, parse(from_os_str), default_value = DEFAULT_REPLAY_DIR)]
+        workout_dir: PathBuf,
+        /// Records paddle pulses and the boat speed derived from them to pulses.csv
+        #[structopt(long)]
+        pulses: bool,
+        /// Comma separated columns of workout_data.csv, limited to those recorded in the capture
+        #[structopt(long, default_value = DEFAULT_COLUMNS)]
+        columns: String,
+        /// Register map (TOML or .json) merged on top of the bundled one
+        #[structopt(long, parse(from_os_str))]
+        registers: Option<PathBuf>,
+        /// Workout the captured recording was programmed with
+        #[structopt(long, parse(try_from_str = wr_program::workout_program_parse))]
+        program: Option<wr_program::WorkoutProgram>,
+        /// Workout plan the captured recording followed
+        #[structopt(long, parse(from_os_str))]
+        plan: Option<PathBuf>,
+        #[structopt(flatten)]
+        finish: FinishOptions,
+        /// Prints debug information during runtime
+        #[structopt(short, long)]
+        debug: bool,
+    },
+    /// Rebuilds the meta data of a workout from its (partial) workout data
+    Recover {
+        /// Directory of the workout to recover
+        #[structopt(parse(from_os_str))]
+        workout_path: PathBuf,
+    },
+    /// Lists the serial ports, probing those with the USB IDs of the WaterRower
+    Ports,
+    /// Simulates an S4 performance monitor on a pseudo-terminal
+    #[cfg(unix)]
+    Simulate {
+        /// Workout segment as <seconds>:<seconds per 500m>:<strokes per minute>, can be repeated
+        #[structopt(short, long, parse(try_from_str = wr_simulator::simulator_segment_parse),
+                    default_value = DEFAULT_SIMULATOR_PROFILE)]
+        profile: Vec<wr_simulator::SimulatorSegment>,
+        /// Seconds between connection and first stroke
+        #[structopt(long, default_value = "5")]
+        start_delay: u64,
         /// Prints debug information during runtime
         #[structopt(short, long)]
         debug: bool,
     },
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    match WaterRower::from_args() {
-        WaterRower::Record {
-            serial_dev,
-            workout_dir,
-            debug,
-        } => {
-            println!("\n### Initializing WaterRower workout recording ...");
+/// When a workout is finished, besides Ctrl-C
+#[derive(StructOpt)]
+struct FinishOptions {
+    /// Ends the workout when the WaterRower's clock stood still this many seconds (0 to ignore)
+    ///
+    /// The clock stops during a pause, so pauses up to this long are kept as part of the workout.
+    #[structopt(long)]
+    clock_stopped: Option<u64>,
+    /// Ends the workout after this many seconds without distance or strokes
+    #[structopt(long)]
+    inactivity: Option<u64>,
+    /// Ends the workout after this many seconds without a stroke
+    #[structopt(long)]
+    no_strokes: Option<u64>,
+    /// Pauses the workout after this many seconds without a stroke, until the next stroke
+    #[structopt(long)]
+    pause_after: Option<u64>,
+    /// Ends the workout when this distance in meters is reached
+    #[structopt(long)]
+    target_distance: Option<u32>,
+    /// Ends the workout when the WaterRower's clock reaches this time, e.g. 30:00 or 1800s
+    #[structopt(long, parse(try_from_str = wr_program::workout_duration_parse))]
+    target_time: Option<u32>,
+    /// Keeps recording when the WaterRower is reset
+    #[structopt(long)]
+    ignore_reset: bool,
+}
 
-            if debug {
-                println!("--- Initializing workout context ...");
+impl FinishOptions {
+    // Limits not given keep the defaults of the session
+    fn criteria(&self) -> wr_session::FinishCriteria {
+        let default = wr_session::FinishCriteria::default();
+        wr_session::FinishCriteria {
+            clock_stopped: match self.clock_stopped {
+                Some(seconds) => Some(time::Duration::from_secs(seconds)),
+                None => default.clock_stopped,
             }
-            let mut workout_context = wr_utils::workout_context_init(serial_dev.as_str(), debug);
+            .filter(|limit| !limit.is_zero()),
+            inactivity: self.inactivity.map(time::Duration::from_secs),
+            no_strokes: self.no_strokes.map(time::Duration::from_secs),
+            pause_after: self
+                .pause_after
+                .map_or(default.pause_after, time::Duration::from_secs),
+            target_distance_in_meters: self.target_distance,
+            target_time_in_seconds: self.target_time,
+            reset: !self.ignore_reset,
+        }
+    }
+}
 
-            if debug {
-                println!("--- Starting WaterRower communication ...");
-            }
-            wr_utils::start(&mut workout_context);
+fn interval_parse(s: &str) -> Result<time::Duration, String> {
+    let seconds: f64 = s
+        .parse()
+        .map_err(|_| format!("Invalid interval '{}', expected seconds", s))?;
+    if seconds.is_nan() || seconds < MIN_INTERVAL_IN_SECONDS {
+        return Err(format!(
+            "Interval must be at least {} seconds",
+            MIN_INTERVAL_IN_SECONDS
+        ));
+    }
+    Ok(time::Duration::from_secs_f64(seconds))
+}
 
-            if debug {
-                println!("--- Initializing global workout values ...");
-            }
-            let mut global_workout_values =
-                wr_utils::global_workout_values_init(&mut workout_context);
+fn debug_print(message: &str) {
+    println!("{}", message);
+}
 
-            // Print basic workout information
-            println!(
-                "--- Date and Time of Start:    {}",
-                global_workout_values.date_time_start
-            );
-            println!(
-                "--- WaterRower Model:          {}",
-                global_workout_values.model
-            );
-            println!(
-                "--- Firmware Version:          {}",
-                global_workout_values.fw_version
-            );
+fn debug_trace(debug: bool) -> Option<wr_utils::DebugTrace> {
+    if debug {
+        Some(debug_print)
+    } else {
+        None
+    }
+}
 
-            if debug {
-                println!("--- Creating workout directory ...");
-            }
-            let workout_path = PathBuf::from(format!(
-                "{}{}{}",
-                &workout_dir.to_str().unwrap(),
-                "/",
-                global_workout_values
-                    .date_time_start
-                    .replace(" ", "_")
-                    .replace(":", "-")
-            ));
-            fs::create_dir_all(&workout_path)?;
-
-            println!("\n### Waiting for first stroke on WaterRower to begin ...");
-            wr_utils::wait_for_first_stroke(&mut workout_context);
-            println!("--- Detected!");
-
-            println!("\n### Recording workout ...");
-            let mut datapoints: Vec<InstantWorkoutValues> = Vec::new();
-
-            loop {
-                let mut instant_workout_values = wr_utils::instant_workout_values_init();
-
-                // Get current workout values
-                wr_utils::workout_values_update(
-                    &mut workout_context,
-                    &mut instant_workout_values,
-                    &mut global_workout_values,
-                );
-
-                // Check if workout finished
-                if let wr_utils::WorkoutState::Finished = workout_context.state {
-                    break;
-                }
-
-                // Append values to datapoint vector
-                datapoints.push(instant_workout_values);
-            }
+// Searches for the WaterRower once, so the device it was found at can be shown
+fn serial_dev_resolve(serial_dev: String) -> Result<String, WaterRowerError> {
+    if serial_dev != wr_discovery::SERIAL_DEV_AUTO {
+        return Ok(serial_dev);
+    }
+    let found = wr_discovery::s4_discover()?;
+    println!("--- WaterRower found at:       {}", found);
+    Ok(found)
+}
 
-            println!("\n### Closing WaterRower workout session ...");
-            wr_utils::stop(&mut workout_context);
+// Shows the progress of a workout recording
+struct Console {
+    // Directory the workouts are stored in, none if a workout is recovered
+    workout_dir: Option<PathBuf>,
+    intervals: Vec<wr_plan::PlanInterval>,
+    last_interval_number: u32,
+    segments: (usize, usize),
+}
 
-            if debug {
-                println!("--- Finalizing global workout values ...");
-            }
-            wr_utils::global_workout_values_finalize(&datapoints, &mut global_workout_values);
+fn console_init(config: &wr_recorder::RecordConfig) -> Console {
+    let plan = match (config.plan.as_ref(), config.program.as_ref()) {
+        (Some(plan), _) => Some(plan.clone()),
+        (None, Some(program)) => Some(wr_plan::workout_plan_from_program(program)),
+        (None, None) => None,
+    };
+    Console {
+        workout_dir: Some(config.workout_dir.clone()),
+        intervals: plan.map_or_else(Vec::new, |plan| plan.intervals()),
+        last_interval_number: 0,
+        segments: (0, 0),
+    }
+}
 
+impl WorkoutObserver for Console {
+    fn on_connected(&mut self, gwv: &GlobalWorkoutValues) -> Result<(), WaterRowerError> {
+        println!("--- Date and Time of Start:    {}", gwv.date_time_start);
+        println!("--- WaterRower Model:          {}", gwv.model);
+        println!("--- Firmware Version:          {}", gwv.fw_version);
+        if !gwv.workout_program.is_empty() {
+            println!("--- Workout Program:           {}", gwv.workout_program);
+        }
+        Ok(())
+    }
+
+    fn on_waiting(&mut self) -> Result<(), WaterRowerError> {
+        println!("\n### Waiting for first stroke on WaterRower to begin ...");
+        Ok(())
+    }
+
+    fn on_first_stroke(&mut self) -> Result<(), WaterRowerError> {
+        println!("--- Detected!");
+        println!("\n### Recording workout ...");
+        Ok(())
+    }
+
+    fn on_sample(&mut self, iwv: &InstantWorkoutValues) -> Result<(), WaterRowerError> {
+        let number = iwv.interval_number;
+        if iwv.interval_resting || number == self.last_interval_number {
+            return Ok(());
+        }
+        if let Some(plan_interval) = (number as usize)
+            .checked_sub(1)
+            .and_then(|i| self.intervals.get(i))
+        {
             println!(
-                "--- Date and Time of End:      {}",
-                global_workout_values.date_time_end
-            );
-            println!(
-                "--- Workout Duration:          {:02}:{:02}:{:02}",
-                global_workout_values.total_time_in_seconds / 3600,
-                global_workout_values.total_time_in_seconds % 3600 / 60,
-                global_workout_values.total_time_in_seconds % 3600 % 60,
+                "--- Interval {}/{}: {}, {}",
+                number,
+                self.intervals.len(),
+                plan_interval.label,
+                plan_interval.interval
             );
+        }
+        self.last_interval_number = number;
+        Ok(())
+    }
+
+    fn on_paused(&mut self) -> Result<(), WaterRowerError> {
+        println!("--- Workout paused");
+        Ok(())
+    }
+
+    fn on_resumed(&mut self) -> Result<(), WaterRowerError> {
+        println!("--- Workout resumed");
+        Ok(())
+    }
+
+    fn on_warning(&mut self, warning: &WaterRowerError) -> Result<(), WaterRowerError> {
+        eprintln!("!!! {}", warning);
+        Ok(())
+    }
+
+    fn on_segments(&mut self, segments: &[WorkoutSegment]) -> Result<(), WaterRowerError> {
+        let count = |kind| segments.iter().filter(|s| s.kind == kind).count();
+        self.segments = (count(SegmentKind::Work), count(SegmentKind::Rest));
+        Ok(())
+    }
+
+    fn on_connection_lost(&mut self, error: &WaterRowerError) -> Result<(), WaterRowerError> {
+        eprintln!("!!! {}, reconnecting ...", error);
+        Ok(())
+    }
+
+    fn on_finished(&mut self, gwv: &GlobalWorkoutValues) -> Result<(), WaterRowerError> {
+        println!("\n### Closing WaterRower workout session ...");
+        println!("--- Date and Time of End:      {}", gwv.date_time_end);
+        println!("--- Finish Reason:             {}", gwv.finish_reason);
+        println!("--- Number of Data Points:     {}", gwv.datapoints);
+        println!(
+            "--- Workout Duration:          {:02}:{:02}:{:02}",
+            gwv.total_time_in_seconds / 3600,
+            gwv.total_time_in_seconds % 3600 / 60,
+            gwv.total_time_in_seconds % 3600 % 60,
+        );
+        println!(
+            "--- Total Distance in Meters:  {}",
+            gwv.total_distance_in_meters
+        );
+        println!(
+            "--- Segments:                  {} work, {} rest",
+            self.segments.0, self.segments.1
+        );
+        if let Some(workout_dir) = self.workout_dir.as_ref() {
             println!(
-                "--- Total Distance in Meters:  {}",
-                global_workout_values.total_distance_in_meters
+                "--- Recorded to:               {}",
+                workout_dir.join(wr_utils::workout_dir_name(gwv)).display()
             );
+        }
+        Ok(())
+    }
+}
 
-            println!("\n### Writing workout data and meta data to CSV files ...");
-            wr_utils::write_meta_data_file(&workout_path, &global_workout_values)?;
-            wr_utils::write_workout_data_file(&workout_path, &datapoints)?;
+fn workout_plan_open(
+    plan_file: Option<PathBuf>,
+) -> Result<Option<wr_plan::WorkoutPlan>, waterrower::WaterRowerError> {
+    plan_file
+        .map(|plan_file| wr_plan::workout_plan_load(&plan_file))
+        .transpose()
+}
 
+fn register_map_open(
+    map_file: Option<PathBuf>,
+) -> Result<wr_registers::RegisterMap, waterrower::WaterRowerError> {
+    match map_file {
+        Some(map_file) => wr_registers::register_map_load(&map_file),
+        None => Ok(wr_registers::register_map_bundled()),
+    }
+}
+
+// First request finishes the workout gracefully, a second one exits immediately
+fn stop_handler_install(stop_requested: Arc<AtomicBool>) -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(move || {
+        if stop_requested.swap(true, Ordering::SeqCst) {
+            eprintln!("\n!!! Forced exit, meta data can be rebuilt with the recover command");
+            process::exit(130);
+        }
+        println!("\n### Stop requested, finishing workout (press Ctrl-C again to force exit) ...");
+    })
+}
+
+fn record(config: &wr_recorder::RecordConfig) -> Result<(), Box<dyn Error>> {
+    let stop_requested = Arc::new(AtomicBool::new(false));
+    stop_handler_install(Arc::clone(&stop_requested))?;
+    println!("\n### Initializing WaterRower workout recording ...");
+    let mut console = console_init(config);
+    wr_recorder::workout_record(config, stop_requested, &mut [&mut console])?;
+    println!("\n### Bye!");
+    Ok(())
+}
+
+fn ports_list() -> Result<(), waterrower::WaterRowerError> {
+    println!("\n### Searching for WaterRower serial ports ...");
+    let ports = wr_discovery::serial_ports_list()?;
+    if ports.is_empty() {
+        println!("--- No serial ports found");
+    }
+    for port in ports.iter() {
+        let mut description = match (port.usb_ids, port.product.as_ref()) {
+            (Some((vid, pid)), Some(product)) => format!("USB {:04X}:{:04X} {}", vid, pid, product),
+            (Some((vid, pid)), None) => format!("USB {:04X}:{:04X}", vid, pid),
+            (None, _) => String::from("not USB"),
+        };
+        if port.s4_ids() {
+            match wr_discovery::serial_port_probe(&port.name) {
+                Ok(()) => description.push_str(", WaterRower answered"),
+                Err(e) => description.push_str(&format!(", no answer ({})", e)),
+            }
+        }
+        println!("--- {:<27}{}", port.name, description);
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("!!! {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    match WaterRower::from_args() {
+        WaterRower::Record {
+            serial_dev,
+            workout_dir,
+            interval,
+            pulses,
+            capture,
+            columns,
+            registers,
+            plan,
+            finish,
+            debug,
+        } => {
+            let registers = register_map_open(registers)?;
+            record(&wr_recorder::RecordConfig {
+                serial_dev: serial_dev_resolve(serial_dev)?,
+                workout_dir,
+                interval,
+                pulses,
+                capture,
+                columns: wr_columns::workout_data_columns_parse(&columns, &registers)?,
+                registers,
+                program: None,
+                plan: workout_plan_open(plan)?,
+                finish: finish.criteria(),
+                debug: debug_trace(debug),
+            })?;
+        }
+        WaterRower::Program {
+            workout,
+            serial_dev,
+            workout_dir,
+            interval,
+            pulses,
+            capture,
+            columns,
+            registers,
+            finish,
+            debug,
+        } => {
+            let registers = register_map_open(registers)?;
+            record(&wr_recorder::RecordConfig {
+                serial_dev: serial_dev_resolve(serial_dev)?,
+                workout_dir,
+                interval,
+                pulses,
+                capture,
+                columns: wr_columns::workout_data_columns_parse(&columns, &registers)?,
+                registers,
+                program: Some(workout),
+                plan: None,
+                finish: finish.criteria(),
+                debug: debug_trace(debug),
+            })?;
+        }
+        WaterRower::Daemon {
+            serial_dev,
+            workout_dir,
+            interval,
+            pulses,
+            columns,
+            registers,
+            plan,
+            finish,
+            debug,
+        } => {
+            let registers = register_map_open(registers)?;
+            let config = wr_recorder::RecordConfig {
+                serial_dev,
+                workout_dir,
+                interval,
+                pulses,
+                capture: None,
+                columns: wr_columns::workout_data_columns_parse(&columns, &registers)?,
+                registers,
+                program: None,
+                plan: workout_plan_open(plan)?,
+                finish: finish.criteria(),
+                debug: debug_trace(debug),
+            };
+            let stop_requested = Arc::new(AtomicBool::new(false));
+            stop_handler_install(Arc::clone(&stop_requested))?;
+            println!("\n### Starting WaterRower workout daemon ...");
+            let mut console = console_init(&config);
+            wr_recorder::workout_daemon(&config, stop_requested, &mut [&mut console])?;
             println!("\n### Bye!");
         }
+        WaterRower::Replay {
+            capture_file,
+            workout_dir,
+            pulses,
+            columns,
+            registers,
+            program,
+            plan,
+            finish,
+            debug,
+        } => {
+            let registers = register_map_open(registers)?;
+            let config = wr_recorder::RecordConfig {
+                serial_dev: String::new(),
+                workout_dir,
+                interval: interval_parse(DEFAULT_INTERVAL)?,
+                pulses,
+                capture: None,
+                columns: wr_columns::workout_data_columns_parse(&columns, &registers)?,
+                registers,
+                program,
+                plan: workout_plan_open(plan)?,
+                finish: finish.criteria(),
+                debug: debug_trace(debug),
+            };
+            println!("\n### Replaying captured WaterRower workout recording ...");
+            let mut console = console_init(&config);
+            wr_recorder::workout_replay(&capture_file, &config, &mut [&mut console])?;
+        }
+        WaterRower::Recover { workout_path } => {
+            println!("\n### Recovering workout meta data ...");
+            let mut console = Console {
+                workout_dir: None,
+                intervals: Vec::new(),
+                last_interval_number: 0,
+                segments: (0, 0),
+            };
+            wr_recorder::workout_recover(&workout_path, &mut [&mut console])?;
+        }
+        WaterRower::Ports => ports_list()?,
+        #[cfg(unix)]
+        WaterRower::Simulate {
+            profile,
+            start_delay,
+            debug,
+        } => {
+            println!("\n### Starting S4 performance monitor simulation ...");
+            wr_simulator::simulator_run(
+                profile,
+                time::Duration::from_secs(start_delay),
+                debug_trace(debug),
+                |serial_dev| println!("--- Simulated S4 available at: {}", serial_dev.display()),
+            )?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,332 @@
+//! Async client for the S4, built on tokio (requires the `async` feature)
+//!
+//! The client drives the same sans-IO [`Session`] as the blocking functions of
+//! [`crate::wr_utils`], so register table, sampling and event decoding are
+//! shared. Instead of polling the port with short timeouts, it waits for data
+//! or the session's next timer, whichever comes first.
+
+use futures::stream::{self, Stream};
+use std::{io, time};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_serial::SerialPortBuilderExt;
+
+use crate::{
+    wr_discovery::SERIAL_DEV_AUTO,
+    wr_error::WaterRowerError,
+    wr_protocol::S4Command,
+    wr_session::{Session, SessionEvent, WorkoutState, RESPONSE_TIMEOUT},
+    wr_transport::{SERIAL_BAUDRATE, TRANSPORT_TCP_PREFIX, TRANSPORT_TRACE_PREFIX},
+};
+
+/// Byte stream an [`AsyncClient`] can talk to the S4 over
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S {}
+
+/// Async connection to an S4 running a workout session
+pub struct AsyncClient<S: AsyncStream> {
+    stream: S,
+    session: Session,
+    buf: Vec<u8>,
+    failed: bool,
+}
+
+impl<S: AsyncStream> AsyncClient<S> {
+    /// Creates a client sampling workout values every `interval`
+    pub fn new(stream: S, interval: time::Duration) -> Self {
+        AsyncClient {
+            stream,
+            session: Session::new(interval),
+            buf: vec![0; 1024],
+            failed: false,
+        }
+    }
+
+    pub fn state(&self) -> WorkoutState {
+        self.session.state()
+    }
+
+    /// Opens the communication, waiting for the S4 to identify itself
+    pub async fn start(&mut self) -> Result<(), WaterRowerError> {
+        self.session.start(time::Instant::now());
+        loop {
+            match self.next_event().await {
+                Some(Ok(SessionEvent::Connected)) => return Ok(()),
+                Some(Err(e)) => return Err(e),
+                Some(Ok(_)) => (),
+                None => return Err(WaterRowerError::Timeout("WaterRower hardware type")),
+            }
+        }
+    }
+
+    /// Requests model and firmware version, `None` if the S4 does not answer in time
+    pub async fn model_info(&mut self) -> Result<Option<(u8, u8, u8)>, WaterRowerError> {
+        self.session.model_info_request();
+        let request = async {
+            loop {
+                match self.next_event().await {
+                    Some(Ok(SessionEvent::ModelInfo {
+                        model,
+                        fw_major,
+                        fw_minor,
+                    })) => return Ok(Some((model, fw_major, fw_minor))),
+                    Some(Err(e)) => return Err(e),
+                    Some(Ok(_)) => (),
+                    None => return Ok(None),
+                }
+            }
+        };
+        tokio::time::timeout(RESPONSE_TIMEOUT, request)
+            .await
+            .unwrap_or(Ok(None))
+    }
+
+    /// Closes the communication with the S4
+    pub async fn stop(&mut self) -> Result<(), WaterRowerError> {
+        self.session.stop();
+        self.transmit().await
+    }
+
+    /// Waits for the next event of the session
+    ///
+    /// Returns `None` once the workout is finished or the session failed.
+    pub async fn next_event(&mut self) -> Option<Result<SessionEvent, WaterRowerError>> {
+        loop {
+            if let Some(event) = self.session.poll_event() {
+                return match event {
+                    SessionEvent::Failed(e) => {
+                        self.failed = true;
+                        Some(Err(e))
+                    }
+                    event => Some(Ok(event)),
+                };
+            }
+            if self.failed || self.session.state() == WorkoutState::Finished {
+                return None;
+            }
+            if let Err(e) = self.exchange().await {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+    }
+
+    /// Stream of session events, ending once the workout is finished or the session failed
+    pub fn events(&mut self) -> impl Stream<Item = Result<SessionEvent, WaterRowerError>> + '_ {
+        stream::unfold(self, |client| async move {
+            let event = client.next_event().await?;
+            Some((event, client))
+        })
+    }
+
+    async fn transmit(&mut self) -> Result<(), WaterRowerError> {
+        loop {
+            let now = time::Instant::now();
+            if let Some(command) = self.session.poll_transmit(now) {
+                self.command_write(&command).await?;
+                continue;
+            }
+            match self.session.poll_timeout() {
+                Some(timeout) if timeout > now => tokio::time::sleep(timeout - now).await,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    async fn command_write(&mut self, command: &S4Command) -> Result<(), WaterRowerError> {
+        let line = format!("{}\n", command.encode());
+        self.stream.write_all(line.as_bytes()).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    // Sends the commands due, then waits for data or the session's next timer
+    async fn exchange(&mut self) -> Result<(), WaterRowerError> {
+        while let Some(command) = self.session.poll_transmit(time::Instant::now()) {
+            self.command_write(&command).await?;
+        }
+        let timeout = self.session.poll_timeout();
+        let sleep = async {
+            match timeout {
+                Some(timeout) => {
+                    tokio::time::sleep_until(tokio::time::Instant::from_std(timeout)).await
+                }
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            read = self.stream.read(&mut self.buf) => match read? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                n => self.session.handle_bytes(&self.buf[..n], time::Instant::now()),
+            },
+            _ = sleep => (),
+        }
+        self.session.handle_timeout(time::Instant::now());
+        Ok(())
+    }
+}
+
+/// Opens an S4 connected to a local serial device
+pub fn async_serial_open(serial_dev: &str) -> Result<tokio_serial::SerialStream, WaterRowerError> {
+    tokio_serial::new(serial_dev, SERIAL_BAUDRATE)
+        .open_native_async()
+        .map_err(|e| WaterRowerError::PortOpen(serial_dev.to_owned(), e.into()))
+}
+
+/// Opens an async client according to a transport specification
+///
+/// Supported are `tcp://<host>:<port>` and serial devices. Unlike
+/// [`crate::wr_transport::transport_open`], `auto` and `trace://<file>` are not,
+/// an S4 can be searched for with [`crate::wr_discovery::s4_discover`] beforehand.
+pub async fn async_client_open(
+    spec: &str,
+    interval: time::Duration,
+) -> Result<AsyncClient<Box<dyn AsyncStream>>, WaterRowerError> {
+    if spec == SERIAL_DEV_AUTO || spec.starts_with(TRANSPORT_TRACE_PREFIX) {
+        return Err(WaterRowerError::PortOpen(
+            spec.to_owned(),
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "not supported by the async client",
+            ),
+        ));
+    }
+    let stream: Box<dyn AsyncStream> = match spec.strip_prefix(TRANSPORT_TCP_PREFIX) {
+        Some(address) => Box::new(
+            TcpStream::connect(address)
+                .await
+                .map_err(|e| WaterRowerError::PortOpen(address.to_owned(), e))?,
+        ),
+        None => Box::new(async_serial_open(spec)?),
+    };
+    Ok(AsyncClient::new(stream, interval))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        wr_codec::FrameDecoder,
+        wr_protocol::S4Message,
+        wr_session::{FinishCriteria, FinishReason},
+    };
+    use futures::StreamExt;
+    use tokio::io::DuplexStream;
+
+    const INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+    async fn fake_s4_write(stream: &mut DuplexStream, data: &str) {
+        stream.write_all(data.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+
+    // Answers like an S4 with all registers zero, the model info arrives in two parts
+    async fn fake_s4(mut stream: DuplexStream, model_info: bool, first_stroke: bool) {
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; 256];
+        loop {
+            let n = match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            decoder.push(&buf[..n]);
+            while let Some(line) = decoder.next_frame() {
+                let mut replies = Vec::new();
+                match S4Command::parse(&line) {
+                    Ok(S4Command::Start) => {
+                        replies.push(S4Message::HardwareType);
+                        if first_stroke {
+                            replies.push(S4Message::StrokeStart);
+                        }
+                    }
+                    Ok(S4Command::ModelInfo) if model_info => {
+                        let reply = S4Message::ModelInfo {
+                            model: 4,
+                            fw_major: 2,
+                            fw_minor: 10,
+                        }
+                        .encode();
+                        let (first, second) = reply.split_at(4);
+                        fake_s4_write(&mut stream, first).await;
+                        tokio::time::sleep(time::Duration::from_millis(50)).await;
+                        fake_s4_write(&mut stream, &format!("{}\r\n", second)).await;
+                    }
+                    Ok(S4Command::Read { size, address }) => replies.push(S4Message::Data {
+                        size,
+                        address,
+                        value: 0,
+                    }),
+                    _ => (),
+                }
+                for reply in replies.iter() {
+                    fake_s4_write(&mut stream, &format!("{}\r\n", reply.encode())).await;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn model_info_split_across_writes() {
+        let (stream, s4_stream) = tokio::io::duplex(1024);
+        let s4 = tokio::spawn(fake_s4(s4_stream, true, false));
+        let mut client = AsyncClient::new(stream, INTERVAL);
+        client.start().await.unwrap();
+        assert_eq!(client.state(), WorkoutState::Connected);
+        assert_eq!(client.model_info().await.unwrap(), Some((4, 2, 10)));
+        client.stop().await.unwrap();
+        drop(client);
+        s4.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn model_info_timed_out() {
+        let (stream, s4_stream) = tokio::io::duplex(1024);
+        tokio::spawn(fake_s4(s4_stream, false, false));
+        let mut client = AsyncClient::new(stream, INTERVAL);
+        client.start().await.unwrap();
+        let requested = time::Instant::now();
+        assert_eq!(client.model_info().await.unwrap(), None);
+        assert!(requested.elapsed() >= RESPONSE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn events_until_finished() {
+        let (stream, s4_stream) = tokio::io::duplex(1024);
+        tokio::spawn(fake_s4(s4_stream, false, true));
+        let mut client = AsyncClient::new(stream, INTERVAL);
+        client.session.finish_criteria_set(FinishCriteria {
+            inactivity: Some(time::Duration::from_secs(1)),
+            ..FinishCriteria::default()
+        });
+        client.start().await.unwrap();
+        let events: Vec<SessionEvent> = client.events().map(Result::unwrap).collect().await;
+        assert!(matches!(events.first(), Some(SessionEvent::FirstStroke)));
+        let samples = events
+            .iter()
+            .filter(|event| matches!(event, SessionEvent::Sample(_)))
+            .count();
+        assert!(samples >= 2, "{} samples", samples);
+        assert!(matches!(
+            events.last(),
+            Some(SessionEvent::Finished(FinishReason::Inactivity))
+        ));
+        assert!(client.next_event().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn open_unsupported() {
+        for spec in ["auto", "trace://capture.csv"].iter() {
+            let opened = async_client_open(spec, time::Duration::from_secs(1)).await;
+            match opened.err() {
+                Some(WaterRowerError::PortOpen(name, e)) => {
+                    assert_eq!(name, *spec);
+                    assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+                }
+                _ => panic!("Expected {} to be rejected", spec),
+            }
+        }
+    }
+}
@@ -0,0 +1,401 @@
+//! Capture of the communication with an S4 and its replay
+//!
+//! Captures extend the trace format (time in milliseconds, direction, data) by
+//! the clock readings of a workout session: besides every command sent (`TX`)
+//! and every chunk of bytes received (`RX`), they hold the sampling interval,
+//! the times timers were handled (`TIMER`) and the date and time read (`CLOCK`).
+//! Received bytes are captured before they are split into messages, noise and
+//! messages split across reads included. This way a replay feeds the session
+//! exactly the same input as the captured recording.
+
+use chrono::{DateTime, Local};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time,
+};
+
+use crate::{wr_error::WaterRowerError, wr_transport::Transport, wr_utils};
+
+pub const CAPTURE_HEADER: [&str; 3] = ["Time in Milliseconds", "Direction", "Data"];
+
+const CAPTURE_DIRECTION_SENT: &str = "TX";
+const CAPTURE_DIRECTION_RECEIVED: &str = "RX";
+const CAPTURE_DIRECTION_TIMER: &str = "TIMER";
+const CAPTURE_DIRECTION_CLOCK: &str = "CLOCK";
+const CAPTURE_DIRECTION_INTERVAL: &str = "INTERVAL";
+
+// Time passing per read once a replay ran out of data, so pending timeouts still expire
+const REPLAY_IDLE_STEP: time::Duration = time::Duration::from_millis(10);
+
+// Milliseconds with nanosecond precision, so replayed times are exactly the captured ones
+fn capture_time_format(offset: time::Duration) -> String {
+    let nanos = offset.as_nanos();
+    format!("{}.{:06}", nanos / 1_000_000, nanos % 1_000_000)
+}
+
+fn capture_time_parse(s: &str) -> Option<time::Duration> {
+    let (millis, fraction) = match s.split_once('.') {
+        Some((millis, fraction)) => (millis, fraction),
+        None => (s, ""),
+    };
+    if fraction.len() > 6 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let nanos: u64 = format!("{:0<6}", fraction).parse().ok()?;
+    Some(time::Duration::from_millis(millis.parse().ok()?) + time::Duration::from_nanos(nanos))
+}
+
+fn capture_invalid(row: &csv::ByteRecord) -> WaterRowerError {
+    let fields: Vec<String> = row
+        .iter()
+        .map(|field| String::from_utf8_lossy(field).into_owned())
+        .collect();
+    let message = format!("Invalid capture row '{}'", fields.join(","));
+    WaterRowerError::FileRead(csv::Error::from(io::Error::new(
+        io::ErrorKind::InvalidData,
+        message,
+    )))
+}
+
+/// Writer of a capture file, fed by the functions driving a workout session
+pub struct CaptureWriter {
+    csv_writer: csv::Writer<File>,
+    start: time::Instant,
+}
+
+/// Creates a capture file, times are relative to `start`
+pub fn capture_writer_init(
+    capture_file: &Path,
+    start: time::Instant,
+    interval: time::Duration,
+) -> Result<CaptureWriter, WaterRowerError> {
+    let mut capture_writer = CaptureWriter {
+        csv_writer: csv::Writer::from_path(capture_file).map_err(WaterRowerError::FileWrite)?,
+        start,
+    };
+    capture_writer
+        .csv_writer
+        .write_record(CAPTURE_HEADER)
+        .map_err(WaterRowerError::FileWrite)?;
+    capture_writer.write(
+        start,
+        CAPTURE_DIRECTION_INTERVAL,
+        format!("{}", interval.as_secs_f64()).as_bytes(),
+    )?;
+    Ok(capture_writer)
+}
+
+impl CaptureWriter {
+    pub fn sent(&mut self, now: time::Instant, command: &str) -> Result<(), WaterRowerError> {
+        self.write(now, CAPTURE_DIRECTION_SENT, command.as_bytes())
+    }
+
+    /// Records bytes as they were received, before they are split into messages
+    pub fn received(&mut self, now: time::Instant, bytes: &[u8]) -> Result<(), WaterRowerError> {
+        self.write(now, CAPTURE_DIRECTION_RECEIVED, bytes)
+    }
+
+    /// Records that timers of the session were due at `now`
+    pub fn timer(&mut self, now: time::Instant) -> Result<(), WaterRowerError> {
+        self.write(now, CAPTURE_DIRECTION_TIMER, b"")
+    }
+
+    pub fn clock(
+        &mut self,
+        now: time::Instant,
+        local_time: &DateTime<Local>,
+    ) -> Result<(), WaterRowerError> {
+        self.write(
+            now,
+            CAPTURE_DIRECTION_CLOCK,
+            local_time.to_rfc3339().as_bytes(),
+        )
+    }
+
+    // Rows are flushed at once, so a capture is complete even if the recording crashes
+    fn write(
+        &mut self,
+        now: time::Instant,
+        direction: &str,
+        data: &[u8],
+    ) -> Result<(), WaterRowerError> {
+        let offset = now.saturating_duration_since(self.start);
+        self.csv_writer
+            .write_record([
+                capture_time_format(offset).as_bytes(),
+                direction.as_bytes(),
+                data,
+            ])
+            .map_err(WaterRowerError::FileWrite)?;
+        self.csv_writer.flush().map_err(wr_utils::file_write_error)
+    }
+}
+
+struct CaptureRow {
+    offset: time::Duration,
+    direction: String,
+    data: Vec<u8>,
+}
+
+/// Transport replaying a capture on the captured clock
+///
+/// Messages are handed out in the steps of the captured session, with the time
+/// they were received at. Commands are not checked, they are answered by the
+/// captured messages anyway.
+pub struct ReplayTransport {
+    rows: VecDeque<CaptureRow>,
+    clock: VecDeque<DateTime<Local>>,
+    interval: Option<time::Duration>,
+    start: time::Instant,
+    now: time::Instant,
+    step: Option<time::Instant>,
+    end_of_capture: Arc<AtomicBool>,
+}
+
+impl ReplayTransport {
+    pub fn open(capture_file: &Path) -> Result<Self, WaterRowerError> {
+        let mut rows = VecDeque::new();
+        let mut clock = VecDeque::new();
+        let mut interval = None;
+        let mut csv_reader =
+            csv::Reader::from_path(capture_file).map_err(WaterRowerError::FileRead)?;
+        // Received bytes are not necessarily text, only the other columns are
+        for row in csv_reader.byte_records() {
+            let row = row.map_err(WaterRowerError::FileRead)?;
+            let text = |i| row.get(i).and_then(|field| std::str::from_utf8(field).ok());
+            let offset = text(0)
+                .and_then(capture_time_parse)
+                .ok_or_else(|| capture_invalid(&row))?;
+            let direction = text(1).unwrap_or("");
+            let data = row.get(2).unwrap_or(b"");
+            match direction {
+                CAPTURE_DIRECTION_INTERVAL => {
+                    let seconds: f64 = text(2)
+                        .and_then(|data| data.parse().ok())
+                        .ok_or_else(|| capture_invalid(&row))?;
+                    interval = Some(time::Duration::from_secs_f64(seconds));
+                }
+                CAPTURE_DIRECTION_CLOCK => {
+                    let local_time = text(2)
+                        .and_then(|data| DateTime::parse_from_rfc3339(data).ok())
+                        .ok_or_else(|| capture_invalid(&row))?;
+                    clock.push_back(local_time.with_timezone(&Local));
+                }
+                CAPTURE_DIRECTION_SENT | CAPTURE_DIRECTION_RECEIVED | CAPTURE_DIRECTION_TIMER => {
+                    rows.push_back(CaptureRow {
+                        offset,
+                        direction: direction.to_owned(),
+                        data: data.to_owned(),
+                    })
+                }
+                _ => (),
+            }
+        }
+        let start = time::Instant::now();
+        Ok(ReplayTransport {
+            rows,
+            clock,
+            interval,
+            start,
+            now: start,
+            step: None,
+            end_of_capture: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Sampling interval of the captured recording
+    pub fn interval(&self) -> Option<time::Duration> {
+        self.interval
+    }
+
+    /// Flag set once all captured messages were replayed, e.g. to stop the recording
+    pub fn end_of_capture(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.end_of_capture)
+    }
+
+    fn row_front_is(&self, direction: &str, step: time::Instant) -> bool {
+        match self.rows.front() {
+            Some(row) => row.direction == direction && self.start + row.offset == step,
+            None => false,
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write_command(&mut self, _command: &str) -> io::Result<()> {
+        if let Some(row) = self.rows.front() {
+            if row.direction == CAPTURE_DIRECTION_SENT {
+                self.rows.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let step = match self.step {
+            Some(step) => step,
+            None => {
+                // Skip commands captured in the previous step that were not sent again
+                while let Some(row) = self.rows.front() {
+                    if row.direction != CAPTURE_DIRECTION_SENT || self.start + row.offset > self.now
+                    {
+                        break;
+                    }
+                    self.rows.pop_front();
+                }
+                let step = match self.rows.front() {
+                    Some(row) => self.start + row.offset,
+                    None => {
+                        self.end_of_capture.store(true, Ordering::SeqCst);
+                        self.now += REPLAY_IDLE_STEP;
+                        return Ok(None);
+                    }
+                };
+                self.now = self.now.max(step);
+                self.step = Some(step);
+                step
+            }
+        };
+        while self.row_front_is(CAPTURE_DIRECTION_TIMER, step) {
+            self.rows.pop_front();
+        }
+        if self.row_front_is(CAPTURE_DIRECTION_RECEIVED, step) {
+            return Ok(self.rows.pop_front().map(|row| row.data));
+        }
+        self.step = None;
+        Ok(None)
+    }
+
+    fn now(&mut self) -> time::Instant {
+        self.now
+    }
+
+    fn sleep(&mut self, duration: time::Duration) {
+        self.now += duration;
+    }
+
+    fn local_time(&mut self) -> DateTime<Local> {
+        self.clock.pop_front().unwrap_or_else(Local::now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        wr_columns,
+        wr_protocol::{S4Command, S4Message},
+        wr_recorder::{self, RecordConfig},
+        wr_registers,
+        wr_session::FinishCriteria,
+        wr_transport::MemoryTransport,
+    };
+    use std::fs;
+
+    // S4 answering over a memory transport, the distance counts up a meter per read and
+    // everything else reads zero. Some replies arrive in parts or after noise on the line.
+    struct FakeS4 {
+        port: MemoryTransport,
+        distance_address: u16,
+        distance: u32,
+    }
+
+    impl Transport for FakeS4 {
+        fn write_command(&mut self, command: &str) -> io::Result<()> {
+            match S4Command::parse(command) {
+                Ok(S4Command::Start) => {
+                    self.port.push_response(&S4Message::HardwareType.encode());
+                    self.port.push_response(&S4Message::StrokeStart.encode());
+                }
+                Ok(S4Command::ModelInfo) => {
+                    self.port.push_bytes(b"IV40");
+                    self.port.push_bytes(b"210\r\n");
+                }
+                Ok(S4Command::Read { size, address }) => {
+                    let value = if address == self.distance_address {
+                        self.distance += 1;
+                        self.distance
+                    } else {
+                        0
+                    };
+                    let reply = S4Message::Data {
+                        size,
+                        address,
+                        value,
+                    }
+                    .encode();
+                    if value % 2 == 0 {
+                        self.port.push_bytes(b"\xff\x00\r\n");
+                        self.port.push_response(&reply);
+                    } else {
+                        let (first, second) = reply.split_at(3);
+                        self.port.push_bytes(first.as_bytes());
+                        self.port.push_bytes(format!("{}\r\n", second).as_bytes());
+                    }
+                }
+                _ => (),
+            }
+            self.port.write_command(command)
+        }
+
+        fn read_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+            self.port.read_bytes()
+        }
+    }
+
+    #[test]
+    fn replay_reproduces_recording() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let registers = wr_registers::register_map_bundled();
+        let config = RecordConfig {
+            serial_dev: String::from("memory"),
+            workout_dir: dir.join("recorded"),
+            interval: time::Duration::from_millis(200),
+            pulses: false,
+            capture: Some(dir.join("capture.csv")),
+            columns: wr_columns::workout_data_columns_parse("time,elapsed,distance", &registers)
+                .unwrap(),
+            registers: registers.clone(),
+            program: None,
+            plan: None,
+            finish: FinishCriteria {
+                target_distance_in_meters: Some(8),
+                ..FinishCriteria::default()
+            },
+            debug: None,
+        };
+        let s4 = FakeS4 {
+            port: MemoryTransport::new(),
+            distance_address: registers.get("Distance").unwrap().address,
+            distance: 0,
+        };
+        let recorded = wr_recorder::workout_run(
+            &config,
+            Box::new(s4),
+            Arc::new(AtomicBool::new(false)),
+            &mut [],
+        )
+        .unwrap();
+
+        let replay_config = RecordConfig {
+            workout_dir: dir.join("replayed"),
+            capture: None,
+            ..config
+        };
+        let capture_file = dir.join("capture.csv");
+        let replayed = wr_recorder::workout_replay(&capture_file, &replay_config, &mut []).unwrap();
+
+        let data_file = |workout_path: &Path| fs::read(workout_path.join("workout_data.csv"));
+        let recorded_data = data_file(&recorded).unwrap();
+        assert!(recorded_data.split(|&b| b == b'\n').count() > 8);
+        assert_eq!(recorded_data, data_file(&replayed).unwrap());
+    }
+}
@@ -0,0 +1,117 @@
+//! Framing of the byte stream received from or sent to an S4
+
+use std::collections::VecDeque;
+
+// S4 messages are short ASCII lines, anything longer is considered noise
+const FRAME_MAX_LENGTH: usize = 64;
+
+/// Stateful decoder turning a byte stream into complete CR/LF-terminated messages
+///
+/// Bytes can be pushed in arbitrary chunks, partial messages are kept until
+/// their terminator arrives. Non-printable bytes corrupt the message they
+/// appear in, which is then discarded like overlong messages.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: String,
+    discarding: bool,
+    frames: VecDeque<String>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds received bytes into the decoder
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes.iter() {
+            match byte {
+                b'\r' | b'\n' => {
+                    if !self.discarding && !self.buffer.is_empty() {
+                        self.frames.push_back(self.buffer.clone());
+                    }
+                    self.buffer.clear();
+                    self.discarding = false;
+                }
+                0x20..=0x7E => {
+                    if self.discarding {
+                        continue;
+                    }
+                    if self.buffer.len() == FRAME_MAX_LENGTH {
+                        self.buffer.clear();
+                        self.discarding = true;
+                    } else {
+                        self.buffer.push(byte as char);
+                    }
+                }
+                _ => {
+                    // Noise between messages is skipped, within a message it is corrupted
+                    if !self.buffer.is_empty() {
+                        self.buffer.clear();
+                        self.discarding = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the next complete message, if any
+    pub fn next_frame(&mut self) -> Option<String> {
+        self.frames.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(decoder: &mut FrameDecoder) -> Vec<String> {
+        std::iter::from_fn(|| decoder.next_frame()).collect()
+    }
+
+    #[test]
+    fn frame_split_across_pushes() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(b"IDD05");
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(b"5002A\r\n");
+        assert_eq!(frames(&mut decoder), ["IDD055002A"]);
+    }
+
+    #[test]
+    fn several_frames_in_one_push() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(b"SS\r\nSE\r\nP0A\r\n");
+        assert_eq!(frames(&mut decoder), ["SS", "SE", "P0A"]);
+    }
+
+    #[test]
+    fn leading_noise_discarded() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(b"\x00\xff\x13_WR_\r\n");
+        assert_eq!(frames(&mut decoder), ["_WR_"]);
+    }
+
+    #[test]
+    fn noise_within_frame_discards_it() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(b"ID\xffD055002A\r\nOK\r\n");
+        assert_eq!(frames(&mut decoder), ["OK"]);
+    }
+
+    #[test]
+    fn overlong_frame_dropped_keeping_next() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[b'A'; FRAME_MAX_LENGTH + 10]);
+        decoder.push(b"\r\nPING\r\n");
+        assert_eq!(frames(&mut decoder), ["PING"]);
+    }
+
+    #[test]
+    fn frame_of_max_length_kept() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[b'A'; FRAME_MAX_LENGTH]);
+        decoder.push(b"\n");
+        assert_eq!(frames(&mut decoder), ["A".repeat(FRAME_MAX_LENGTH)]);
+    }
+}
@@ -0,0 +1,428 @@
+//! Selectable columns of the workout data file
+//!
+//! Every column names the S4 registers it is derived from, so a session only
+//! polls what is recorded. This keeps short sampling intervals feasible, as the
+//! S4 answers one read at a time. Besides the built-in columns, registers added
+//! by a user register map can be recorded by their column key.
+
+use crate::{
+    wr_registers::{Register, RegisterMap},
+    wr_utils::{DataRow, InstantWorkoutValues},
+};
+
+/// Column of the workout data file
+#[derive(Clone)]
+pub struct WorkoutDataColumn {
+    /// Name used to select the column, e.g. on the command line
+    pub key: String,
+    pub header: String,
+    /// Registers the column's value is decoded from
+    pub registers: Vec<String>,
+    value: ColumnValue,
+}
+
+#[derive(Clone)]
+enum ColumnValue {
+    Builtin(fn(&InstantWorkoutValues) -> String),
+    /// Decoded value of a register added by a user register map
+    Register {
+        name: String,
+        precision: usize,
+    },
+}
+
+impl WorkoutDataColumn {
+    pub fn format(&self, iwv: &InstantWorkoutValues) -> String {
+        match &self.value {
+            ColumnValue::Builtin(format) => format(iwv),
+            ColumnValue::Register { name, precision } => {
+                let value = iwv.register_values.get(name).copied().unwrap_or(0.0);
+                format!("{:.*}", precision, value)
+            }
+        }
+    }
+}
+
+struct BuiltinColumn {
+    key: &'static str,
+    header: &'static str,
+    registers: &'static [&'static str],
+    format: fn(&InstantWorkoutValues) -> String,
+}
+
+const REGISTERS_TIME: [&str; 3] = ["Seconds (Display)", "Minutes (Display)", "Hours (Display)"];
+
+const REGISTERS_TIME_PRECISE: [&str; 4] = [
+    "Second Decimals (Display)",
+    "Seconds (Display)",
+    "Minutes (Display)",
+    "Hours (Display)",
+];
+
+static BUILTIN_COLUMNS: [BuiltinColumn; 24] = [
+    BuiltinColumn {
+        key: "time",
+        header: "Time in Seconds",
+        registers: &REGISTERS_TIME,
+        format: |iwv| format!("{}", iwv.time_in_seconds),
+    },
+    BuiltinColumn {
+        key: "time-precise",
+        header: "Time in Seconds (precise)",
+        registers: &REGISTERS_TIME_PRECISE,
+        format: |iwv| format!("{}.{}", iwv.time_in_seconds, iwv.time_decimals),
+    },
+    BuiltinColumn {
+        key: "elapsed",
+        header: "Elapsed Time in Seconds",
+        registers: &[],
+        format: |iwv| format!("{:.1}", iwv.elapsed_in_seconds),
+    },
+    BuiltinColumn {
+        key: "distance",
+        header: "Distance in Meters",
+        registers: &["Distance"],
+        format: |iwv| format!("{}", iwv.distance_in_meters),
+    },
+    BuiltinColumn {
+        key: "display-distance",
+        header: "Display Distance in Meters",
+        registers: &["Distance (Display)"],
+        format: |iwv| format!("{}", iwv.display_distance_in_meters),
+    },
+    BuiltinColumn {
+        key: "pace",
+        header: "Seconds per 500 Meters",
+        registers: &["Seconds per 500 Meters (Zone)"],
+        format: |iwv| format!("{}", iwv.seconds_per_500m),
+    },
+    BuiltinColumn {
+        key: "pace-2km",
+        header: "Seconds per 2 Kilometers",
+        registers: &["Seconds per 2 Kilometers (Zone)"],
+        format: |iwv| format!("{}", iwv.seconds_per_2km),
+    },
+    BuiltinColumn {
+        key: "strokes",
+        header: "Stroke Count",
+        registers: &["Stroke Count"],
+        format: |iwv| format!("{}", iwv.stroke_count),
+    },
+    BuiltinColumn {
+        key: "stroke-rate",
+        header: "Strokes per Minute",
+        registers: &["Stroke Rate (Zone)"],
+        format: |iwv| format!("{}", iwv.strokes_per_minute),
+    },
+    BuiltinColumn {
+        key: "ratio",
+        header: "Stroke Ratio",
+        registers: &["Stroke Time Average", "Stroke Pull Time Average"],
+        format: |iwv| format!("{:.2}", iwv.stroke_ratio),
+    },
+    BuiltinColumn {
+        key: "heart-rate",
+        header: "Heart Rate",
+        registers: &["Heart Rate (Zone)"],
+        format: |iwv| format!("{}", iwv.heart_rate),
+    },
+    BuiltinColumn {
+        key: "power",
+        header: "Power in Watts",
+        registers: &["Power"],
+        format: |iwv| format!("{}", iwv.power_in_watts),
+    },
+    BuiltinColumn {
+        key: "calories",
+        header: "Calories in kcal",
+        registers: &["Total Calories"],
+        format: |iwv| format!("{}", iwv.calories_in_kcal),
+    },
+    BuiltinColumn {
+        key: "speed",
+        header: "Speed in Meters per Second",
+        registers: &["Instant Centimeters per Second"],
+        format: |iwv| format!("{:.2}", iwv.speed_in_meters_per_second),
+    },
+    BuiltinColumn {
+        key: "speed-avg",
+        header: "Speed (avg) in Meters per Second",
+        registers: &["Total Centimeters per Second"],
+        format: |iwv| format!("{:.2}", iwv.speed_avg_in_meters_per_second),
+    },
+    BuiltinColumn {
+        key: "speed-zone",
+        header: "Speed (Zone) in Meters per Second",
+        registers: &["Centimeters per Second (Zone)"],
+        format: |iwv| format!("{:.2}", iwv.speed_zone_in_meters_per_second),
+    },
+    BuiltinColumn {
+        key: "countdown",
+        header: "Count Down in Seconds",
+        registers: &["Clock Count Down"],
+        format: |iwv| format!("{}", iwv.count_down_in_seconds),
+    },
+    BuiltinColumn {
+        key: "odometer",
+        header: "Odometer in Meters",
+        registers: &["Total Distance"],
+        format: |iwv| format!("{}", iwv.odometer_in_meters),
+    },
+    BuiltinColumn {
+        key: "tank-volume",
+        header: "Tank Volume in Liters",
+        registers: &["Tank Volume"],
+        format: |iwv| format!("{:.1}", iwv.tank_volume_in_liters),
+    },
+    BuiltinColumn {
+        key: "screen-mode",
+        header: "Screen Mode",
+        registers: &["Screen Mode"],
+        format: |iwv| format!("{}", iwv.screen_mode),
+    },
+    BuiltinColumn {
+        key: "interval",
+        header: "Interval",
+        registers: &[],
+        format: |iwv| format!("{}", iwv.interval_number),
+    },
+    BuiltinColumn {
+        key: "remaining",
+        header: "Remaining in Interval",
+        registers: &[],
+        format: |iwv| format!("{}", iwv.interval_remaining),
+    },
+    BuiltinColumn {
+        key: "resting",
+        header: "Resting",
+        registers: &[],
+        format: |iwv| format!("{}", u8::from(iwv.interval_resting)),
+    },
+    BuiltinColumn {
+        key: "paused",
+        header: "Paused",
+        registers: &[],
+        format: |iwv| format!("{}", u8::from(iwv.paused)),
+    },
+];
+
+/// Columns recorded unless others are selected
+pub const DEFAULT_WORKOUT_DATA_COLUMNS: [&str; 11] = [
+    "time",
+    "elapsed",
+    "distance",
+    "pace",
+    "strokes",
+    "stroke-rate",
+    "ratio",
+    "heart-rate",
+    "power",
+    "calories",
+    "paused",
+];
+
+fn builtin_column(column: &BuiltinColumn) -> WorkoutDataColumn {
+    WorkoutDataColumn {
+        key: column.key.to_owned(),
+        header: column.header.to_owned(),
+        registers: column.registers.iter().map(|&r| r.to_owned()).collect(),
+        value: ColumnValue::Builtin(column.format),
+    }
+}
+
+fn register_column(register: &Register) -> Option<WorkoutDataColumn> {
+    Some(WorkoutDataColumn {
+        key: register.column.clone()?,
+        header: if register.unit.is_empty() {
+            register.name.clone()
+        } else {
+            format!("{} in {}", register.name, register.unit)
+        },
+        registers: vec![register.name.clone()],
+        value: ColumnValue::Register {
+            name: register.name.clone(),
+            precision: register.precision(),
+        },
+    })
+}
+
+/// Built-in column of the given key
+pub fn workout_data_column(key: &str) -> Option<WorkoutDataColumn> {
+    BUILTIN_COLUMNS
+        .iter()
+        .find(|column| column.key == key)
+        .map(builtin_column)
+}
+
+pub fn workout_data_columns_default() -> Vec<WorkoutDataColumn> {
+    DEFAULT_WORKOUT_DATA_COLUMNS
+        .iter()
+        .filter_map(|key| workout_data_column(key))
+        .collect()
+}
+
+/// Built-in columns followed by those of registers added by a user register map
+pub fn workout_data_columns_all(registers: &RegisterMap) -> Vec<WorkoutDataColumn> {
+    BUILTIN_COLUMNS
+        .iter()
+        .map(builtin_column)
+        .chain(registers.columns().filter_map(register_column))
+        .collect()
+}
+
+/// Parses a comma separated list of column keys
+///
+/// `default` stands for the default columns, `all` for every column. Columns
+/// selected more than once are recorded once, in the order first selected.
+pub fn workout_data_columns_parse(
+    s: &str,
+    registers: &RegisterMap,
+) -> Result<Vec<WorkoutDataColumn>, String> {
+    let all = workout_data_columns_all(registers);
+    let mut columns: Vec<WorkoutDataColumn> = Vec::new();
+    for key in s.split(',').map(str::trim) {
+        let selected = match key {
+            "default" => workout_data_columns_default(),
+            "all" => all.clone(),
+            key => match all.iter().find(|column| column.key == key) {
+                Some(column) => vec![column.clone()],
+                None => {
+                    let keys: Vec<&str> = all.iter().map(|c| c.key.as_str()).collect();
+                    return Err(format!(
+                        "Unknown column '{}', expected 'default', 'all' or one of: {}",
+                        key,
+                        keys.join(", ")
+                    ));
+                }
+            },
+        };
+        for column in selected {
+            if !columns.iter().any(|c| c.key == column.key) {
+                columns.push(column);
+            }
+        }
+    }
+    Ok(columns)
+}
+
+/// Names of the registers to poll for the given columns
+pub fn workout_data_columns_registers(columns: &[WorkoutDataColumn]) -> Vec<&str> {
+    let mut registers: Vec<&str> = Vec::new();
+    for register in columns.iter().flat_map(|column| column.registers.iter()) {
+        if !registers.contains(&register.as_str()) {
+            registers.push(register);
+        }
+    }
+    registers
+}
+
+/// Row of selected columns of the workout data file
+pub struct WorkoutDataRow<'a> {
+    pub values: &'a InstantWorkoutValues,
+    pub columns: &'a [WorkoutDataColumn],
+}
+
+impl DataRow for WorkoutDataRow<'_> {
+    fn data_row(&self) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|column| column.format(self.values))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{wr_registers, wr_utils};
+
+    // A register added by a user map, recorded as its own column
+    const USER_MAP: &str = r#"
+[[register]]
+name = "Drag Factor"
+address = 0x0A0
+width = 2
+scale = 0.1
+unit = "kg/m"
+column = "drag"
+"#;
+
+    fn keys(columns: &[WorkoutDataColumn]) -> Vec<&str> {
+        columns.iter().map(|column| column.key.as_str()).collect()
+    }
+
+    #[test]
+    fn default_and_all_expanded() {
+        let registers = wr_registers::register_map_bundled();
+        let columns = workout_data_columns_parse("default", &registers).unwrap();
+        assert_eq!(keys(&columns), DEFAULT_WORKOUT_DATA_COLUMNS);
+
+        let columns = workout_data_columns_parse("all", &registers).unwrap();
+        assert_eq!(columns.len(), BUILTIN_COLUMNS.len());
+        assert_eq!(columns[0].key, "time");
+        assert_eq!(columns.last().unwrap().key, "paused");
+    }
+
+    #[test]
+    fn selected_once_in_first_order() {
+        let registers = wr_registers::register_map_bundled();
+        let columns =
+            workout_data_columns_parse(" odometer, time ,default,distance,odometer", &registers)
+                .unwrap();
+        let keys = keys(&columns);
+        assert_eq!(keys[..3], ["odometer", "time", "elapsed"]);
+        assert_eq!(keys.len(), DEFAULT_WORKOUT_DATA_COLUMNS.len() + 1);
+        assert_eq!(keys.iter().filter(|&&key| key == "time").count(), 1);
+    }
+
+    #[test]
+    fn unknown_column_rejected() {
+        let registers = wr_registers::register_map_bundled();
+        let e = workout_data_columns_parse("time,drag", &registers)
+            .err()
+            .unwrap();
+        assert!(e.starts_with("Unknown column 'drag'"), "{}", e);
+        assert!(e.contains("stroke-rate"), "{}", e);
+        assert!(workout_data_columns_parse("time,", &registers).is_err());
+    }
+
+    #[test]
+    fn register_column_selected() {
+        let registers = wr_registers::register_map_parse(USER_MAP, false).unwrap();
+        let columns = workout_data_columns_parse("time,drag", &registers).unwrap();
+        let drag = &columns[1];
+        assert_eq!(drag.header, "Drag Factor in kg/m");
+        assert_eq!(drag.registers, ["Drag Factor"]);
+
+        let mut iwv = wr_utils::instant_workout_values_init();
+        iwv.time_in_seconds = 75;
+        iwv.register_values
+            .insert(String::from("Drag Factor"), 12.3);
+        let row = WorkoutDataRow {
+            values: &iwv,
+            columns: &columns,
+        };
+        assert_eq!(row.data_row(), ["75", "12.3"]);
+
+        let all = workout_data_columns_parse("all", &registers).unwrap();
+        assert_eq!(all.last().unwrap().key, "drag");
+    }
+
+    #[test]
+    fn registers_polled_once() {
+        let registers = wr_registers::register_map_bundled();
+        let columns =
+            workout_data_columns_parse("time,time-precise,elapsed,ratio", &registers).unwrap();
+        assert_eq!(
+            workout_data_columns_registers(&columns),
+            [
+                "Seconds (Display)",
+                "Minutes (Display)",
+                "Hours (Display)",
+                "Second Decimals (Display)",
+                "Stroke Time Average",
+                "Stroke Pull Time Average",
+            ]
+        );
+    }
+}
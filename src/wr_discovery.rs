@@ -0,0 +1,125 @@
+//! Discovery of S4 performance monitors among the serial ports
+//!
+//! The S4 shows up as a USB serial device with the vendor and product ID of its
+//! USB controller. Ports with these IDs are probed by opening the communication,
+//! which only an S4 answers with its hardware type.
+
+use std::{io, time};
+
+use crate::{
+    wr_error::WaterRowerError,
+    wr_transport::{self, Transport},
+    wr_utils,
+};
+
+pub const S4_USB_VENDOR_ID: u16 = 0x04D8;
+pub const S4_USB_PRODUCT_ID: u16 = 0x000A;
+
+/// Serial device given to search for the S4 instead of naming it
+pub const SERIAL_DEV_AUTO: &str = "auto";
+
+// Nothing is sampled while probing, the interval is never used
+const PROBE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Serial port of the system
+#[derive(Clone, Debug)]
+pub struct SerialPortFound {
+    /// Device to open, e.g. `/dev/ttyACM0`
+    pub name: String,
+    /// USB vendor and product ID, if connected over USB
+    pub usb_ids: Option<(u16, u16)>,
+    /// Product name reported by a USB device
+    pub product: Option<String>,
+}
+
+impl SerialPortFound {
+    /// The port has the USB IDs of an S4
+    pub fn s4_ids(&self) -> bool {
+        self.usb_ids == Some((S4_USB_VENDOR_ID, S4_USB_PRODUCT_ID))
+    }
+}
+
+/// Lists the serial ports of the system
+pub fn serial_ports_list() -> Result<Vec<SerialPortFound>, WaterRowerError> {
+    let ports = serialport::available_ports()
+        .map_err(|e| WaterRowerError::PortOpen(String::from("serial ports"), e.into()))?;
+    Ok(ports
+        .into_iter()
+        .map(|port| match port.port_type {
+            serialport::SerialPortType::UsbPort(usb) => SerialPortFound {
+                name: port.port_name,
+                usb_ids: Some((usb.vid, usb.pid)),
+                product: usb.product,
+            },
+            _ => SerialPortFound {
+                name: port.port_name,
+                usb_ids: None,
+                product: None,
+            },
+        })
+        .collect())
+}
+
+/// Checks if an S4 answers on a serial device, by opening and closing the communication
+pub fn serial_port_probe(serial_dev: &str) -> Result<(), WaterRowerError> {
+    let port: Box<dyn Transport> = Box::new(wr_transport::serial_open(serial_dev)?);
+    let mut ctx = wr_utils::workout_context_init(port, PROBE_INTERVAL, None);
+    wr_utils::start(&mut ctx)?;
+    wr_utils::stop(&mut ctx)
+}
+
+/// Keeps the ports with the USB IDs of an S4, in the order they were listed
+pub fn s4_candidates(ports: Vec<SerialPortFound>) -> Vec<SerialPortFound> {
+    ports.into_iter().filter(SerialPortFound::s4_ids).collect()
+}
+
+/// Searches the serial ports with the USB IDs of an S4 for the first one an S4 answers on
+pub fn s4_discover() -> Result<String, WaterRowerError> {
+    let candidates = s4_candidates(serial_ports_list()?);
+    for candidate in candidates.iter() {
+        if serial_port_probe(&candidate.name).is_ok() {
+            return Ok(candidate.name.clone());
+        }
+    }
+    Err(WaterRowerError::PortOpen(
+        String::from(SERIAL_DEV_AUTO),
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no WaterRower answered on {} serial port(s) with USB ID {:04X}:{:04X}",
+                candidates.len(),
+                S4_USB_VENDOR_ID,
+                S4_USB_PRODUCT_ID
+            ),
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(name: &str, usb_ids: Option<(u16, u16)>) -> SerialPortFound {
+        SerialPortFound {
+            name: String::from(name),
+            usb_ids,
+            product: None,
+        }
+    }
+
+    #[test]
+    fn only_s4_usb_ids_are_candidates() {
+        let ports = vec![
+            port("/dev/ttyS0", None),
+            port("/dev/ttyACM0", Some((S4_USB_VENDOR_ID, 0x000B))),
+            port("/dev/ttyACM1", Some((S4_USB_VENDOR_ID, S4_USB_PRODUCT_ID))),
+            port("/dev/ttyUSB0", Some((0x0403, S4_USB_PRODUCT_ID))),
+            port("/dev/ttyACM2", Some((S4_USB_VENDOR_ID, S4_USB_PRODUCT_ID))),
+        ];
+        let candidates: Vec<String> = s4_candidates(ports)
+            .into_iter()
+            .map(|port| port.name)
+            .collect();
+        assert_eq!(candidates, ["/dev/ttyACM1", "/dev/ttyACM2"]);
+    }
+}
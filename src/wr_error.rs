@@ -0,0 +1,66 @@
+//! Errors of the communication with an S4 and of workout file handling
+
+use std::{error, fmt, io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum WaterRowerError {
+    /// Opening the connection to the S4 failed
+    PortOpen(String, io::Error),
+    /// Communication with the S4 failed
+    Io(io::Error),
+    /// The S4 did not respond in time
+    Timeout(&'static str),
+    /// The S4 sent an unexpected or malformed response
+    Protocol(String),
+    /// A register value has not been received from the S4 yet
+    MissingRegister(String),
+    /// A register map file is invalid
+    RegisterMap(String),
+    /// A workout plan file is invalid
+    WorkoutPlan(String),
+    /// Creating a workout directory failed
+    FileCreate(PathBuf, io::Error),
+    /// Reading a workout file failed
+    FileRead(csv::Error),
+    /// Writing a workout file failed
+    FileWrite(csv::Error),
+}
+
+impl fmt::Display for WaterRowerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaterRowerError::PortOpen(spec, e) => write!(f, "Failed to open {}: {}", spec, e),
+            WaterRowerError::Io(e) => write!(f, "Communication with WaterRower failed: {}", e),
+            WaterRowerError::Timeout(what) => write!(f, "Timeout while waiting for {}", what),
+            WaterRowerError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            WaterRowerError::MissingRegister(name) => {
+                write!(f, "No value received for register '{}'", name)
+            }
+            WaterRowerError::RegisterMap(msg) => write!(f, "Invalid register map: {}", msg),
+            WaterRowerError::WorkoutPlan(msg) => write!(f, "Invalid workout plan: {}", msg),
+            WaterRowerError::FileCreate(path, e) => {
+                write!(f, "Failed to create {}: {}", path.display(), e)
+            }
+            WaterRowerError::FileRead(e) => write!(f, "Failed to read workout file: {}", e),
+            WaterRowerError::FileWrite(e) => write!(f, "Failed to write workout file: {}", e),
+        }
+    }
+}
+
+impl error::Error for WaterRowerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            WaterRowerError::PortOpen(_, e)
+            | WaterRowerError::Io(e)
+            | WaterRowerError::FileCreate(_, e) => Some(e),
+            WaterRowerError::FileRead(e) | WaterRowerError::FileWrite(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WaterRowerError {
+    fn from(e: io::Error) -> Self {
+        WaterRowerError::Io(e)
+    }
+}
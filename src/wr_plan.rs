@@ -0,0 +1,429 @@
+//! Workout plans with warm-up, repeated work and rest blocks and cool-down
+//!
+//! A plan is written as TOML (or JSON) and expanded into a [`WorkoutProgram`],
+//! which is followed during the recording without being loaded onto the S4.
+//! Every interval rowed is summed up as a row of `intervals.csv`:
+//!
+//! ```toml
+//! name = "8 x 500 m"
+//! warmup = { target = "5:00", stroke_rate = 18 }
+//! cooldown = { target = "1000m" }
+//!
+//! [[block]]
+//! repeat = 8
+//! target = "500m"
+//! rest = "1:30"
+//! stroke_rate = 28
+//! ```
+
+use serde::{de, Deserialize, Deserializer};
+use std::{fmt, fs, path::Path};
+
+use crate::{
+    wr_error::WaterRowerError,
+    wr_program::{
+        workout_rest_parse, workout_target_parse, WorkoutInterval, WorkoutProgram, WorkoutTarget,
+    },
+    wr_recorder::WorkoutObserver,
+    wr_utils::{self, DataFileWriter, DataRow, GlobalWorkoutValues, InstantWorkoutValues},
+};
+
+pub const INTERVAL_DATA_FILE: &str = "intervals.csv";
+
+pub const INTERVAL_DATA_HEADER: [&str; 12] = [
+    "Interval",
+    "Block",
+    "Target",
+    "Strokes per Minute (target)",
+    "Time in Seconds",
+    "Distance in Meters",
+    "Stroke Count",
+    "Seconds per 500 Meters (avg)",
+    "Strokes per Minute (avg)",
+    "Heart Rate (avg)",
+    "Power in Watts (avg)",
+    "Rest in Seconds",
+];
+
+/// Part of a plan, rowed `repeat` times with a rest after each
+///
+/// Warm-up and cool-down are rowed once.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlanBlock {
+    #[serde(default = "repeat_default")]
+    pub repeat: u32,
+    /// Given as e.g. `"500m"` or `"10:00"`
+    #[serde(deserialize_with = "target_deserialize")]
+    pub target: WorkoutTarget,
+    /// Given as e.g. `"1:30"`, none by default
+    #[serde(default, deserialize_with = "rest_deserialize")]
+    pub rest: u32,
+    /// Stroke rate to row at, for reference only
+    #[serde(default)]
+    pub stroke_rate: Option<u32>,
+}
+
+fn repeat_default() -> u32 {
+    1
+}
+
+fn target_deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<WorkoutTarget, D::Error> {
+    workout_target_parse(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+fn rest_deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    workout_rest_parse(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+/// Workout plan as written by a coach
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkoutPlan {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub warmup: Option<PlanBlock>,
+    #[serde(rename = "block", default)]
+    pub blocks: Vec<PlanBlock>,
+    #[serde(default)]
+    pub cooldown: Option<PlanBlock>,
+}
+
+/// Interval of an expanded plan
+#[derive(Clone, Debug)]
+pub struct PlanInterval {
+    /// Block the interval belongs to, e.g. `Block 2 (3/8)` or `Warm-up`
+    pub label: String,
+    pub interval: WorkoutInterval,
+    pub stroke_rate: Option<u32>,
+}
+
+impl WorkoutPlan {
+    /// Intervals in the order they are rowed
+    pub fn intervals(&self) -> Vec<PlanInterval> {
+        let mut intervals = Vec::new();
+        let mut push = |label: String, block: &PlanBlock| {
+            intervals.push(PlanInterval {
+                label,
+                interval: WorkoutInterval {
+                    target: block.target,
+                    rest_in_seconds: block.rest,
+                },
+                stroke_rate: block.stroke_rate,
+            })
+        };
+        if let Some(warmup) = self.warmup.as_ref() {
+            push(String::from("Warm-up"), warmup);
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            for rep in 1..=block.repeat {
+                push(format!("Block {} ({}/{})", i + 1, rep, block.repeat), block);
+            }
+        }
+        if let Some(cooldown) = self.cooldown.as_ref() {
+            push(String::from("Cool-down"), cooldown);
+        }
+        intervals
+    }
+
+    /// Program followed during the recording
+    pub fn program(&self) -> WorkoutProgram {
+        WorkoutProgram {
+            intervals: self
+                .intervals()
+                .into_iter()
+                .map(|interval| interval.interval)
+                .collect(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.warmup.is_none() && self.blocks.is_empty() && self.cooldown.is_none() {
+            return Err(String::from(
+                "plan has neither warm-up, blocks nor cool-down",
+            ));
+        }
+        if let Some(block) = self.blocks.iter().find(|block| block.repeat == 0) {
+            return Err(format!("block '{}' is repeated 0 times", block.target));
+        }
+        for (name, block) in [("warm-up", &self.warmup), ("cool-down", &self.cooldown)].iter() {
+            if let Some(block) = block.as_ref().filter(|block| block.repeat != 1) {
+                return Err(format!(
+                    "{} '{}' is rowed once, it cannot be repeated {} times",
+                    name, block.target, block.repeat
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Plans without a name are shown as their program
+impl fmt::Display for WorkoutPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "{}", self.program())
+        } else {
+            write!(f, "{} ({})", self.name, self.program())
+        }
+    }
+}
+
+/// Plan of the intervals of a program, e.g. one loaded onto the S4
+pub fn workout_plan_from_program(program: &WorkoutProgram) -> WorkoutPlan {
+    WorkoutPlan {
+        name: String::new(),
+        warmup: None,
+        blocks: program
+            .intervals
+            .iter()
+            .map(|interval| PlanBlock {
+                repeat: 1,
+                target: interval.target,
+                rest: interval.rest_in_seconds,
+                stroke_rate: None,
+            })
+            .collect(),
+        cooldown: None,
+    }
+}
+
+/// Parses a workout plan, JSON if `json` is set, TOML otherwise
+pub fn workout_plan_parse(s: &str, json: bool) -> Result<WorkoutPlan, WaterRowerError> {
+    let plan: WorkoutPlan = if json {
+        serde_json::from_str(s).map_err(|e| WaterRowerError::WorkoutPlan(e.to_string()))?
+    } else {
+        toml::from_str(s).map_err(|e| WaterRowerError::WorkoutPlan(e.to_string()))?
+    };
+    plan.validate().map_err(WaterRowerError::WorkoutPlan)?;
+    Ok(plan)
+}
+
+/// Loads a workout plan from a TOML or `.json` file
+pub fn workout_plan_load(plan_file: &Path) -> Result<WorkoutPlan, WaterRowerError> {
+    let s = fs::read_to_string(plan_file)
+        .map_err(|e| WaterRowerError::WorkoutPlan(format!("{}: {}", plan_file.display(), e)))?;
+    let json = plan_file.extension().is_some_and(|ext| ext == "json");
+    workout_plan_parse(&s, json)
+}
+
+/// Summary of one interval rowed, as written to the interval data file
+#[derive(Clone, Debug)]
+pub struct IntervalValues {
+    pub interval_number: u32,
+    pub label: String,
+    pub target: String,
+    pub stroke_rate_target: Option<u32>,
+    pub time_in_seconds: u32,
+    pub distance_in_meters: u32,
+    pub stroke_count: u32,
+    pub seconds_per_500m_avg: f32,
+    pub strokes_per_minute_avg: f32,
+    pub heart_rate_avg: f32,
+    pub power_in_watts_avg: f32,
+    pub rest_in_seconds: u32,
+}
+
+impl DataRow for IntervalValues {
+    fn data_row(&self) -> Vec<String> {
+        vec![
+            format!("{}", self.interval_number),
+            self.label.clone(),
+            self.target.clone(),
+            self.stroke_rate_target
+                .map_or(String::new(), |rate| format!("{}", rate)),
+            format!("{}", self.time_in_seconds),
+            format!("{}", self.distance_in_meters),
+            format!("{}", self.stroke_count),
+            format!("{:.2}", self.seconds_per_500m_avg),
+            format!("{:.2}", self.strokes_per_minute_avg),
+            format!("{:.2}", self.heart_rate_avg),
+            format!("{:.2}", self.power_in_watts_avg),
+            format!("{}", self.rest_in_seconds),
+        ]
+    }
+}
+
+// Average of the values greater than zero, zero if there are none
+fn average_valid(values: &[u32]) -> f32 {
+    let valid: Vec<u32> = values.iter().copied().filter(|&v| v > 0).collect();
+    if valid.is_empty() {
+        return 0.0;
+    }
+    valid.iter().sum::<u32>() as f32 / valid.len() as f32
+}
+
+// Samples of the interval being rowed
+struct IntervalSamples {
+    interval_number: u32,
+    start: InstantWorkoutValues,
+    end: InstantWorkoutValues,
+    seconds_per_500m: Vec<u32>,
+    strokes_per_minute: Vec<u32>,
+    heart_rate: Vec<u32>,
+    power_in_watts: Vec<u32>,
+}
+
+impl IntervalSamples {
+    fn add(&mut self, iwv: &InstantWorkoutValues) {
+        self.seconds_per_500m.push(iwv.seconds_per_500m);
+        self.strokes_per_minute.push(iwv.strokes_per_minute);
+        self.heart_rate.push(iwv.heart_rate);
+        self.power_in_watts.push(iwv.power_in_watts);
+    }
+}
+
+/// Observer writing a row per interval to intervals.csv
+///
+/// An interval ends with the sample that completes it, which is the start of
+/// the next interval if there is no rest in between.
+pub struct IntervalFiles {
+    intervals: Vec<PlanInterval>,
+    writer: DataFileWriter,
+    current: Option<IntervalSamples>,
+    last_interval_number: u32,
+}
+
+/// Creates the interval data file of a plan in `workout_path`
+pub fn interval_files_init(
+    workout_path: &Path,
+    plan: &WorkoutPlan,
+) -> Result<IntervalFiles, WaterRowerError> {
+    Ok(IntervalFiles {
+        intervals: plan.intervals(),
+        writer: wr_utils::data_file_writer_init(
+            &workout_path.join(INTERVAL_DATA_FILE),
+            &INTERVAL_DATA_HEADER,
+        )?,
+        current: None,
+        last_interval_number: 0,
+    })
+}
+
+impl IntervalFiles {
+    fn interval_write(&mut self, samples: IntervalSamples) -> Result<(), WaterRowerError> {
+        let plan_interval = &self.intervals[samples.interval_number as usize - 1];
+        self.writer.write(&IntervalValues {
+            interval_number: samples.interval_number,
+            label: plan_interval.label.clone(),
+            target: plan_interval.interval.target.to_string(),
+            stroke_rate_target: plan_interval.stroke_rate,
+            time_in_seconds: samples
+                .end
+                .time_in_seconds
+                .saturating_sub(samples.start.time_in_seconds),
+            distance_in_meters: samples
+                .end
+                .distance_in_meters
+                .saturating_sub(samples.start.distance_in_meters),
+            stroke_count: samples
+                .end
+                .stroke_count
+                .saturating_sub(samples.start.stroke_count),
+            seconds_per_500m_avg: average_valid(&samples.seconds_per_500m),
+            strokes_per_minute_avg: average_valid(&samples.strokes_per_minute),
+            heart_rate_avg: average_valid(&samples.heart_rate),
+            power_in_watts_avg: average_valid(&samples.power_in_watts),
+            rest_in_seconds: plan_interval.interval.rest_in_seconds,
+        })
+    }
+}
+
+impl WorkoutObserver for IntervalFiles {
+    fn on_sample(&mut self, iwv: &InstantWorkoutValues) -> Result<(), WaterRowerError> {
+        let number = iwv.interval_number;
+        if number == 0 || number as usize > self.intervals.len() {
+            return Ok(());
+        }
+        if let Some(samples) = self.current.as_mut() {
+            samples.end = iwv.clone();
+            if iwv.interval_resting || number != samples.interval_number {
+                let samples = self.current.take().unwrap();
+                self.interval_write(samples)?;
+            } else {
+                samples.add(iwv);
+            }
+        }
+        if self.current.is_none() && !iwv.interval_resting && number != self.last_interval_number {
+            let mut samples = IntervalSamples {
+                interval_number: number,
+                start: iwv.clone(),
+                end: iwv.clone(),
+                seconds_per_500m: Vec::new(),
+                strokes_per_minute: Vec::new(),
+                heart_rate: Vec::new(),
+                power_in_watts: Vec::new(),
+            };
+            samples.add(iwv);
+            self.current = Some(samples);
+            self.last_interval_number = number;
+        }
+        Ok(())
+    }
+
+    fn on_finished(&mut self, _gwv: &GlobalWorkoutValues) -> Result<(), WaterRowerError> {
+        // The last interval, completed or not
+        if let Some(samples) = self.current.take() {
+            self.interval_write(samples)?;
+        }
+        self.writer.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAN: &str = r#"
+name = "8 x 500 m"
+warmup = { target = "5:00", stroke_rate = 18 }
+cooldown = { target = "1000m" }
+
+[[block]]
+repeat = 8
+target = "500m"
+rest = "1:30"
+stroke_rate = 28
+"#;
+
+    #[test]
+    fn plan_expanded() {
+        let plan = workout_plan_parse(PLAN, false).unwrap();
+        let intervals = plan.intervals();
+        assert_eq!(intervals.len(), 10);
+        assert_eq!(intervals[0].label, "Warm-up");
+        assert_eq!(intervals[3].label, "Block 1 (3/8)");
+        assert_eq!(intervals[3].interval.rest_in_seconds, 90);
+        assert_eq!(intervals[9].label, "Cool-down");
+        assert_eq!(
+            plan.to_string(),
+            "8 x 500 m (5:00, 8 x 500 m / 1:30 rest, 1000 m)"
+        );
+    }
+
+    #[test]
+    fn plan_invalid() {
+        let plans = [
+            "name = \"empty\"",
+            "[[block]]\nrepeat = 0\ntarget = \"500m\"",
+            "warmup = { target = \"5:00\", repeat = 2 }",
+            "cooldown = { target = \"1000m\", repeat = 0 }",
+            "[[block]]\ntarget = \"500m\"\nrest = \"2:00:00\"",
+        ];
+        for plan in plans.iter() {
+            assert!(
+                matches!(
+                    workout_plan_parse(plan, false),
+                    Err(WaterRowerError::WorkoutPlan(_))
+                ),
+                "{}",
+                plan
+            );
+        }
+        let json = r#"{"warmup": {"target": "5:00", "repeat": 3}, "block": [{"target": "500m"}]}"#;
+        assert!(workout_plan_parse(json, true).is_err());
+    }
+}
@@ -0,0 +1,432 @@
+//! Workouts programmed onto the S4 before rowing, and tracking of their progress
+//!
+//! A program is a list of intervals, each with a distance or duration target
+//! and a rest after it. A single interval without rest is programmed as a plain
+//! distance or duration workout. Progress is tracked on the recorder's side, so
+//! interval ends and completion do not depend on what the S4 displays.
+
+use std::{fmt, time};
+
+use crate::{wr_protocol::S4Command, wr_utils::InstantWorkoutValues};
+
+// Limits of the S4 workout commands, distances in meters or strokes
+const DISTANCE_MAX: u32 = 0xFA00;
+const DURATION_MAX_IN_SECONDS: u32 = 18_000;
+const REST_MAX_IN_SECONDS: u32 = 3_600;
+
+const METERS_PER_MILE: f64 = 1609.344;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DistanceUnit {
+    Meters,
+    Miles,
+    Kilometers,
+    Strokes,
+}
+
+impl DistanceUnit {
+    // Unit as encoded in the S4 workout commands
+    fn code(self) -> u8 {
+        match self {
+            DistanceUnit::Meters => 1,
+            DistanceUnit::Miles => 2,
+            DistanceUnit::Kilometers => 3,
+            DistanceUnit::Strokes => 4,
+        }
+    }
+
+    // Length of a distance in this unit, strokes have none
+    fn meters(self, value: u32) -> Option<f64> {
+        match self {
+            DistanceUnit::Meters => Some(value as f64),
+            DistanceUnit::Miles => Some(METERS_PER_MILE * value as f64),
+            DistanceUnit::Kilometers => Some(1000.0 * value as f64),
+            DistanceUnit::Strokes => None,
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            DistanceUnit::Meters => "m",
+            DistanceUnit::Miles => "mi",
+            DistanceUnit::Kilometers => "km",
+            DistanceUnit::Strokes => "strokes",
+        }
+    }
+}
+
+/// What has to be rowed to complete an interval
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkoutTarget {
+    Distance { unit: DistanceUnit, value: u32 },
+    Duration { seconds: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorkoutInterval {
+    pub target: WorkoutTarget,
+    /// Rest after the interval, 0 for none
+    pub rest_in_seconds: u32,
+}
+
+/// Workout to be loaded onto the S4
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorkoutProgram {
+    pub intervals: Vec<WorkoutInterval>,
+}
+
+fn duration_format(f: &mut fmt::Formatter<'_>, seconds: u32) -> fmt::Result {
+    if seconds >= 3600 {
+        write!(
+            f,
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        write!(f, "{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+impl fmt::Display for WorkoutTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            WorkoutTarget::Distance { unit, value } => write!(f, "{} {}", value, unit.suffix()),
+            WorkoutTarget::Duration { seconds } => duration_format(f, seconds),
+        }
+    }
+}
+
+impl fmt::Display for WorkoutInterval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.target)?;
+        if self.rest_in_seconds > 0 {
+            write!(f, " / ")?;
+            duration_format(f, self.rest_in_seconds)?;
+            write!(f, " rest")?;
+        }
+        Ok(())
+    }
+}
+
+/// Repeated intervals are summarized, e.g. `4 x 500 m / 1:00 rest`
+impl fmt::Display for WorkoutProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut groups: Vec<(u32, &WorkoutInterval)> = Vec::new();
+        for interval in self.intervals.iter() {
+            match groups.last_mut() {
+                Some((count, last)) if *last == interval => *count += 1,
+                _ => groups.push((1, interval)),
+            }
+        }
+        for (i, (count, interval)) in groups.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            if *count > 1 {
+                write!(f, "{} x ", count)?;
+            }
+            write!(f, "{}", interval)?;
+        }
+        Ok(())
+    }
+}
+
+impl WorkoutProgram {
+    /// Commands loading the program onto the S4
+    pub fn commands(&self) -> Vec<S4Command> {
+        if let [interval] = self.intervals.as_slice() {
+            if interval.rest_in_seconds == 0 {
+                return vec![match interval.target {
+                    WorkoutTarget::Distance { unit, value } => S4Command::WorkoutDistance {
+                        unit: unit.code(),
+                        distance: value as u16,
+                    },
+                    WorkoutTarget::Duration { seconds } => S4Command::WorkoutDuration {
+                        seconds: seconds as u16,
+                    },
+                }];
+            }
+        }
+        self.intervals
+            .iter()
+            .map(|interval| match interval.target {
+                WorkoutTarget::Distance { unit, value } => S4Command::IntervalDistance {
+                    unit: unit.code(),
+                    distance: value as u16,
+                    rest: interval.rest_in_seconds as u16,
+                },
+                WorkoutTarget::Duration { seconds } => S4Command::IntervalDuration {
+                    seconds: seconds as u16,
+                    rest: interval.rest_in_seconds as u16,
+                },
+            })
+            .collect()
+    }
+}
+
+// Seconds given as `90s`, `1:30` or `1:00:00`
+fn seconds_parse(s: &str) -> Option<u32> {
+    if let Some(seconds) = s.strip_suffix('s') {
+        return seconds.parse().ok();
+    }
+    let parts = s
+        .split(':')
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    match parts.as_slice() {
+        [minutes, seconds] if *seconds < 60 => Some(60 * minutes + seconds),
+        [hours, minutes, seconds] if *minutes < 60 && *seconds < 60 => {
+            Some(3600 * hours + 60 * minutes + seconds)
+        }
+        _ => None,
+    }
+}
+
+/// Parses a distance (`2000m`, `5km`, `2mi`, `300strokes`) or duration (`30:00`, `1800s`)
+pub fn workout_target_parse(s: &str) -> Result<WorkoutTarget, String> {
+    let invalid = || {
+        format!(
+            "Invalid target '{}', expected e.g. 2000m, 5km, 2mi, 300strokes, 30:00 or 1800s",
+            s
+        )
+    };
+    // Longest suffixes first, as "m" ends "km" as well
+    for unit in [
+        DistanceUnit::Strokes,
+        DistanceUnit::Kilometers,
+        DistanceUnit::Miles,
+        DistanceUnit::Meters,
+    ] {
+        if let Some(value) = s.strip_suffix(unit.suffix()) {
+            let value: u32 = value.parse().map_err(|_| invalid())?;
+            let (length, per) = match unit.meters(value) {
+                Some(meters) => (meters, "meters"),
+                None => (value as f64, "strokes"),
+            };
+            if value == 0 || length > DISTANCE_MAX as f64 {
+                return Err(format!(
+                    "Distance of '{}' must be 1 to {} {}",
+                    s, DISTANCE_MAX, per
+                ));
+            }
+            return Ok(WorkoutTarget::Distance { unit, value });
+        }
+    }
+    let seconds = seconds_parse(s).ok_or_else(invalid)?;
+    if seconds == 0 || seconds > DURATION_MAX_IN_SECONDS {
+        return Err(format!(
+            "Duration of '{}' must be 1 to {} seconds",
+            s, DURATION_MAX_IN_SECONDS
+        ));
+    }
+    Ok(WorkoutTarget::Duration { seconds })
+}
+
+/// Parses a duration (`30:00`, `1800s`, `1:00:00`) in seconds
+pub fn workout_duration_parse(s: &str) -> Result<u32, String> {
+    seconds_parse(s)
+        .filter(|&seconds| seconds > 0)
+        .ok_or_else(|| format!("Invalid duration '{}', expected e.g. 30:00 or 1800s", s))
+}
+
+/// Parses a rest (`1:00`, `60s`) in seconds
+pub fn workout_rest_parse(s: &str) -> Result<u32, String> {
+    seconds_parse(s)
+        .filter(|&rest| rest <= REST_MAX_IN_SECONDS)
+        .ok_or_else(|| {
+            format!(
+                "Invalid rest '{}', expected e.g. 1:00 or 60s (at most {} seconds)",
+                s, REST_MAX_IN_SECONDS
+            )
+        })
+}
+
+/// Parses a workout program
+///
+/// A single target (`2000m`, `5km`, `2mi`, `300strokes`, `30:00`, `1800s`) is a
+/// plain workout. Intervals are given as `<target>/<rest>`, optionally repeated
+/// (`4x500m/1:00`), and separated by commas (`2000m/3:00,1000m/2:00,500m/1:00`).
+pub fn workout_program_parse(s: &str) -> Result<WorkoutProgram, String> {
+    let mut intervals = Vec::new();
+    let items: Vec<&str> = s.split(',').map(str::trim).collect();
+    for item in items.iter() {
+        let (count, interval) = match item.split_once('x') {
+            Some((count, interval)) if count.chars().all(|c| c.is_ascii_digit()) => {
+                let count: u32 = count
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| format!("Invalid repetition in '{}'", item))?;
+                (count, interval)
+            }
+            _ => (1, *item),
+        };
+        let (target, rest) = match interval.split_once('/') {
+            Some((target, rest)) => (target, workout_rest_parse(rest)?),
+            None if items.len() == 1 && count == 1 => (interval, 0),
+            None => return Err(format!("Interval '{}' needs a rest, e.g. 500m/1:00", item)),
+        };
+        let target = workout_target_parse(target)?;
+        for _ in 0..count {
+            intervals.push(WorkoutInterval {
+                target,
+                rest_in_seconds: rest,
+            });
+        }
+    }
+    if intervals.is_empty() {
+        return Err(format!("Workout program '{}' has no intervals", s));
+    }
+    Ok(WorkoutProgram { intervals })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IntervalPhase {
+    Work,
+    Rest,
+}
+
+/// Follows the progress through a program, sample by sample
+pub struct ProgramTracker {
+    program: WorkoutProgram,
+    index: usize,
+    phase: IntervalPhase,
+    phase_start: time::Instant,
+    start_distance_in_meters: u32,
+    start_stroke_count: u32,
+}
+
+impl ProgramTracker {
+    /// Starts tracking at the first stroke, counting from the distance and strokes of the first sample
+    pub fn new(
+        program: WorkoutProgram,
+        first_stroke: time::Instant,
+        first_sample: &InstantWorkoutValues,
+    ) -> Self {
+        ProgramTracker {
+            program,
+            index: 0,
+            phase: IntervalPhase::Work,
+            phase_start: first_stroke,
+            start_distance_in_meters: first_sample.distance_in_meters,
+            start_stroke_count: first_sample.stroke_count,
+        }
+    }
+
+    /// Whether the rower is resting between intervals, the S4's clock may stop then
+    pub fn resting(&self) -> bool {
+        self.phase == IntervalPhase::Rest
+    }
+
+    /// Sets interval and remaining target of a sample, returns whether the program is completed
+    pub fn update(&mut self, now: time::Instant, iwv: &mut InstantWorkoutValues) -> bool {
+        loop {
+            let interval = self.program.intervals[self.index];
+            let elapsed = now.saturating_duration_since(self.phase_start).as_secs() as u32;
+            iwv.interval_number = self.index as u32 + 1;
+            iwv.interval_resting = self.resting();
+            match self.phase {
+                IntervalPhase::Work => {
+                    let (done, target) = match interval.target {
+                        WorkoutTarget::Distance {
+                            unit: DistanceUnit::Strokes,
+                            value,
+                        } => (
+                            iwv.stroke_count.saturating_sub(self.start_stroke_count),
+                            value,
+                        ),
+                        WorkoutTarget::Distance { unit, value } => {
+                            let meters = unit.meters(value).unwrap_or(value as f64);
+                            (
+                                iwv.distance_in_meters
+                                    .saturating_sub(self.start_distance_in_meters),
+                                meters.round() as u32,
+                            )
+                        }
+                        WorkoutTarget::Duration { seconds } => (elapsed, seconds),
+                    };
+                    if done < target {
+                        iwv.interval_remaining = target - done;
+                        return false;
+                    }
+                    iwv.interval_remaining = 0;
+                    if self.index + 1 == self.program.intervals.len() {
+                        return true;
+                    }
+                    // Durations end exactly on time, distances with the sample reaching them
+                    self.phase_start = match interval.target {
+                        WorkoutTarget::Duration { seconds } => {
+                            self.phase_start + time::Duration::from_secs(seconds.into())
+                        }
+                        _ => now,
+                    };
+                    if interval.rest_in_seconds > 0 {
+                        self.phase = IntervalPhase::Rest;
+                    } else {
+                        self.interval_next(iwv);
+                    }
+                }
+                IntervalPhase::Rest => {
+                    if elapsed < interval.rest_in_seconds {
+                        iwv.interval_remaining = interval.rest_in_seconds - elapsed;
+                        return false;
+                    }
+                    self.phase_start += time::Duration::from_secs(interval.rest_in_seconds.into());
+                    self.phase = IntervalPhase::Work;
+                    self.interval_next(iwv);
+                }
+            }
+        }
+    }
+
+    fn interval_next(&mut self, iwv: &InstantWorkoutValues) {
+        self.index += 1;
+        self.start_distance_in_meters = iwv.distance_in_meters;
+        self.start_stroke_count = iwv.stroke_count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_distance_limit_in_meters() {
+        let distance = |unit, value| Ok(WorkoutTarget::Distance { unit, value });
+        assert_eq!(
+            workout_target_parse("64000m"),
+            distance(DistanceUnit::Meters, 64000)
+        );
+        assert_eq!(
+            workout_target_parse("64km"),
+            distance(DistanceUnit::Kilometers, 64)
+        );
+        assert_eq!(
+            workout_target_parse("39mi"),
+            distance(DistanceUnit::Miles, 39)
+        );
+        assert_eq!(
+            workout_target_parse("64000strokes"),
+            distance(DistanceUnit::Strokes, 64000)
+        );
+        for target in ["0m", "64001m", "65km", "40mi", "64001strokes"].iter() {
+            assert!(workout_target_parse(target).is_err(), "{}", target);
+        }
+    }
+
+    #[test]
+    fn program_repetitions() {
+        let program = workout_program_parse("2x500m/1:00,1000m/2:00").unwrap();
+        assert_eq!(program.intervals.len(), 3);
+        assert_eq!(
+            program.to_string(),
+            "2 x 500 m / 1:00 rest, 1000 m / 2:00 rest"
+        );
+        assert_eq!(program.commands().len(), 3);
+        for program in ["0x500m", "0x500m/1:00,1000m/2:00", "2000m/3:00,0x500m/1:00"].iter() {
+            assert!(workout_program_parse(program).is_err(), "{}", program);
+        }
+    }
+}
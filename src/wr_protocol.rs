@@ -0,0 +1,512 @@
+//! Messages and commands of the S4 serial protocol
+
+use std::{error, fmt};
+
+const CMD_START: &str = "USB";
+const CMD_STOP: &str = "EXIT";
+const CMD_RESET: &str = "RESET";
+const CMD_MODEL_INFO: &str = "IV?";
+const CMD_READ_1_BYTE: &str = "IRS";
+const CMD_READ_2_BYTES: &str = "IRD";
+const CMD_READ_3_BYTES: &str = "IRT";
+const CMD_WORKOUT_DISTANCE: &str = "WSI"; // WSI + Unit + Distance
+const CMD_WORKOUT_DURATION: &str = "WSU"; // WSU + Seconds
+const CMD_INTERVAL_DISTANCE: &str = "WII"; // WII + Unit + Distance + Rest Seconds
+const CMD_INTERVAL_DURATION: &str = "WIU"; // WIU + Seconds + Rest Seconds
+
+const RET_OK: &str = "OK";
+const RET_ERROR: &str = "ERROR";
+const RET_HW_TYPE: &str = "_WR_";
+const RET_MODEL_INFO: &str = "IV"; // IV + Model (4/5) + Version High + Version Low
+const RET_DATA_1_BYTE: &str = "IDS"; // IDS + Memory Addr + 1st Byte
+const RET_DATA_2_BYTES: &str = "IDD"; // IDD + Memory Addr + 2nd Byte + 1st Byte
+const RET_DATA_3_BYTES: &str = "IDT"; // IDT + Memory Addr + 3rd Byte + 2nd Byte + 1st Byte
+
+const WR_STROKE_START: &str = "SS";
+const WR_STROKE_END: &str = "SE";
+const WR_PING: &str = "PING";
+const WR_PULSE: &str = "P"; // P + Pulse Count within the last 25 ms
+
+/// Number of bytes read from or returned for a memory address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataSize {
+    Single,
+    Double,
+    Triple,
+}
+
+impl DataSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            DataSize::Single => 1,
+            DataSize::Double => 2,
+            DataSize::Triple => 3,
+        }
+    }
+}
+
+/// Line that is not a valid S4 message or command
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError(pub String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid S4 message: {}", self.0)
+    }
+}
+
+impl error::Error for ParseError {}
+
+fn parse_hex(digits: &str, line: &str) -> Result<u32, ParseError> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ParseError(line.to_owned()));
+    }
+    u32::from_str_radix(digits, 16).map_err(|_| ParseError(line.to_owned()))
+}
+
+// Splits `<address><value>` into memory address and value of the given size
+fn parse_address_and_value(
+    data: &str,
+    size: DataSize,
+    line: &str,
+) -> Result<(u16, u32), ParseError> {
+    // Raw serial input may hold any character, slicing requires ASCII
+    if data.len() != 3 + 2 * size.bytes() || !data.is_ascii() {
+        return Err(ParseError(line.to_owned()));
+    }
+    let address = parse_hex(&data[0..3], line)? as u16;
+    let value = parse_hex(&data[3..], line)?;
+    Ok((address, value))
+}
+
+/// Message sent by the S4 performance monitor
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum S4Message {
+    HardwareType,
+    ModelInfo {
+        model: u8,
+        fw_major: u8,
+        fw_minor: u8,
+    },
+    Data {
+        size: DataSize,
+        address: u16,
+        value: u32,
+    },
+    StrokeStart,
+    StrokeEnd,
+    Ping,
+    Pulse(u32),
+    Ok,
+    Error,
+}
+
+impl S4Message {
+    /// Parses a single line received from the S4
+    pub fn parse(line: &str) -> Result<S4Message, ParseError> {
+        let data_size = |prefix: &str| match prefix {
+            RET_DATA_1_BYTE => Some(DataSize::Single),
+            RET_DATA_2_BYTES => Some(DataSize::Double),
+            RET_DATA_3_BYTES => Some(DataSize::Triple),
+            _ => None,
+        };
+        match line {
+            RET_HW_TYPE => Ok(S4Message::HardwareType),
+            RET_OK => Ok(S4Message::Ok),
+            RET_ERROR => Ok(S4Message::Error),
+            WR_STROKE_START => Ok(S4Message::StrokeStart),
+            WR_STROKE_END => Ok(S4Message::StrokeEnd),
+            WR_PING => Ok(S4Message::Ping),
+            _ => {
+                if let Some(size) = line.get(0..3).and_then(data_size) {
+                    let (address, value) = parse_address_and_value(&line[3..], size, line)?;
+                    Ok(S4Message::Data {
+                        size,
+                        address,
+                        value,
+                    })
+                } else if let Some(info) = line.strip_prefix(RET_MODEL_INFO) {
+                    if info.len() != 5 || !info.chars().all(|c| c.is_ascii_digit()) {
+                        return Err(ParseError(line.to_owned()));
+                    }
+                    Ok(S4Message::ModelInfo {
+                        model: info[0..1].parse().unwrap(),
+                        fw_major: info[1..3].parse().unwrap(),
+                        fw_minor: info[3..5].parse().unwrap(),
+                    })
+                } else if let Some(count) = line.strip_prefix(WR_PULSE) {
+                    Ok(S4Message::Pulse(parse_hex(count, line)?))
+                } else {
+                    Err(ParseError(line.to_owned()))
+                }
+            }
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        match self {
+            S4Message::HardwareType => String::from(RET_HW_TYPE),
+            S4Message::ModelInfo {
+                model,
+                fw_major,
+                fw_minor,
+            } => format!("{}{}{:02}{:02}", RET_MODEL_INFO, model, fw_major, fw_minor),
+            S4Message::Data {
+                size,
+                address,
+                value,
+            } => {
+                let prefix = match size {
+                    DataSize::Single => RET_DATA_1_BYTE,
+                    DataSize::Double => RET_DATA_2_BYTES,
+                    DataSize::Triple => RET_DATA_3_BYTES,
+                };
+                format!(
+                    "{}{:03X}{:0width$X}",
+                    prefix,
+                    address,
+                    value,
+                    width = 2 * size.bytes()
+                )
+            }
+            S4Message::StrokeStart => String::from(WR_STROKE_START),
+            S4Message::StrokeEnd => String::from(WR_STROKE_END),
+            S4Message::Ping => String::from(WR_PING),
+            S4Message::Pulse(count) => format!("{}{:02X}", WR_PULSE, count),
+            S4Message::Ok => String::from(RET_OK),
+            S4Message::Error => String::from(RET_ERROR),
+        }
+    }
+}
+
+/// Command sent to the S4 performance monitor
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum S4Command {
+    Start,
+    Stop,
+    Reset,
+    ModelInfo,
+    Read {
+        size: DataSize,
+        address: u16,
+    },
+    /// Programs a distance workout, the unit is 1 (meters), 2 (miles), 3 (km) or 4 (strokes)
+    WorkoutDistance {
+        unit: u8,
+        distance: u16,
+    },
+    /// Programs a duration workout
+    WorkoutDuration {
+        seconds: u16,
+    },
+    /// Adds a distance interval followed by a rest to the interval workout
+    IntervalDistance {
+        unit: u8,
+        distance: u16,
+        rest: u16,
+    },
+    /// Adds a duration interval followed by a rest to the interval workout
+    IntervalDuration {
+        seconds: u16,
+        rest: u16,
+    },
+}
+
+// Parses `<unit digit>` followed by 4 hex digits per value
+fn parse_workout(
+    data: &str,
+    unit: bool,
+    values: usize,
+    line: &str,
+) -> Result<(u8, Vec<u16>), ParseError> {
+    let offset = if unit { 1 } else { 0 };
+    if data.len() != offset + 4 * values || !data.is_ascii() {
+        return Err(ParseError(line.to_owned()));
+    }
+    let unit = if unit {
+        match data[0..1].parse() {
+            Ok(unit @ 1..=4) => unit,
+            _ => return Err(ParseError(line.to_owned())),
+        }
+    } else {
+        0
+    };
+    let values = (0..values)
+        .map(|i| parse_hex(&data[offset + 4 * i..offset + 4 * (i + 1)], line).map(|v| v as u16))
+        .collect::<Result<Vec<u16>, ParseError>>()?;
+    Ok((unit, values))
+}
+
+impl S4Command {
+    /// Parses a single line sent to the S4
+    pub fn parse(line: &str) -> Result<S4Command, ParseError> {
+        match line {
+            CMD_START => Ok(S4Command::Start),
+            CMD_STOP => Ok(S4Command::Stop),
+            CMD_RESET => Ok(S4Command::Reset),
+            CMD_MODEL_INFO => Ok(S4Command::ModelInfo),
+            _ => {
+                let data = line.get(3..).unwrap_or("");
+                match line.get(0..3) {
+                    Some(CMD_WORKOUT_DISTANCE) => {
+                        let (unit, values) = parse_workout(data, true, 1, line)?;
+                        return Ok(S4Command::WorkoutDistance {
+                            unit,
+                            distance: values[0],
+                        });
+                    }
+                    Some(CMD_WORKOUT_DURATION) => {
+                        let (_, values) = parse_workout(data, false, 1, line)?;
+                        return Ok(S4Command::WorkoutDuration { seconds: values[0] });
+                    }
+                    Some(CMD_INTERVAL_DISTANCE) => {
+                        let (unit, values) = parse_workout(data, true, 2, line)?;
+                        return Ok(S4Command::IntervalDistance {
+                            unit,
+                            distance: values[0],
+                            rest: values[1],
+                        });
+                    }
+                    Some(CMD_INTERVAL_DURATION) => {
+                        let (_, values) = parse_workout(data, false, 2, line)?;
+                        return Ok(S4Command::IntervalDuration {
+                            seconds: values[0],
+                            rest: values[1],
+                        });
+                    }
+                    _ => (),
+                }
+                let size = match line.get(0..3) {
+                    Some(CMD_READ_1_BYTE) => DataSize::Single,
+                    Some(CMD_READ_2_BYTES) => DataSize::Double,
+                    Some(CMD_READ_3_BYTES) => DataSize::Triple,
+                    _ => return Err(ParseError(line.to_owned())),
+                };
+                if line.len() != 6 {
+                    return Err(ParseError(line.to_owned()));
+                }
+                let address = parse_hex(&line[3..6], line)? as u16;
+                Ok(S4Command::Read { size, address })
+            }
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        match self {
+            S4Command::Start => String::from(CMD_START),
+            S4Command::Stop => String::from(CMD_STOP),
+            S4Command::Reset => String::from(CMD_RESET),
+            S4Command::ModelInfo => String::from(CMD_MODEL_INFO),
+            S4Command::Read { size, address } => {
+                let prefix = match size {
+                    DataSize::Single => CMD_READ_1_BYTE,
+                    DataSize::Double => CMD_READ_2_BYTES,
+                    DataSize::Triple => CMD_READ_3_BYTES,
+                };
+                format!("{}{:03X}", prefix, address)
+            }
+            S4Command::WorkoutDistance { unit, distance } => {
+                format!("{}{}{:04X}", CMD_WORKOUT_DISTANCE, unit, distance)
+            }
+            S4Command::WorkoutDuration { seconds } => {
+                format!("{}{:04X}", CMD_WORKOUT_DURATION, seconds)
+            }
+            S4Command::IntervalDistance {
+                unit,
+                distance,
+                rest,
+            } => format!(
+                "{}{}{:04X}{:04X}",
+                CMD_INTERVAL_DISTANCE, unit, distance, rest
+            ),
+            S4Command::IntervalDuration { seconds, rest } => {
+                format!("{}{:04X}{:04X}", CMD_INTERVAL_DURATION, seconds, rest)
+            }
+        }
+    }
+}
+
+/// Decodes a value the S4 stores as binary-coded decimal, e.g. display time
+pub fn bcd_decode(value: u32) -> u32 {
+    let mut decoded = 0;
+    let mut factor = 1;
+    let mut remaining = value;
+    while remaining > 0 {
+        decoded += (remaining & 0xF) * factor;
+        factor *= 10;
+        remaining >>= 4;
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        let cases = [
+            ("_WR_", S4Message::HardwareType),
+            (
+                "IV41002",
+                S4Message::ModelInfo {
+                    model: 4,
+                    fw_major: 10,
+                    fw_minor: 2,
+                },
+            ),
+            (
+                "IDS1A01F",
+                S4Message::Data {
+                    size: DataSize::Single,
+                    address: 0x1A0,
+                    value: 0x1F,
+                },
+            ),
+            (
+                "IDD055002A",
+                S4Message::Data {
+                    size: DataSize::Double,
+                    address: 0x055,
+                    value: 0x002A,
+                },
+            ),
+            (
+                "IDT08A123456",
+                S4Message::Data {
+                    size: DataSize::Triple,
+                    address: 0x08A,
+                    value: 0x123456,
+                },
+            ),
+            ("SS", S4Message::StrokeStart),
+            ("SE", S4Message::StrokeEnd),
+            ("PING", S4Message::Ping),
+            ("P0A", S4Message::Pulse(10)),
+            ("OK", S4Message::Ok),
+            ("ERROR", S4Message::Error),
+        ];
+        for (line, message) in cases.iter() {
+            assert_eq!(S4Message::parse(line).as_ref(), Ok(message), "{}", line);
+            assert_eq!(&message.encode(), line);
+        }
+    }
+
+    #[test]
+    fn message_invalid() {
+        let cases = [
+            "",
+            "WR",
+            "IDS00",
+            "IDS00\u{e9}1",
+            "IDD055002",
+            "IDD05G002A",
+            "IDT08A12345\u{e9}",
+            "IV4100",
+            "IV4a002",
+            "P",
+            "PXY",
+            "\u{e9}\u{e9}\u{e9}",
+        ];
+        for line in cases.iter() {
+            assert_eq!(
+                S4Message::parse(line),
+                Err(ParseError((*line).to_owned())),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn command_round_trip() {
+        let cases = [
+            ("USB", S4Command::Start),
+            ("EXIT", S4Command::Stop),
+            ("RESET", S4Command::Reset),
+            ("IV?", S4Command::ModelInfo),
+            (
+                "IRS1A0",
+                S4Command::Read {
+                    size: DataSize::Single,
+                    address: 0x1A0,
+                },
+            ),
+            (
+                "IRD057",
+                S4Command::Read {
+                    size: DataSize::Double,
+                    address: 0x057,
+                },
+            ),
+            (
+                "IRT08A",
+                S4Command::Read {
+                    size: DataSize::Triple,
+                    address: 0x08A,
+                },
+            ),
+            (
+                "WSI107D0",
+                S4Command::WorkoutDistance {
+                    unit: 1,
+                    distance: 2000,
+                },
+            ),
+            ("WSU0708", S4Command::WorkoutDuration { seconds: 1800 }),
+            (
+                "WII101F4003C",
+                S4Command::IntervalDistance {
+                    unit: 1,
+                    distance: 500,
+                    rest: 60,
+                },
+            ),
+            (
+                "WIU012C003C",
+                S4Command::IntervalDuration {
+                    seconds: 300,
+                    rest: 60,
+                },
+            ),
+        ];
+        for (line, command) in cases.iter() {
+            assert_eq!(S4Command::parse(line).as_ref(), Ok(command), "{}", line);
+            assert_eq!(&command.encode(), line);
+        }
+    }
+
+    #[test]
+    fn command_invalid() {
+        let cases = [
+            "",
+            "IRS",
+            "IRS1A",
+            "IRS1A0F",
+            "IRSXYZ",
+            "WSI507D0",
+            "WSI107D",
+            "WSU07\u{e9}",
+            "WII101F4003",
+            "USBX",
+        ];
+        for line in cases.iter() {
+            assert!(S4Command::parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn bcd() {
+        let cases = [
+            (0x00, 0),
+            (0x09, 9),
+            (0x10, 10),
+            (0x59, 59),
+            (0x123, 123),
+            (0x2359, 2359),
+        ];
+        for (value, decoded) in cases.iter() {
+            assert_eq!(bcd_decode(*value), *decoded, "{:#X}", value);
+        }
+    }
+}
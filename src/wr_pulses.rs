@@ -0,0 +1,63 @@
+//! Paddle pulses reported by the S4 and the boat speed derived from them
+
+use std::{collections::VecDeque, time};
+
+use crate::wr_utils::DataRow;
+
+pub const PULSE_DATA_FILE: &str = "pulses.csv";
+
+pub const PULSE_DATA_HEADER: [&str; 5] = [
+    "Time in Seconds",
+    "Pulse Count",
+    "Speed in Meters per Second",
+    "Stroke Number",
+    "Time in Stroke in Seconds",
+];
+
+pub const PULSES_PER_METER: f32 = 4.805;
+// A single message holds only a few pulses, so speed is averaged over a short window
+const SPEED_WINDOW: time::Duration = time::Duration::from_millis(250);
+
+/// Paddle pulses reported by one P message, with the boat speed derived from them
+#[derive(Clone, Debug)]
+pub struct PulseValues {
+    /// Arrival relative to the first stroke of the workout
+    pub time_in_seconds: f32,
+    pub pulse_count: u32,
+    pub speed_in_meters_per_second: f32,
+    /// Stroke the pulses belong to, counted from 1
+    pub stroke_number: u32,
+    pub time_in_stroke_in_seconds: f32,
+}
+
+impl DataRow for PulseValues {
+    fn data_row(&self) -> Vec<String> {
+        vec![
+            format!("{:.3}", self.time_in_seconds),
+            format!("{}", self.pulse_count),
+            format!("{:.2}", self.speed_in_meters_per_second),
+            format!("{}", self.stroke_number),
+            format!("{:.3}", self.time_in_stroke_in_seconds),
+        ]
+    }
+}
+
+/// Derives the instantaneous boat speed from the pulses of the recent past
+#[derive(Default)]
+pub struct SpeedEstimator {
+    window: VecDeque<(time::Instant, u32)>,
+}
+
+impl SpeedEstimator {
+    pub fn push(&mut self, now: time::Instant, pulse_count: u32) -> f32 {
+        self.window.push_back((now, pulse_count));
+        while let Some(&(received, _)) = self.window.front() {
+            if now.duration_since(received) < SPEED_WINDOW {
+                break;
+            }
+            self.window.pop_front();
+        }
+        let pulses: u32 = self.window.iter().map(|&(_, count)| count).sum();
+        pulses as f32 / PULSES_PER_METER / SPEED_WINDOW.as_secs_f32()
+    }
+}
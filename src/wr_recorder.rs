@@ -0,0 +1,761 @@
+//! Recording of complete workout sessions, as done by the command line tool
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use crate::{
+    wr_capture::ReplayTransport,
+    wr_columns::{WorkoutDataColumn, WorkoutDataRow},
+    wr_error::WaterRowerError,
+    wr_plan::{self, WorkoutPlan},
+    wr_program::WorkoutProgram,
+    wr_pulses::PulseValues,
+    wr_registers::RegisterMap,
+    wr_segments::{self, WorkoutSegment},
+    wr_session::{FinishCriteria, SessionEvent},
+    wr_strokes::StrokeValues,
+    wr_transport::{self, Transport},
+    wr_utils::{
+        self, DataFileWriter, DebugTrace, GlobalWorkoutValues, InstantWorkoutValues, WorkoutContext,
+    },
+};
+
+// Time between attempts to connect to an S4 that is not available
+const DAEMON_RECONNECT_INTERVAL: time::Duration = time::Duration::from_secs(5);
+const DAEMON_STOP_CHECK_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Options of a workout recording
+pub struct RecordConfig {
+    /// Serial device, `tcp://<host>:<port>` or `trace://<file>`
+    pub serial_dev: String,
+    /// Directory the workout's directory is created in
+    pub workout_dir: PathBuf,
+    /// Time between two recorded datapoints
+    pub interval: time::Duration,
+    /// Also write the paddle pulse stream to pulses.csv
+    pub pulses: bool,
+    /// Capture the communication with the S4 to this file, see [`workout_replay`]
+    pub capture: Option<PathBuf>,
+    /// Registers of the S4, see [`crate::wr_registers`]
+    pub registers: RegisterMap,
+    /// Columns of the workout data file, only their registers are polled
+    pub columns: Vec<WorkoutDataColumn>,
+    /// Workout loaded onto the S4 before the first stroke, the recording ends when it is completed
+    pub program: Option<WorkoutProgram>,
+    /// Plan followed during the recording, summed up per interval in intervals.csv
+    ///
+    /// Without a plan, the intervals of `program` are summed up.
+    pub plan: Option<WorkoutPlan>,
+    /// When the workout is finished, besides a stop request
+    pub finish: FinishCriteria,
+    /// Receives debug traces, of the recording steps and the communication with the S4
+    pub debug: Option<DebugTrace>,
+}
+
+/// Receives the progress of a workout recording
+///
+/// All methods do nothing by default, so observers implement only what they
+/// need. An error returned by an observer interrupts the recording.
+pub trait WorkoutObserver {
+    /// The S4 identified itself, `gwv` holds its model, the program and the start time
+    fn on_connected(&mut self, _gwv: &GlobalWorkoutValues) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    /// The recording waits for the first stroke on the S4
+    fn on_waiting(&mut self) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    fn on_first_stroke(&mut self) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    /// A datapoint was recorded, once per sampling interval
+    fn on_sample(&mut self, _iwv: &InstantWorkoutValues) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    fn on_stroke(&mut self, _stroke: &StrokeValues) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    fn on_pulse(&mut self, _pulse: &PulseValues) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    /// No stroke was started for a while, the workout is paused until the next one
+    fn on_paused(&mut self) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    fn on_resumed(&mut self) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    /// Something failed without ending the recording, e.g. a sample was skipped
+    fn on_warning(&mut self, _warning: &WaterRowerError) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    /// The workout was split into work and rest segments, before it is finished
+    fn on_segments(&mut self, _segments: &[WorkoutSegment]) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    /// The daemon lost the connection to the S4 or could not open it, it retries
+    fn on_connection_lost(&mut self, _error: &WaterRowerError) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+
+    /// The recording ended, also when interrupted, `gwv` holds the workout summary
+    fn on_finished(&mut self, _gwv: &GlobalWorkoutValues) -> Result<(), WaterRowerError> {
+        Ok(())
+    }
+}
+
+/// Observer writing a workout's CSV files to its directory
+pub struct WorkoutFiles {
+    pub workout_path: PathBuf,
+    workout_data_writer: DataFileWriter,
+    columns: Vec<WorkoutDataColumn>,
+    stroke_data_writer: DataFileWriter,
+    pulse_data_writer: Option<DataFileWriter>,
+}
+
+/// Creates the data files in `workout_path`, pulses.csv only if `pulses` is set
+pub fn workout_files_init(
+    workout_path: &Path,
+    pulses: bool,
+    columns: &[WorkoutDataColumn],
+) -> Result<WorkoutFiles, WaterRowerError> {
+    Ok(WorkoutFiles {
+        workout_path: workout_path.to_path_buf(),
+        workout_data_writer: wr_utils::workout_data_writer_init(workout_path, columns)?,
+        columns: columns.to_vec(),
+        stroke_data_writer: wr_utils::stroke_data_writer_init(workout_path)?,
+        pulse_data_writer: if pulses {
+            Some(wr_utils::pulse_data_writer_init(workout_path)?)
+        } else {
+            None
+        },
+    })
+}
+
+impl WorkoutObserver for WorkoutFiles {
+    fn on_connected(&mut self, gwv: &GlobalWorkoutValues) -> Result<(), WaterRowerError> {
+        // Store what is known already, so an aborted workout can be recovered
+        wr_utils::write_meta_data_file(&self.workout_path, gwv)
+    }
+
+    fn on_sample(&mut self, iwv: &InstantWorkoutValues) -> Result<(), WaterRowerError> {
+        self.workout_data_writer.write(&WorkoutDataRow {
+            values: iwv,
+            columns: &self.columns,
+        })
+    }
+
+    fn on_stroke(&mut self, stroke: &StrokeValues) -> Result<(), WaterRowerError> {
+        self.stroke_data_writer.write(stroke)
+    }
+
+    fn on_pulse(&mut self, pulse: &PulseValues) -> Result<(), WaterRowerError> {
+        match self.pulse_data_writer.as_mut() {
+            Some(writer) => writer.write(pulse),
+            None => Ok(()),
+        }
+    }
+
+    fn on_finished(&mut self, gwv: &GlobalWorkoutValues) -> Result<(), WaterRowerError> {
+        self.workout_data_writer.sync()?;
+        self.stroke_data_writer.sync()?;
+        if let Some(writer) = self.pulse_data_writer.as_mut() {
+            writer.sync()?;
+        }
+        wr_utils::write_meta_data_file(&self.workout_path, gwv)
+    }
+}
+
+fn observers_notify<F>(
+    observers: &mut [&mut dyn WorkoutObserver],
+    mut notify: F,
+) -> Result<(), WaterRowerError>
+where
+    F: FnMut(&mut dyn WorkoutObserver) -> Result<(), WaterRowerError>,
+{
+    observers
+        .iter_mut()
+        .try_for_each(|observer| notify(&mut **observer))
+}
+
+// Passes on pauses and failures the session reported besides samples, strokes and pulses
+fn session_events_notify(
+    observers: &mut [&mut dyn WorkoutObserver],
+    events: &[SessionEvent],
+) -> Result<(), WaterRowerError> {
+    let rejected = WaterRowerError::Protocol(String::from("WaterRower rejected a command"));
+    observers_notify(observers, |o| {
+        events.iter().try_for_each(|event| match event {
+            SessionEvent::Paused => o.on_paused(),
+            SessionEvent::Resumed => o.on_resumed(),
+            SessionEvent::SampleSkipped(e) | SessionEvent::RegisterDropped(e) => o.on_warning(e),
+            SessionEvent::CommandRejected => o.on_warning(&rejected),
+            _ => Ok(()),
+        })
+    })
+}
+
+// Splits the workout into work and rest segments, a failure to write them does not end the recording
+fn segments_write(
+    workout_path: &Path,
+    datapoints: &[InstantWorkoutValues],
+    observers: &mut [&mut dyn WorkoutObserver],
+) -> Result<(), WaterRowerError> {
+    let segments = wr_segments::workout_segments_detect(datapoints);
+    observers_notify(observers, |o| o.on_segments(&segments))?;
+    if let Err(e) = wr_segments::write_segment_data_file(workout_path, &segments) {
+        observers_notify(observers, |o| o.on_warning(&e))?;
+    }
+    Ok(())
+}
+
+/// Records a workout from first stroke until it is finished on the S4 or stop is requested
+///
+/// Data is written to disk as it is recorded, so it is kept when recording is
+/// interrupted. The `observers` are notified alongside the CSV files, e.g. to
+/// show or publish the workout live. Returns the directory the workout was
+/// stored in.
+pub fn workout_record(
+    config: &RecordConfig,
+    stop_requested: Arc<AtomicBool>,
+    observers: &mut [&mut dyn WorkoutObserver],
+) -> Result<PathBuf, WaterRowerError> {
+    let port = wr_transport::transport_open(&config.serial_dev)?;
+    workout_run(config, port, stop_requested, observers)
+}
+
+/// Records a workout again from a capture, reproducing the data files of the captured recording
+///
+/// The sampling interval is the one of the captured recording, `config.serial_dev`
+/// and `config.interval` are ignored. The replay stops at the end of the capture.
+/// Only columns recorded in the captured recording can be selected, and a
+/// programmed workout is followed only if `config.program` is the captured one.
+pub fn workout_replay(
+    capture_file: &Path,
+    config: &RecordConfig,
+    observers: &mut [&mut dyn WorkoutObserver],
+) -> Result<PathBuf, WaterRowerError> {
+    let replay = ReplayTransport::open(capture_file)?;
+    let end_of_capture = replay.end_of_capture();
+    let replay_config = RecordConfig {
+        serial_dev: capture_file.display().to_string(),
+        workout_dir: config.workout_dir.clone(),
+        interval: replay.interval().unwrap_or(config.interval),
+        pulses: config.pulses,
+        capture: None,
+        registers: config.registers.clone(),
+        columns: config.columns.clone(),
+        program: config.program.clone(),
+        plan: config.plan.clone(),
+        finish: config.finish.clone(),
+        debug: config.debug,
+    };
+    workout_run(&replay_config, Box::new(replay), end_of_capture, observers)
+}
+
+// Connects to the S4 with the session set up as configured
+fn workout_context_open(
+    config: &RecordConfig,
+    port: Box<dyn Transport>,
+    stop_requested: Arc<AtomicBool>,
+) -> Result<WorkoutContext, WaterRowerError> {
+    let debug = config.debug;
+    if let Some(trace) = debug {
+        trace("--- Initializing workout context ...");
+    }
+    let mut workout_context = wr_utils::workout_context_init(port, config.interval, debug);
+    workout_context.stop_requested = stop_requested;
+    workout_context
+        .session
+        .register_map_set(config.registers.clone());
+    wr_utils::workout_data_columns_select(&mut workout_context, &config.columns);
+    workout_context
+        .session
+        .finish_criteria_set(config.finish.clone());
+    if let Some(capture_file) = config.capture.as_ref() {
+        wr_utils::capture_start(&mut workout_context, capture_file)?;
+    }
+
+    if let Some(trace) = debug {
+        trace("--- Starting WaterRower communication ...");
+    }
+    wr_utils::start(&mut workout_context)?;
+    Ok(workout_context)
+}
+
+// Starts the workout summary and loads the program, returns the plan whose intervals are summed up
+fn workout_begin(
+    config: &RecordConfig,
+    workout_context: &mut WorkoutContext,
+) -> Result<(GlobalWorkoutValues, Option<WorkoutPlan>), WaterRowerError> {
+    if let Some(trace) = config.debug {
+        trace("--- Initializing global workout values ...");
+    }
+    let mut global_workout_values = wr_utils::global_workout_values_init(workout_context)?;
+
+    if let Some(program) = config.program.as_ref() {
+        if let Some(trace) = config.debug {
+            trace("--- Programming workout ...");
+        }
+        wr_utils::program_load(workout_context, program)?;
+        global_workout_values.workout_program = program.to_string();
+    }
+    let plan = match (config.plan.as_ref(), config.program.as_ref()) {
+        (Some(plan), _) => {
+            workout_context.session.program_track(&plan.program());
+            global_workout_values.workout_program = plan.to_string();
+            Some(plan.clone())
+        }
+        (None, Some(program)) => Some(wr_plan::workout_plan_from_program(program)),
+        (None, None) => None,
+    };
+    Ok((global_workout_values, plan))
+}
+
+pub(crate) fn workout_run(
+    config: &RecordConfig,
+    port: Box<dyn Transport>,
+    stop_requested: Arc<AtomicBool>,
+    observers: &mut [&mut dyn WorkoutObserver],
+) -> Result<PathBuf, WaterRowerError> {
+    let mut workout_context = workout_context_open(config, port, stop_requested)?;
+    let (global_workout_values, plan) = workout_begin(config, &mut workout_context)?;
+    workout_finish_recording(
+        config,
+        &mut workout_context,
+        global_workout_values,
+        plan.as_ref(),
+        observers,
+    )
+}
+
+// Records the workout into its directory until it is finished, waiting for the first stroke if
+// it was not detected yet, and closes the communication
+fn workout_finish_recording(
+    config: &RecordConfig,
+    workout_context: &mut WorkoutContext,
+    mut global_workout_values: GlobalWorkoutValues,
+    plan: Option<&WorkoutPlan>,
+    observers: &mut [&mut dyn WorkoutObserver],
+) -> Result<PathBuf, WaterRowerError> {
+    let debug = config.debug;
+    if let Some(trace) = debug {
+        trace("--- Creating workout directory ...");
+    }
+    let workout_path = config
+        .workout_dir
+        .join(wr_utils::workout_dir_name(&global_workout_values));
+    fs::create_dir_all(&workout_path)
+        .map_err(|e| WaterRowerError::FileCreate(workout_path.clone(), e))?;
+
+    // The workout files come first, so data is on disk before others see it
+    let mut workout_files = workout_files_init(&workout_path, config.pulses, &config.columns)?;
+    let mut interval_files = match plan {
+        Some(plan) => Some(wr_plan::interval_files_init(&workout_path, plan)?),
+        None => None,
+    };
+    let mut all_observers: Vec<&mut dyn WorkoutObserver> = vec![&mut workout_files];
+    if let Some(interval_files) = interval_files.as_mut() {
+        all_observers.push(interval_files);
+    }
+    for observer in observers.iter_mut() {
+        all_observers.push(&mut **observer);
+    }
+    observers_notify(&mut all_observers, |o| {
+        o.on_connected(&global_workout_values)
+    })?;
+
+    if let wr_utils::WorkoutState::Connected = workout_context.state {
+        observers_notify(&mut all_observers, |o| o.on_waiting())?;
+        wr_utils::wait_for_first_stroke(workout_context)?;
+    }
+    if let wr_utils::WorkoutState::Running = workout_context.state {
+        observers_notify(&mut all_observers, |o| o.on_first_stroke())?;
+    }
+
+    let mut datapoints: Vec<InstantWorkoutValues> = Vec::new();
+
+    let recording_result = loop {
+        let mut instant_workout_values = wr_utils::instant_workout_values_init();
+
+        // Get current workout values, keep what was recorded so far on failure
+        if let Err(e) = wr_utils::workout_values_update(
+            workout_context,
+            &mut instant_workout_values,
+            &mut global_workout_values,
+        ) {
+            break Err(e);
+        }
+
+        // Pass on strokes, pulses and pauses received meanwhile
+        let completed_strokes = wr_utils::completed_strokes(workout_context);
+        let received_pulses = wr_utils::received_pulses(workout_context);
+        let session_events = wr_utils::session_events(workout_context);
+        let notified = observers_notify(&mut all_observers, |o| {
+            completed_strokes
+                .iter()
+                .try_for_each(|stroke| o.on_stroke(stroke))?;
+            received_pulses
+                .iter()
+                .try_for_each(|pulse| o.on_pulse(pulse))
+        })
+        .and_then(|_| session_events_notify(&mut all_observers, &session_events));
+        if let Err(e) = notified {
+            break Err(e);
+        }
+
+        // Check if workout finished
+        if let wr_utils::WorkoutState::Finished = workout_context.state {
+            break Ok(());
+        }
+
+        // Pass on values and append them to datapoint vector
+        if let Err(e) =
+            observers_notify(&mut all_observers, |o| o.on_sample(&instant_workout_values))
+        {
+            break Err(e);
+        }
+        datapoints.push(instant_workout_values);
+    };
+
+    // The workout is finished with what was recorded, whatever fails from here on
+    let mut warnings = Vec::new();
+    if let Some(trace) = debug {
+        trace("--- Closing WaterRower workout session ...");
+    }
+    if let Err(e) = wr_utils::stop(workout_context) {
+        warnings.push(e);
+    }
+
+    if let Some(trace) = debug {
+        trace("--- Finalizing global workout values ...");
+    }
+    match wr_utils::date_time_now(workout_context) {
+        Ok(date_time_end) => global_workout_values.date_time_end = date_time_end,
+        Err(e) => warnings.push(e),
+    }
+    wr_utils::global_workout_values_finalize(&datapoints, &mut global_workout_values);
+    if let Some(reason) = workout_context.finish_reason {
+        global_workout_values.finish_reason = reason.to_string();
+    }
+
+    observers_notify(&mut all_observers, |o| {
+        warnings.iter().try_for_each(|e| o.on_warning(e))
+    })?;
+    segments_write(&workout_path, &datapoints, &mut all_observers)?;
+    observers_notify(&mut all_observers, |o| {
+        o.on_finished(&global_workout_values)
+    })?;
+
+    recording_result.map(|_| workout_path)
+}
+
+// Sleeps until the next connection attempt, returns false if stop was requested meanwhile
+fn daemon_reconnect_wait(stop_requested: &AtomicBool, reconnect_interval: time::Duration) -> bool {
+    let deadline = time::Instant::now() + reconnect_interval;
+    while time::Instant::now() < deadline {
+        if stop_requested.load(Ordering::SeqCst) {
+            return false;
+        }
+        thread::sleep(DAEMON_STOP_CHECK_INTERVAL);
+    }
+    !stop_requested.load(Ordering::SeqCst)
+}
+
+// Waits for the first stroke and records the workout, returns `None` if stop was requested before
+fn daemon_workout_record(
+    config: &RecordConfig,
+    workout_context: &mut WorkoutContext,
+    observers: &mut [&mut dyn WorkoutObserver],
+) -> Result<Option<PathBuf>, WaterRowerError> {
+    let (mut global_workout_values, plan) = workout_begin(config, workout_context)?;
+    observers_notify(observers, |o| o.on_waiting())?;
+    wr_utils::wait_for_first_stroke(workout_context)?;
+    if workout_context.state != wr_utils::WorkoutState::Running {
+        return Ok(None);
+    }
+
+    // Sessions are stored by the time rowing began, not when the S4 was connected
+    global_workout_values.date_time_start = wr_utils::date_time_now(workout_context)?;
+    workout_finish_recording(
+        config,
+        workout_context,
+        global_workout_values,
+        plan.as_ref(),
+        observers,
+    )
+    .map(Some)
+}
+
+/// Records one workout after another until stop is requested, e.g. on a shared rower
+///
+/// Every workout is stored in its own directory, named after the time of its
+/// first stroke. Between workouts, the connection to the S4 is kept open. If it
+/// is lost, e.g. as the S4 was unplugged, it is opened again as soon as the S4
+/// is back, a workout being recorded is finished with what was recorded so far.
+pub fn workout_daemon(
+    config: &RecordConfig,
+    stop_requested: Arc<AtomicBool>,
+    observers: &mut [&mut dyn WorkoutObserver],
+) -> Result<(), WaterRowerError> {
+    daemon_run(
+        config,
+        || wr_transport::transport_open(&config.serial_dev),
+        DAEMON_RECONNECT_INTERVAL,
+        stop_requested,
+        observers,
+    )
+}
+
+// Runs the daemon over the transports `transport_open` returns, one per connection
+fn daemon_run<F>(
+    config: &RecordConfig,
+    mut transport_open: F,
+    reconnect_interval: time::Duration,
+    stop_requested: Arc<AtomicBool>,
+    observers: &mut [&mut dyn WorkoutObserver],
+) -> Result<(), WaterRowerError>
+where
+    F: FnMut() -> Result<Box<dyn Transport>, WaterRowerError>,
+{
+    let mut connection_lost = false;
+    loop {
+        if stop_requested.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut port = match transport_open() {
+            Ok(port) => port,
+            Err(e) => {
+                // Report once, the S4 is expected to come back
+                if !connection_lost {
+                    observers_notify(observers, |o| o.on_connection_lost(&e))?;
+                    connection_lost = true;
+                }
+                if !daemon_reconnect_wait(&stop_requested, reconnect_interval) {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        if let Some(trace) = config.debug {
+            trace(&format!(
+                "--- Connected to:              {}",
+                config.serial_dev
+            ));
+        }
+
+        let error = loop {
+            let workout_context = workout_context_open(config, port, Arc::clone(&stop_requested));
+            let mut workout_context = match workout_context {
+                Ok(workout_context) => workout_context,
+                Err(e) => break e,
+            };
+            match daemon_workout_record(config, &mut workout_context, observers) {
+                Ok(Some(_)) => (),
+                Ok(None) => return Ok(()),
+                Err(e) => break e,
+            }
+            if stop_requested.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            // The next workout starts a new session over the same connection
+            port = workout_context.port;
+        };
+
+        // Workout files that cannot be written will not be writable after a reconnect either
+        if let WaterRowerError::FileCreate(..)
+        | WaterRowerError::FileRead(_)
+        | WaterRowerError::FileWrite(_) = error
+        {
+            return Err(error);
+        }
+        observers_notify(observers, |o| o.on_connection_lost(&error))?;
+        connection_lost = true;
+        if !daemon_reconnect_wait(&stop_requested, reconnect_interval) {
+            return Ok(());
+        }
+    }
+}
+
+/// Rebuilds the meta data and segment files of a workout from its (partial) workout data
+///
+/// The `observers` are told about a damaged row the workout data ends with,
+/// the segments and the rebuilt summary.
+pub fn workout_recover(
+    workout_path: &Path,
+    observers: &mut [&mut dyn WorkoutObserver],
+) -> Result<GlobalWorkoutValues, WaterRowerError> {
+    let (datapoints, damaged) = wr_utils::read_workout_data_file(workout_path)?;
+    if let Some(e) = damaged {
+        observers_notify(observers, |o| o.on_warning(&e))?;
+    }
+    let global_workout_values = wr_utils::global_workout_values_recover(workout_path, &datapoints)?;
+    segments_write(workout_path, &datapoints, observers)?;
+
+    wr_utils::write_meta_data_file(workout_path, &global_workout_values)?;
+    observers_notify(observers, |o| o.on_finished(&global_workout_values))?;
+    Ok(global_workout_values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        wr_columns,
+        wr_protocol::{S4Command, S4Message},
+        wr_registers,
+        wr_transport::MemoryTransport,
+    };
+    use chrono::{DateTime, Duration, Local};
+    use std::{cell::Cell, io};
+
+    // S4 answering every read with zero over a memory transport, the line breaks down
+    // after `commands_left` commands. Each connection has its own clock, so workouts
+    // recorded over different connections get different directories.
+    struct FlakyS4 {
+        port: MemoryTransport,
+        commands_left: Option<usize>,
+        local_time: DateTime<Local>,
+    }
+
+    impl Transport for FlakyS4 {
+        fn write_command(&mut self, command: &str) -> io::Result<()> {
+            if let Some(left) = self.commands_left.as_mut() {
+                if *left == 0 {
+                    return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+                }
+                *left -= 1;
+            }
+            match S4Command::parse(command) {
+                Ok(S4Command::Start) => {
+                    self.port.push_response(&S4Message::HardwareType.encode());
+                    self.port.push_response(&S4Message::StrokeStart.encode());
+                }
+                Ok(S4Command::ModelInfo) => self.port.push_response("IV40210"),
+                Ok(S4Command::Read { size, address }) => {
+                    let reply = S4Message::Data {
+                        size,
+                        address,
+                        value: 0,
+                    };
+                    self.port.push_response(&reply.encode());
+                }
+                _ => (),
+            }
+            self.port.write_command(command)
+        }
+
+        fn read_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+            self.port.read_bytes()
+        }
+
+        fn local_time(&mut self) -> DateTime<Local> {
+            self.local_time
+        }
+    }
+
+    // Collects what the daemon reports, requests stop once the second workout has a few samples
+    struct DaemonLog {
+        stop_requested: Arc<AtomicBool>,
+        samples: usize,
+        connections_lost: usize,
+        finish_reasons: Vec<String>,
+    }
+
+    impl WorkoutObserver for DaemonLog {
+        fn on_sample(&mut self, _iwv: &InstantWorkoutValues) -> Result<(), WaterRowerError> {
+            self.samples += 1;
+            if self.finish_reasons.len() == 1 && self.samples >= 3 {
+                self.stop_requested.store(true, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+
+        fn on_connection_lost(&mut self, _error: &WaterRowerError) -> Result<(), WaterRowerError> {
+            self.connections_lost += 1;
+            Ok(())
+        }
+
+        fn on_finished(&mut self, gwv: &GlobalWorkoutValues) -> Result<(), WaterRowerError> {
+            assert!(gwv.datapoints > 0);
+            self.finish_reasons.push(gwv.finish_reason.clone());
+            self.samples = 0;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn daemon_reconnects_after_connection_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let registers = wr_registers::register_map_bundled();
+        let config = RecordConfig {
+            serial_dev: String::from("memory"),
+            workout_dir: dir.path().to_path_buf(),
+            interval: time::Duration::from_millis(200),
+            pulses: false,
+            capture: None,
+            columns: wr_columns::workout_data_columns_parse("time,elapsed,distance", &registers)
+                .unwrap(),
+            registers,
+            program: None,
+            plan: None,
+            finish: FinishCriteria::default(),
+            debug: None,
+        };
+
+        // The first connection breaks down mid-workout, the S4 is then gone for one attempt
+        let opened = Cell::new(0);
+        let started = Local::now();
+        let transport_open = || -> Result<Box<dyn Transport>, WaterRowerError> {
+            opened.set(opened.get() + 1);
+            match opened.get() {
+                2 => Err(WaterRowerError::Io(io::Error::from(
+                    io::ErrorKind::NotFound,
+                ))),
+                n => Ok(Box::new(FlakyS4 {
+                    port: MemoryTransport::new(),
+                    commands_left: if n == 1 { Some(30) } else { None },
+                    local_time: started + Duration::hours(n),
+                })),
+            }
+        };
+        let stop_requested = Arc::new(AtomicBool::new(false));
+        let mut log = DaemonLog {
+            stop_requested: Arc::clone(&stop_requested),
+            samples: 0,
+            connections_lost: 0,
+            finish_reasons: Vec::new(),
+        };
+        daemon_run(
+            &config,
+            transport_open,
+            time::Duration::from_millis(50),
+            stop_requested,
+            &mut [&mut log],
+        )
+        .unwrap();
+
+        assert_eq!(opened.get(), 3);
+        assert_eq!(log.connections_lost, 1);
+        assert_eq!(log.finish_reasons, ["", "Stop requested"]);
+        let workouts = fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(workouts, 2);
+    }
+}
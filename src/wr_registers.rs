@@ -0,0 +1,296 @@
+//! Map of the S4 registers polled during a workout, loaded from TOML or JSON
+//!
+//! The map bundled with the crate (`src/registers.toml`) holds the registers the
+//! workout data is decoded from. Users can merge their own map on top of it, to
+//! adapt addresses or decoding to another firmware version, or to record extra
+//! registers without recompiling.
+
+use serde::{de, Deserialize, Deserializer};
+use std::{collections::HashSet, fs, path::Path, time};
+
+use crate::{
+    wr_columns::workout_data_column,
+    wr_error::WaterRowerError,
+    wr_protocol::{bcd_decode, DataSize},
+};
+
+const REGISTER_MAP_BUNDLED: &str = include_str!("registers.toml");
+
+// Fast-changing registers are polled in every sample, slow ones at most this often
+const POLLING_PERIOD_FAST: time::Duration = time::Duration::from_secs(0);
+const POLLING_PERIOD_SLOW: time::Duration = time::Duration::from_secs(2);
+
+// Registers are addressed by three hex digits
+const ADDRESS_MAX: u16 = 0xFFF;
+
+/// Order of the bytes of a register value
+///
+/// The S4 replies with the byte at the highest address first, so values stored
+/// least significant byte first (most registers) are taken as received. Values
+/// stored most significant byte first, like the zone paces, are swapped.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteOrder {
+    /// Taken as received
+    #[default]
+    Raw,
+    /// Bytes in reverse order of the reply
+    Swapped,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Base {
+    #[default]
+    Binary,
+    /// Binary coded decimal, one decimal digit per nibble
+    Bcd,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Polling {
+    #[default]
+    Fast,
+    Slow,
+}
+
+/// Register of the S4 and how to decode its value
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Register {
+    pub name: String,
+    /// Given as number or hex string, e.g. `"0x055"` in JSON
+    #[serde(deserialize_with = "address_deserialize")]
+    pub address: u16,
+    /// Number of bytes, 1 to 3
+    pub width: u8,
+    #[serde(default)]
+    pub byte_order: ByteOrder,
+    #[serde(default)]
+    pub base: Base,
+    /// Factor applied to the decoded value
+    #[serde(default = "scale_default")]
+    pub scale: f64,
+    #[serde(default)]
+    pub unit: String,
+    #[serde(default)]
+    pub polling: Polling,
+    /// Key to select the register as column of the workout data file
+    #[serde(default)]
+    pub column: Option<String>,
+}
+
+fn scale_default() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Address {
+    Number(u16),
+    Hex(String),
+}
+
+fn address_deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    match Address::deserialize(deserializer)? {
+        Address::Number(address) => Ok(address),
+        Address::Hex(s) => {
+            let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+            u16::from_str_radix(digits, 16)
+                .map_err(|_| de::Error::custom(format!("invalid address '{}'", s)))
+        }
+    }
+}
+
+impl Register {
+    pub fn size(&self) -> DataSize {
+        match self.width {
+            1 => DataSize::Single,
+            2 => DataSize::Double,
+            _ => DataSize::Triple,
+        }
+    }
+
+    pub fn polling_period(&self) -> time::Duration {
+        match self.polling {
+            Polling::Fast => POLLING_PERIOD_FAST,
+            Polling::Slow => POLLING_PERIOD_SLOW,
+        }
+    }
+
+    /// Decodes a value received from the S4
+    pub fn decode(&self, raw: u32) -> f64 {
+        let value = match self.byte_order {
+            ByteOrder::Raw => raw,
+            ByteOrder::Swapped => raw.swap_bytes() >> (8 * (4 - u32::from(self.width))),
+        };
+        let value = match self.base {
+            Base::Binary => value,
+            Base::Bcd => bcd_decode(value),
+        };
+        f64::from(value) * self.scale
+    }
+
+    /// Decimal places that represent the scale, e.g. 2 for 0.01
+    pub fn precision(&self) -> usize {
+        let mut precision = 0;
+        let mut scale = self.scale.abs();
+        while scale > 0.0 && scale.fract().abs() > 1e-9 && precision < 6 {
+            scale *= 10.0;
+            precision += 1;
+        }
+        precision
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=3).contains(&self.width) {
+            return Err(format!(
+                "width of register '{}' must be 1 to 3 bytes",
+                self.name
+            ));
+        }
+        if self.address > ADDRESS_MAX {
+            return Err(format!(
+                "address of register '{}' exceeds 0x{:03X}",
+                self.name, ADDRESS_MAX
+            ));
+        }
+        if !self.scale.is_finite() {
+            return Err(format!("scale of register '{}' is not finite", self.name));
+        }
+        if let Some(key) = self.column.as_ref() {
+            if key.is_empty() || key.contains(',') {
+                return Err(format!("invalid column key '{}'", key));
+            }
+            if workout_data_column(key).is_some() || key == "default" || key == "all" {
+                return Err(format!("column key '{}' is already taken", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Registers polled during a workout
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegisterMap {
+    #[serde(rename = "register", default)]
+    pub registers: Vec<Register>,
+}
+
+impl RegisterMap {
+    pub fn get(&self, name: &str) -> Option<&Register> {
+        self.registers.iter().find(|register| register.name == name)
+    }
+
+    /// Registers selectable as columns of the workout data file
+    pub fn columns(&self) -> impl Iterator<Item = &Register> {
+        self.registers
+            .iter()
+            .filter(|register| register.column.is_some())
+    }
+
+    // Registers of `other` replace those of the same name, others are added
+    fn merge(&mut self, other: RegisterMap) {
+        for register in other.registers {
+            match self.registers.iter_mut().find(|r| r.name == register.name) {
+                Some(existing) => *existing = register,
+                None => self.registers.push(register),
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        let mut columns = HashSet::new();
+        for register in self.registers.iter() {
+            register.validate()?;
+            if !names.insert(register.name.as_str()) {
+                return Err(format!("register '{}' is defined twice", register.name));
+            }
+            if let Some(key) = register.column.as_ref() {
+                if !columns.insert(key.as_str()) {
+                    return Err(format!("column key '{}' is used twice", key));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Register map bundled with the crate, validated like user maps
+pub fn register_map_bundled() -> RegisterMap {
+    register_map_parse(REGISTER_MAP_BUNDLED, false)
+        .unwrap_or_else(|e| panic!("Bundled register map is invalid: {}", e))
+}
+
+/// Parses a register map, JSON if `json` is set, TOML otherwise
+pub fn register_map_parse(s: &str, json: bool) -> Result<RegisterMap, WaterRowerError> {
+    let map: RegisterMap = if json {
+        serde_json::from_str(s).map_err(|e| WaterRowerError::RegisterMap(e.to_string()))?
+    } else {
+        toml::from_str(s).map_err(|e| WaterRowerError::RegisterMap(e.to_string()))?
+    };
+    map.validate().map_err(WaterRowerError::RegisterMap)?;
+    Ok(map)
+}
+
+/// Loads a user register map (`.json` or TOML) and merges it on top of the bundled one
+pub fn register_map_load(map_file: &Path) -> Result<RegisterMap, WaterRowerError> {
+    let s = fs::read_to_string(map_file)
+        .map_err(|e| WaterRowerError::RegisterMap(format!("{}: {}", map_file.display(), e)))?;
+    let json = map_file.extension().is_some_and(|ext| ext == "json");
+    let mut map = register_map_bundled();
+    map.merge(register_map_parse(&s, json)?);
+    map.validate().map_err(WaterRowerError::RegisterMap)?;
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_map_validates() {
+        let map = register_map_parse(REGISTER_MAP_BUNDLED, false).unwrap();
+        assert!(map.get("Distance").is_some());
+        assert_eq!(map.columns().count(), 0);
+    }
+
+    #[test]
+    fn decode_byte_order_and_base() {
+        let map = register_map_bundled();
+        // Stored most significant byte first, 120 seconds arrive as 0x7800
+        let pace = map.get("Seconds per 500 Meters (Zone)").unwrap();
+        assert_eq!(pace.byte_order, ByteOrder::Swapped);
+        assert_eq!(pace.decode(0x7800), 120.0);
+        let distance = map.get("Distance").unwrap();
+        assert_eq!(distance.decode(0x07D0), 2000.0);
+        let minutes = map.get("Minutes (Display)").unwrap();
+        assert_eq!(minutes.decode(0x59), 59.0);
+    }
+
+    #[test]
+    fn user_map_invalid() {
+        let cases = [
+            "[[register]]\nname = \"A\"\naddress = 0x100\nwidth = 4",
+            "[[register]]\nname = \"A\"\naddress = 0x1000\nwidth = 1",
+            "[[register]]\nname = \"A\"\naddress = 0x100\nwidth = 1\ncolumn = \"time\"",
+            "[[register]]\nname = \"A\"\naddress = 0x100\nwidth = 1\nbyte_order = \"big\"",
+        ];
+        for map in cases.iter() {
+            assert!(register_map_parse(map, false).is_err(), "{}", map);
+        }
+    }
+
+    #[test]
+    fn user_map_json_hex_address() {
+        let map = register_map_parse(
+            r#"{"register": [{"name": "A", "address": "0x05A", "width": 1, "column": "a"}]}"#,
+            true,
+        )
+        .unwrap();
+        assert_eq!(map.registers[0].address, 0x05A);
+    }
+}
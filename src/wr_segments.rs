@@ -0,0 +1,328 @@
+//! Detection of work and rest segments in the samples of a free-form workout
+//!
+//! A sample is rowed if strokes were taken since the previous sample, or if
+//! the distance grew at a pace faster than a coasting flywheel. Pauses shorter
+//! than [`SEGMENT_REST_MIN_IN_SECONDS`] are part of the work around them.
+
+use std::path::Path;
+
+use crate::{
+    wr_error::WaterRowerError,
+    wr_utils::{self, DataRow, InstantWorkoutValues},
+};
+
+pub const SEGMENT_DATA_FILE: &str = "segments.csv";
+
+pub const SEGMENT_DATA_HEADER: [&str; 9] = [
+    "Segment",
+    "Type",
+    "Start in Seconds",
+    "Duration in Seconds",
+    "Distance in Meters",
+    "Stroke Count",
+    "Seconds per 500 Meters (avg)",
+    "Strokes per Minute (avg)",
+    "Heart Rate (avg)",
+];
+
+/// Shortest pause recorded as a rest
+pub const SEGMENT_REST_MIN_IN_SECONDS: f32 = 10.0;
+
+// Distance gained slower than this is the flywheel running out
+const SEGMENT_COASTING_SECONDS_PER_500M: u32 = 300;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentKind {
+    Work,
+    Rest,
+}
+
+/// Summary of a work or rest segment, as written to the segment data file
+#[derive(Clone, Debug)]
+pub struct WorkoutSegment {
+    pub segment_number: u32,
+    pub kind: SegmentKind,
+    /// Start relative to the first stroke
+    pub start_in_seconds: f32,
+    pub duration_in_seconds: f32,
+    pub distance_in_meters: u32,
+    pub stroke_count: u32,
+    pub seconds_per_500m_avg: f32,
+    pub strokes_per_minute_avg: f32,
+    pub heart_rate_avg: f32,
+}
+
+impl DataRow for WorkoutSegment {
+    fn data_row(&self) -> Vec<String> {
+        vec![
+            format!("{}", self.segment_number),
+            String::from(match self.kind {
+                SegmentKind::Work => "Work",
+                SegmentKind::Rest => "Rest",
+            }),
+            format!("{:.1}", self.start_in_seconds),
+            format!("{:.1}", self.duration_in_seconds),
+            format!("{}", self.distance_in_meters),
+            format!("{}", self.stroke_count),
+            format!("{:.2}", self.seconds_per_500m_avg),
+            format!("{:.2}", self.strokes_per_minute_avg),
+            format!("{:.2}", self.heart_rate_avg),
+        ]
+    }
+}
+
+// Time of a sample, the S4's clock if elapsed time was not recorded
+fn sample_time(iwv: &InstantWorkoutValues, elapsed: bool) -> f32 {
+    if elapsed {
+        iwv.elapsed_in_seconds
+    } else {
+        iwv.time_in_seconds as f32
+    }
+}
+
+fn sample_rowed(previous: &InstantWorkoutValues, iwv: &InstantWorkoutValues) -> bool {
+    let pace_rowing =
+        iwv.seconds_per_500m == 0 || iwv.seconds_per_500m < SEGMENT_COASTING_SECONDS_PER_500M;
+    iwv.stroke_count > previous.stroke_count
+        || (iwv.distance_in_meters > previous.distance_in_meters && pace_rowing)
+}
+
+fn average_valid(values: impl Iterator<Item = u32>) -> f32 {
+    let valid: Vec<u32> = values.filter(|&v| v > 0).collect();
+    if valid.is_empty() {
+        return 0.0;
+    }
+    valid.iter().sum::<u32>() as f32 / valid.len() as f32
+}
+
+/// Splits the samples of a workout into work and rest segments
+///
+/// Every sample stands for the time since the previous one. The first sample
+/// is its own baseline, the S4 may still show the counters of a workout before.
+pub fn workout_segments_detect(datapoints: &[InstantWorkoutValues]) -> Vec<WorkoutSegment> {
+    let elapsed = datapoints.iter().any(|iwv| iwv.elapsed_in_seconds > 0.0);
+    let previous = |i: usize| &datapoints[i.saturating_sub(1)];
+
+    // Runs of rowed and paused samples, as (kind, first, last)
+    let mut runs: Vec<(SegmentKind, usize, usize)> = Vec::new();
+    for (i, iwv) in datapoints.iter().enumerate() {
+        let kind = if i == 0 || sample_rowed(previous(i), iwv) {
+            SegmentKind::Work
+        } else {
+            SegmentKind::Rest
+        };
+        match runs.last_mut() {
+            Some((last_kind, _, last)) if *last_kind == kind => *last = i,
+            _ => runs.push((kind, i, i)),
+        }
+    }
+
+    // Short pauses belong to the work around them
+    let duration = |first: usize, last: usize| {
+        sample_time(&datapoints[last], elapsed) - sample_time(previous(first), elapsed)
+    };
+    let mut merged: Vec<(SegmentKind, usize, usize)> = Vec::new();
+    for (kind, first, last) in runs {
+        let kind = match kind {
+            SegmentKind::Rest if duration(first, last) < SEGMENT_REST_MIN_IN_SECONDS => {
+                SegmentKind::Work
+            }
+            kind => kind,
+        };
+        match merged.last_mut() {
+            Some((last_kind, _, merged_last)) if *last_kind == kind => *merged_last = last,
+            _ => merged.push((kind, first, last)),
+        }
+    }
+
+    merged
+        .iter()
+        .enumerate()
+        .map(|(i, &(kind, first, last))| {
+            let samples = &datapoints[first..=last];
+            let before = previous(first);
+            WorkoutSegment {
+                segment_number: i as u32 + 1,
+                kind,
+                start_in_seconds: sample_time(before, elapsed),
+                duration_in_seconds: duration(first, last),
+                distance_in_meters: datapoints[last]
+                    .distance_in_meters
+                    .saturating_sub(before.distance_in_meters),
+                stroke_count: datapoints[last]
+                    .stroke_count
+                    .saturating_sub(before.stroke_count),
+                seconds_per_500m_avg: average_valid(samples.iter().map(|v| v.seconds_per_500m)),
+                strokes_per_minute_avg: average_valid(samples.iter().map(|v| v.strokes_per_minute)),
+                heart_rate_avg: average_valid(samples.iter().map(|v| v.heart_rate)),
+            }
+        })
+        .collect()
+}
+
+/// Writes the work and rest segments to the segment data file of a workout directory
+pub fn write_segment_data_file(
+    workout_dir: &Path,
+    segments: &[WorkoutSegment],
+) -> Result<(), WaterRowerError> {
+    let mut writer = wr_utils::data_file_writer_init(
+        &workout_dir.join(SEGMENT_DATA_FILE),
+        &SEGMENT_DATA_HEADER,
+    )?;
+    for segment in segments.iter() {
+        writer.write(segment)?;
+    }
+    writer.sync()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Samples a second apart, rowing at 4 m/s with a stroke every other second
+    struct Rower {
+        second: u32,
+        distance: u32,
+        strokes: u32,
+        samples: Vec<InstantWorkoutValues>,
+    }
+
+    impl Rower {
+        // The first sample is taken with the first stroke, on the counters the S4 shows then
+        fn new(distance: u32, strokes: u32) -> Self {
+            let mut rower = Rower {
+                second: 0,
+                distance,
+                strokes,
+                samples: Vec::new(),
+            };
+            rower.sample(0, 0, 0);
+            rower
+        }
+
+        fn sample(&mut self, pace: u32, stroke_rate: u32, heart_rate: u32) {
+            let mut iwv = wr_utils::instant_workout_values_init();
+            iwv.time_in_seconds = self.second;
+            iwv.elapsed_in_seconds = self.second as f32;
+            iwv.distance_in_meters = self.distance;
+            iwv.stroke_count = self.strokes;
+            iwv.seconds_per_500m = pace;
+            iwv.strokes_per_minute = stroke_rate;
+            iwv.heart_rate = heart_rate;
+            self.samples.push(iwv);
+        }
+
+        fn row(&mut self, seconds: u32) {
+            for _ in 0..seconds {
+                self.second += 1;
+                self.distance += 4;
+                if self.second % 2 == 0 {
+                    self.strokes += 1;
+                }
+                self.sample(125, 30, 150);
+            }
+        }
+
+        // The flywheel runs out after the last stroke, a meter per second
+        fn coast(&mut self, seconds: u32) {
+            for _ in 0..seconds {
+                self.second += 1;
+                self.distance += 1;
+                self.sample(500, 0, 140);
+            }
+        }
+
+        fn rest(&mut self, seconds: u32) {
+            for _ in 0..seconds {
+                self.second += 1;
+                self.sample(0, 0, 100);
+            }
+        }
+    }
+
+    #[test]
+    fn rest_splits_segments() {
+        let mut rower = Rower::new(0, 0);
+        rower.row(20);
+        rower.coast(3);
+        rower.rest(27);
+        rower.row(10);
+        let segments = workout_segments_detect(&rower.samples);
+        let kinds: Vec<SegmentKind> = segments.iter().map(|s| s.kind).collect();
+        assert_eq!(
+            kinds,
+            [SegmentKind::Work, SegmentKind::Rest, SegmentKind::Work]
+        );
+
+        let work = &segments[0];
+        assert_eq!(work.start_in_seconds, 0.0);
+        assert_eq!(work.duration_in_seconds, 20.0);
+        assert_eq!(work.distance_in_meters, 80);
+        assert_eq!(work.stroke_count, 10);
+        assert_eq!(work.seconds_per_500m_avg, 125.0);
+        assert_eq!(work.strokes_per_minute_avg, 30.0);
+        assert_eq!(work.heart_rate_avg, 150.0);
+
+        let rest = &segments[1];
+        assert_eq!(rest.start_in_seconds, 20.0);
+        assert_eq!(rest.duration_in_seconds, 30.0);
+        assert_eq!(rest.distance_in_meters, 3);
+        assert_eq!(rest.stroke_count, 0);
+        assert_eq!(rest.strokes_per_minute_avg, 0.0);
+        assert_eq!(rest.heart_rate_avg, 104.0);
+
+        let work = &segments[2];
+        assert_eq!(work.segment_number, 3);
+        assert_eq!(work.start_in_seconds, 50.0);
+        assert_eq!(work.duration_in_seconds, 10.0);
+        assert_eq!(work.distance_in_meters, 40);
+        assert_eq!(work.stroke_count, 5);
+    }
+
+    #[test]
+    fn short_pause_kept_in_work() {
+        let mut rower = Rower::new(0, 0);
+        rower.row(10);
+        rower.rest(5);
+        rower.row(10);
+        let segments = workout_segments_detect(&rower.samples);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].kind, SegmentKind::Work);
+        assert_eq!(segments[0].duration_in_seconds, 25.0);
+        assert_eq!(segments[0].distance_in_meters, 80);
+        assert_eq!(segments[0].stroke_count, 10);
+        // Paused samples have no pace, but lower the average heart rate
+        assert_eq!(segments[0].seconds_per_500m_avg, 125.0);
+        assert_eq!(segments[0].heart_rate_avg, 140.0);
+    }
+
+    #[test]
+    fn coasting_after_last_stroke_not_work() {
+        let mut rower = Rower::new(0, 0);
+        rower.row(10);
+        rower.coast(12);
+        let segments = workout_segments_detect(&rower.samples);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].kind, SegmentKind::Work);
+        assert_eq!(segments[0].duration_in_seconds, 10.0);
+        assert_eq!(segments[0].distance_in_meters, 40);
+        assert_eq!(segments[1].kind, SegmentKind::Rest);
+        assert_eq!(segments[1].distance_in_meters, 12);
+    }
+
+    #[test]
+    fn counters_left_on_monitor_not_rowed() {
+        let mut rower = Rower::new(500, 40);
+        rower.row(10);
+        rower.rest(15);
+        let segments = workout_segments_detect(&rower.samples);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].kind, SegmentKind::Work);
+        assert_eq!(segments[0].start_in_seconds, 0.0);
+        assert_eq!(segments[0].duration_in_seconds, 10.0);
+        assert_eq!(segments[0].distance_in_meters, 40);
+        assert_eq!(segments[0].stroke_count, 5);
+        assert_eq!(segments[1].kind, SegmentKind::Rest);
+        assert_eq!(segments[1].distance_in_meters, 0);
+    }
+}
@@ -0,0 +1,1300 @@
+//! Sans-IO state machine of a workout session with an S4
+//!
+//! [`Session`] neither reads, writes nor sleeps. It is fed with received bytes
+//! (or lines) and timer ticks, both stamped with the current time, and hands out
+//! the commands to send and the events decoded. This way it can be driven by
+//! blocking I/O (see [`crate::wr_utils`]), an async runtime or a test with a
+//! fake clock.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, time,
+};
+
+use crate::{
+    wr_codec::FrameDecoder,
+    wr_columns::{workout_data_columns_default, workout_data_columns_registers},
+    wr_error::WaterRowerError,
+    wr_program::{ProgramTracker, WorkoutProgram},
+    wr_protocol::{ParseError, S4Command, S4Message},
+    wr_pulses::PulseValues,
+    wr_registers::{register_map_bundled, Register, RegisterMap},
+    wr_strokes::{StrokeTracker, StrokeValues},
+    wr_utils::{instant_workout_values_init, InstantWorkoutValues},
+};
+
+// The S4 needs some time to process a command before it accepts the next one
+const SERIAL_COMMAND_WAIT: time::Duration = time::Duration::from_millis(25);
+// Longest wait for the S4's answer to a request
+pub(crate) const RESPONSE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+// The S4 stops its clock while no stroke is taken, so this is the longest break
+// kept as part of the workout
+const DEFAULT_CLOCK_STOPPED: time::Duration = time::Duration::from_secs(300);
+// Rowing slower than 10 strokes per minute is a pause, e.g. to drink
+const DEFAULT_PAUSE_AFTER: time::Duration = time::Duration::from_secs(6);
+// Samples skipped for a register never received before it is no longer polled,
+// e.g. as the firmware does not have it
+const MISSING_REGISTER_RETRIES: u32 = 5;
+
+// Registers the workout data is decoded from, by their name in the register map
+const SCREEN_MODE: &str = "Screen Mode";
+const DISTANCE: &str = "Distance";
+const DISPLAY_DISTANCE: &str = "Distance (Display)";
+const CLOCK_COUNT_DOWN: &str = "Clock Count Down";
+const TOTAL_DISTANCE: &str = "Total Distance";
+const POWER: &str = "Power";
+const TOTAL_CALORIES: &str = "Total Calories";
+const TANK_VOLUME: &str = "Tank Volume";
+const STROKE_COUNT: &str = "Stroke Count";
+const STROKE_TIME_AVG: &str = "Stroke Time Average";
+const STROKE_PULL_TIME_AVG: &str = "Stroke Pull Time Average";
+const TOTAL_CENTIMETERS_PER_SECOND: &str = "Total Centimeters per Second";
+const INSTANT_CENTIMETERS_PER_SECOND: &str = "Instant Centimeters per Second";
+const ZONE_HEART_RATE: &str = "Heart Rate (Zone)";
+const ZONE_CENTIMETERS_PER_SECOND: &str = "Centimeters per Second (Zone)";
+const ZONE_SECONDS_PER_500M: &str = "Seconds per 500 Meters (Zone)";
+const ZONE_SECONDS_PER_2KM: &str = "Seconds per 2 Kilometers (Zone)";
+const ZONE_STROKE_RATE: &str = "Stroke Rate (Zone)";
+const DISPLAY_SECOND_DECIMALS: &str = "Second Decimals (Display)";
+const DISPLAY_SECONDS: &str = "Seconds (Display)";
+const DISPLAY_MINUTES: &str = "Minutes (Display)";
+const DISPLAY_HOURS: &str = "Hours (Display)";
+
+// Polled whatever is recorded: the display clock tells when the workout ended,
+// the distance is needed for the distance per stroke
+const REQUIRED_VALUES: [&str; 5] = [
+    DISPLAY_SECOND_DECIMALS,
+    DISPLAY_SECONDS,
+    DISPLAY_MINUTES,
+    DISPLAY_HOURS,
+    DISTANCE,
+];
+
+/// Phase of a workout session
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkoutState {
+    Init,
+    Connected,
+    Running,
+    Finished,
+}
+
+/// Why a workout session finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The S4's clock stood still, e.g. as the workout was ended on the S4
+    ClockStopped,
+    /// Neither distance nor stroke count changed
+    Inactivity,
+    NoStrokes,
+    TargetDistance,
+    TargetTime,
+    /// The S4 was reset, its distance went back
+    Reset,
+    ProgramCompleted,
+    StopRequested,
+}
+
+impl fmt::Display for FinishReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FinishReason::ClockStopped => "WaterRower clock stopped",
+            FinishReason::Inactivity => "Inactivity",
+            FinishReason::NoStrokes => "No strokes",
+            FinishReason::TargetDistance => "Target distance reached",
+            FinishReason::TargetTime => "Target time reached",
+            FinishReason::Reset => "WaterRower reset",
+            FinishReason::ProgramCompleted => "Workout program completed",
+            FinishReason::StopRequested => "Stop requested",
+        })
+    }
+}
+
+/// When a running workout is considered finished, criteria set to `None` are not checked
+///
+/// Pauses shorter than the time limits are kept as part of the workout. By
+/// default, the workout ends once the S4's clock stood still for 5 minutes or
+/// the S4 is reset.
+#[derive(Clone, Debug)]
+pub struct FinishCriteria {
+    /// The S4's clock stood still this long
+    pub clock_stopped: Option<time::Duration>,
+    /// Neither distance nor stroke count changed this long
+    pub inactivity: Option<time::Duration>,
+    /// No stroke was started this long
+    pub no_strokes: Option<time::Duration>,
+    /// No stroke was started this long, the workout is paused until the next stroke
+    pub pause_after: time::Duration,
+    pub target_distance_in_meters: Option<u32>,
+    /// Time shown by the S4
+    pub target_time_in_seconds: Option<u32>,
+    /// The S4 was reset during the workout
+    pub reset: bool,
+}
+
+impl Default for FinishCriteria {
+    fn default() -> Self {
+        FinishCriteria {
+            clock_stopped: Some(DEFAULT_CLOCK_STOPPED),
+            inactivity: None,
+            no_strokes: None,
+            pause_after: DEFAULT_PAUSE_AFTER,
+            target_distance_in_meters: None,
+            target_time_in_seconds: None,
+            reset: true,
+        }
+    }
+}
+
+/// Decides which registers are due for polling, based on when they were last requested
+///
+/// Requests are counted from the start of the sample, replies arrive a little
+/// later, so a register polled every other second is due in every sample of a
+/// two second interval.
+#[derive(Default)]
+struct PollingScheduler {
+    last_requested: HashMap<String, time::Instant>,
+}
+
+impl PollingScheduler {
+    fn due<'a>(&self, registers: &[&'a Register], now: time::Instant) -> Vec<&'a Register> {
+        registers
+            .iter()
+            .filter(|register| match self.last_requested.get(&register.name) {
+                Some(requested) => now.duration_since(*requested) >= register.polling_period(),
+                None => true,
+            })
+            .copied()
+            .collect()
+    }
+
+    fn requested(&mut self, register: &Register, now: time::Instant) {
+        self.last_requested.insert(register.name.clone(), now);
+    }
+
+    // Requests the register again in the next sample, e.g. as its reply is missing
+    fn forget(&mut self, register: &Register) {
+        self.last_requested.remove(&register.name);
+    }
+}
+
+// Decoded value of a register, registers that are not polled read as zero
+fn register_value(registers: &RegisterMap, raw_values: &HashMap<String, u32>, name: &str) -> f64 {
+    match (registers.get(name), raw_values.get(name)) {
+        (Some(register), Some(raw)) => register.decode(*raw),
+        _ => 0.0,
+    }
+}
+
+#[rustfmt::skip]
+fn instant_workout_values_update(
+    registers: &RegisterMap,
+    raw_values: &HashMap<String, u32>,
+    iwv: &mut InstantWorkoutValues,
+) {
+    let value = |name: &str| register_value(registers, raw_values, name);
+    let count = |name: &str| value(name).round() as u32;
+    iwv.time_in_seconds =
+        count(DISPLAY_SECONDS)
+        + 60 * count(DISPLAY_MINUTES)
+        + 3600 * count(DISPLAY_HOURS);
+    iwv.time_decimals = count(DISPLAY_SECOND_DECIMALS);
+    iwv.distance_in_meters = count(DISTANCE);
+    iwv.display_distance_in_meters = count(DISPLAY_DISTANCE);
+    iwv.seconds_per_500m = count(ZONE_SECONDS_PER_500M);
+    iwv.seconds_per_2km = count(ZONE_SECONDS_PER_2KM);
+    iwv.stroke_count = count(STROKE_COUNT);
+    iwv.strokes_per_minute = count(ZONE_STROKE_RATE);
+    
+    // Somewhat vague note for stroke ratio calculation from WaterRower docs:
+    //   Stroke_pull is first subtracted from stroke_average
+    //   then a modifier of 1.25 multiplied by the result to generate the ratio value for display.
+    let stroke_time_avg: f32 = value(STROKE_TIME_AVG) as f32;
+    let pull_time_avg: f32 = value(STROKE_PULL_TIME_AVG) as f32;
+    if pull_time_avg > 0.0 {
+        iwv.stroke_ratio = (stroke_time_avg - pull_time_avg) / (pull_time_avg * 1.25);
+    }
+    else {
+        iwv.stroke_ratio = 0.0;
+    }
+    
+    iwv.heart_rate = count(ZONE_HEART_RATE);
+    iwv.power_in_watts = count(POWER);
+    iwv.calories_in_kcal = count(TOTAL_CALORIES);
+    iwv.speed_in_meters_per_second = value(INSTANT_CENTIMETERS_PER_SECOND) as f32;
+    iwv.speed_avg_in_meters_per_second = value(TOTAL_CENTIMETERS_PER_SECOND) as f32;
+    iwv.speed_zone_in_meters_per_second = value(ZONE_CENTIMETERS_PER_SECOND) as f32;
+    iwv.count_down_in_seconds = count(CLOCK_COUNT_DOWN);
+    iwv.odometer_in_meters = count(TOTAL_DISTANCE);
+    iwv.tank_volume_in_liters = value(TANK_VOLUME) as f32;
+    iwv.screen_mode = count(SCREEN_MODE);
+
+    // Registers added by a user register map are recorded as they are
+    for register in registers.columns() {
+        if raw_values.contains_key(&register.name) {
+            iwv.register_values.insert(register.name.clone(), value(&register.name));
+        }
+    }
+}
+
+/// Decoded outcome of the communication with the S4
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// The S4 answered the start of the communication
+    Connected,
+    ModelInfo {
+        model: u8,
+        fw_major: u8,
+        fw_minor: u8,
+    },
+    /// The S4 accepted every command of a workout program
+    ProgramLoaded,
+    /// The first stroke was detected, sampling of workout values begins
+    FirstStroke,
+    /// Workout values polled during one sampling interval
+    Sample(InstantWorkoutValues),
+    /// A register was never received, the sample is skipped and the register polled again
+    SampleSkipped(WaterRowerError),
+    /// A register was still not received after some skipped samples, it is no longer
+    /// polled and reads as zero
+    RegisterDropped(WaterRowerError),
+    /// No stroke was taken for a while, the following samples are paused
+    Paused,
+    /// Rowing went on after a pause
+    Resumed,
+    Stroke(StrokeValues),
+    Pulse(PulseValues),
+    /// The S4 answered a command with an error, the session continues
+    CommandRejected,
+    /// The workout met one of the finish criteria or its program completed
+    Finished(FinishReason),
+    /// The session cannot continue
+    Failed(WaterRowerError),
+}
+
+/// Protocol state of a workout session, independent of any I/O and clock
+pub struct Session {
+    state: WorkoutState,
+    interval: time::Duration,
+    decoder: FrameDecoder,
+    transmit: VecDeque<S4Command>,
+    next_transmit: Option<time::Instant>,
+    events: VecDeque<SessionEvent>,
+    start_deadline: Option<time::Instant>,
+    sample_deadline: Option<time::Instant>,
+    program: Option<WorkoutProgram>,
+    // Commands of the program not acknowledged yet
+    program_pending: usize,
+    program_deadline: Option<time::Instant>,
+    program_tracker: Option<ProgramTracker>,
+    registers: RegisterMap,
+    registers_selected: Vec<String>,
+    // Indices of the polled registers in the register map
+    polled: Vec<usize>,
+    polling: PollingScheduler,
+    // Samples skipped for each register never received
+    missing_retries: HashMap<String, u32>,
+    raw_values: HashMap<String, u32>,
+    previous_decimals: Option<u32>,
+    previous_time_in_seconds: u32,
+    previous_distance_in_meters: u32,
+    previous_stroke_count: u32,
+    first_stroke: Option<time::Instant>,
+    finish: FinishCriteria,
+    last_clock_change: Option<time::Instant>,
+    last_activity: Option<time::Instant>,
+    last_stroke: Option<time::Instant>,
+    paused: bool,
+    strokes: StrokeTracker,
+}
+
+impl Session {
+    /// Creates a session sampling workout values every `interval`
+    pub fn new(interval: time::Duration) -> Self {
+        let mut session = Session {
+            state: WorkoutState::Init,
+            interval,
+            decoder: FrameDecoder::new(),
+            transmit: VecDeque::new(),
+            next_transmit: None,
+            events: VecDeque::new(),
+            start_deadline: None,
+            sample_deadline: None,
+            program: None,
+            program_pending: 0,
+            program_deadline: None,
+            program_tracker: None,
+            registers: register_map_bundled(),
+            registers_selected: Vec::new(),
+            polled: Vec::new(),
+            polling: PollingScheduler::default(),
+            missing_retries: HashMap::new(),
+            raw_values: HashMap::new(),
+            previous_decimals: None,
+            previous_time_in_seconds: 0,
+            previous_distance_in_meters: 0,
+            previous_stroke_count: 0,
+            first_stroke: None,
+            finish: FinishCriteria::default(),
+            last_clock_change: None,
+            last_activity: None,
+            last_stroke: None,
+            paused: false,
+            strokes: StrokeTracker::default(),
+        };
+        session.registers_select(&workout_data_columns_registers(
+            &workout_data_columns_default(),
+        ));
+        session
+    }
+
+    /// Selects the registers polled in every sample, by name
+    ///
+    /// The registers needed to detect the end of the workout and the distance
+    /// per stroke are always polled. Registers that are not polled read as zero.
+    pub fn registers_select(&mut self, register_names: &[&str]) {
+        self.registers_selected = register_names.iter().map(|&name| name.to_owned()).collect();
+        self.polled = (0..self.registers.registers.len())
+            .filter(|&i| {
+                let name = self.registers.registers[i].name.as_str();
+                REQUIRED_VALUES.contains(&name)
+                    || self
+                        .registers_selected
+                        .iter()
+                        .any(|selected| selected == name)
+            })
+            .collect();
+    }
+
+    /// Replaces the register map, e.g. by one merged with a user map, keeping the selection
+    pub fn register_map_set(&mut self, registers: RegisterMap) {
+        self.registers = registers;
+        self.raw_values.clear();
+        let selected = self.registers_selected.clone();
+        self.registers_select(&selected.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+
+    /// Sets when a running workout is finished, to be done before the first stroke
+    pub fn finish_criteria_set(&mut self, finish: FinishCriteria) {
+        self.finish = finish;
+    }
+
+    pub fn state(&self) -> WorkoutState {
+        self.state
+    }
+
+    pub fn interval(&self) -> time::Duration {
+        self.interval
+    }
+
+    /// Opens the communication, the S4 has to identify itself within a timeout
+    pub fn start(&mut self, now: time::Instant) {
+        self.transmit.push_back(S4Command::Start);
+        self.start_deadline = Some(now + RESPONSE_TIMEOUT);
+    }
+
+    pub fn model_info_request(&mut self) {
+        self.transmit.push_back(S4Command::ModelInfo);
+    }
+
+    /// Loads a workout program onto the S4, every command has to be acknowledged within a timeout
+    ///
+    /// From the first stroke on, samples carry the interval and the target left,
+    /// and the session finishes once the program is completed.
+    pub fn program_load(&mut self, program: &WorkoutProgram, now: time::Instant) {
+        let commands = program.commands();
+        self.program_pending = commands.len();
+        self.program_deadline =
+            Some(now + RESPONSE_TIMEOUT + SERIAL_COMMAND_WAIT * commands.len() as u32);
+        self.transmit.extend(commands);
+        self.program_track(program);
+    }
+
+    /// Follows a workout program without loading it onto the S4, e.g. the plan of a free workout
+    pub fn program_track(&mut self, program: &WorkoutProgram) {
+        self.program = Some(program.clone());
+    }
+
+    /// Closes the communication, pending register reads are dropped
+    pub fn stop(&mut self) {
+        self.transmit.clear();
+        self.transmit.push_back(S4Command::Stop);
+        self.state = WorkoutState::Finished;
+        self.start_deadline = None;
+        self.sample_deadline = None;
+        self.program_deadline = None;
+    }
+
+    /// Feeds bytes received from the S4
+    pub fn handle_bytes(&mut self, bytes: &[u8], now: time::Instant) {
+        self.decoder.push(bytes);
+        while let Some(line) = self.decoder.next_frame() {
+            // Noise is expected on the line, invalid messages are skipped
+            let _ = self.handle_line(&line, now);
+        }
+    }
+
+    /// Feeds a single message received from the S4, for transports doing their own framing
+    pub fn handle_line(&mut self, line: &str, now: time::Instant) -> Result<(), ParseError> {
+        let message = S4Message::parse(line)?;
+        self.message_handle(message, now);
+        Ok(())
+    }
+
+    /// Advances timers, to be called when [`Session::poll_timeout`] has passed
+    pub fn handle_timeout(&mut self, now: time::Instant) {
+        if let Some(deadline) = self.start_deadline {
+            if now >= deadline {
+                self.start_deadline = None;
+                self.events
+                    .push_back(SessionEvent::Failed(WaterRowerError::Timeout(
+                        "WaterRower hardware type",
+                    )));
+            }
+        }
+        if let Some(deadline) = self.program_deadline {
+            if now >= deadline {
+                self.program_deadline = None;
+                self.events
+                    .push_back(SessionEvent::Failed(WaterRowerError::Timeout(
+                        "WaterRower to accept workout program",
+                    )));
+            }
+        }
+        if let Some(deadline) = self.sample_deadline {
+            if now >= deadline {
+                // Wait for reads that are still queued, e.g. if the interval is too short for all
+                match self.next_transmit {
+                    Some(next_transmit) if !self.transmit.is_empty() => {
+                        self.sample_deadline = Some(next_transmit.max(now) + SERIAL_COMMAND_WAIT)
+                    }
+                    _ => self.sample_complete(now),
+                }
+            }
+        }
+    }
+
+    /// Next command to send, commands are spaced so the S4 can keep up
+    pub fn poll_transmit(&mut self, now: time::Instant) -> Option<S4Command> {
+        if let Some(next_transmit) = self.next_transmit {
+            if now < next_transmit {
+                return None;
+            }
+        }
+        let command = self.transmit.pop_front()?;
+        self.next_transmit = Some(now + SERIAL_COMMAND_WAIT);
+        Some(command)
+    }
+
+    pub fn poll_event(&mut self) -> Option<SessionEvent> {
+        self.events.pop_front()
+    }
+
+    /// Time at which [`Session::handle_timeout`] or [`Session::poll_transmit`] has work to do
+    pub fn poll_timeout(&self) -> Option<time::Instant> {
+        let transmit = if self.transmit.is_empty() {
+            None
+        } else {
+            self.next_transmit
+        };
+        [
+            transmit,
+            self.start_deadline,
+            self.program_deadline,
+            self.sample_deadline,
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    fn message_handle(&mut self, message: S4Message, now: time::Instant) {
+        match message {
+            S4Message::HardwareType => {
+                if let WorkoutState::Init = self.state {
+                    self.start_deadline = None;
+                    self.state = WorkoutState::Connected;
+                    self.events.push_back(SessionEvent::Connected);
+                }
+            }
+            S4Message::ModelInfo {
+                model,
+                fw_major,
+                fw_minor,
+            } => self.events.push_back(SessionEvent::ModelInfo {
+                model,
+                fw_major,
+                fw_minor,
+            }),
+            S4Message::Error => {
+                if self.start_deadline.take().is_some() {
+                    self.events
+                        .push_back(SessionEvent::Failed(WaterRowerError::Protocol(
+                            String::from("WaterRower rejected USB connection"),
+                        )));
+                } else if self.program_deadline.take().is_some() {
+                    self.program_pending = 0;
+                    self.events
+                        .push_back(SessionEvent::Failed(WaterRowerError::Protocol(
+                            String::from("WaterRower rejected workout program"),
+                        )));
+                } else {
+                    self.events.push_back(SessionEvent::CommandRejected);
+                }
+            }
+            S4Message::Data {
+                size,
+                address,
+                value: data,
+            } => {
+                for register in self.registers.registers.iter() {
+                    if register.size() == size && register.address == address {
+                        self.raw_values.insert(register.name.clone(), data);
+                        if register.name == DISTANCE {
+                            let distance = register.decode(data).round() as u32;
+                            self.strokes.distance_received(distance);
+                        }
+                    }
+                }
+            }
+            S4Message::StrokeStart => match self.state {
+                WorkoutState::Connected => {
+                    let distance = self.distance_received();
+                    self.strokes.stroke_start(now, distance);
+                    self.first_stroke = Some(now);
+                    self.last_clock_change = Some(now);
+                    self.last_activity = Some(now);
+                    self.last_stroke = Some(now);
+                    self.paused = false;
+                    self.state = WorkoutState::Running;
+                    self.events.push_back(SessionEvent::FirstStroke);
+                    self.sample_begin(now);
+                }
+                WorkoutState::Running => {
+                    self.last_stroke = Some(now);
+                    let distance = self.distance_received();
+                    self.strokes.stroke_start(now, distance);
+                }
+                _ => (),
+            },
+            S4Message::StrokeEnd => self.strokes.stroke_end(now),
+            S4Message::Pulse(count) => self.strokes.pulse(now, count),
+            S4Message::Ok => {
+                if self.program_pending > 0 {
+                    self.program_pending -= 1;
+                    if self.program_pending == 0 {
+                        self.program_deadline = None;
+                        self.events.push_back(SessionEvent::ProgramLoaded);
+                    }
+                }
+            }
+            S4Message::Ping => (),
+        }
+        for stroke in self.strokes.completed() {
+            self.events.push_back(SessionEvent::Stroke(stroke));
+        }
+        for pulse in self.strokes.pulses() {
+            self.events.push_back(SessionEvent::Pulse(pulse));
+        }
+    }
+
+    // Most recent distance reading, none before the register was first received
+    fn distance_received(&self) -> Option<u32> {
+        match (self.registers.get(DISTANCE), self.raw_values.get(DISTANCE)) {
+            (Some(register), Some(raw)) => Some(register.decode(*raw).round() as u32),
+            _ => None,
+        }
+    }
+
+    // Requests every register due in this sample, the sample completes after the interval
+    fn sample_begin(&mut self, now: time::Instant) {
+        self.previous_decimals = self.raw_values.get(DISPLAY_SECOND_DECIMALS).copied();
+        let registers = &self.registers.registers;
+        let polled: Vec<&Register> = self.polled.iter().map(|&i| &registers[i]).collect();
+        let due = self.polling.due(&polled, now);
+        for register in due.iter() {
+            self.polling.requested(register, now);
+            self.transmit.push_back(S4Command::Read {
+                size: register.size(),
+                address: register.address,
+            });
+        }
+        self.sample_deadline = Some(now + self.interval);
+    }
+
+    fn sample_complete(&mut self, now: time::Instant) {
+        self.sample_deadline = None;
+        // A late reply keeps the previous value, only a register never received holds the sample
+        let registers = &self.registers.registers;
+        let raw_values = &self.raw_values;
+        let missing: Vec<usize> = self
+            .polled
+            .iter()
+            .copied()
+            .filter(|&i| !raw_values.contains_key(&registers[i].name))
+            .collect();
+        let mut held: Vec<&Register> = Vec::new();
+        for i in missing {
+            let register = &registers[i];
+            let retries = self
+                .missing_retries
+                .entry(register.name.clone())
+                .or_insert(0);
+            if *retries < MISSING_REGISTER_RETRIES {
+                *retries += 1;
+                held.push(register);
+            } else {
+                self.polled.retain(|&polled| polled != i);
+                self.events.push_back(SessionEvent::RegisterDropped(
+                    WaterRowerError::MissingRegister(register.name.clone()),
+                ));
+            }
+        }
+        if let Some(register) = held.first() {
+            self.events.push_back(SessionEvent::SampleSkipped(
+                WaterRowerError::MissingRegister(register.name.clone()),
+            ));
+            for register in held.iter() {
+                self.polling.forget(register);
+            }
+            self.sample_begin(now);
+            return;
+        }
+        let mut iwv = instant_workout_values_init();
+        instant_workout_values_update(&self.registers, &self.raw_values, &mut iwv);
+        if let Some(first_stroke) = self.first_stroke {
+            iwv.elapsed_in_seconds = now.saturating_duration_since(first_stroke).as_secs_f32();
+        }
+        let decimals = self.raw_values.get(DISPLAY_SECOND_DECIMALS).copied();
+        if iwv.time_in_seconds != self.previous_time_in_seconds
+            || decimals != self.previous_decimals
+        {
+            self.last_clock_change = Some(now);
+        }
+        if iwv.distance_in_meters != self.previous_distance_in_meters
+            || iwv.stroke_count != self.previous_stroke_count
+        {
+            self.last_activity = Some(now);
+        }
+
+        // A reset sets the S4's counters back, the values read belong to no workout
+        if self.finish.reset && iwv.distance_in_meters < self.previous_distance_in_meters {
+            self.finish(FinishReason::Reset);
+            return;
+        }
+
+        // The S4 may still show the counters of a workout rowed before the first stroke
+        if self.program_tracker.is_none() {
+            if let (Some(program), Some(first_stroke)) = (self.program.as_ref(), self.first_stroke)
+            {
+                self.program_tracker =
+                    Some(ProgramTracker::new(program.clone(), first_stroke, &iwv));
+            }
+        }
+        let resting = self
+            .program_tracker
+            .as_ref()
+            .is_some_and(|tracker| tracker.resting());
+        let completed = self
+            .program_tracker
+            .as_mut()
+            .is_some_and(|tracker| tracker.update(now, &mut iwv));
+
+        // Check if the workout was ended, e.g. on the WaterRower device (display clock
+        // stopped), the S4 may hold its clock during the rest of an interval workout
+        let passed =
+            |since: Option<time::Instant>, limit: Option<time::Duration>| match (since, limit) {
+                (Some(since), Some(limit)) => now.saturating_duration_since(since) >= limit,
+                _ => false,
+            };
+        let stopped = if !resting
+            && iwv.time_in_seconds > 0
+            && passed(self.last_clock_change, self.finish.clock_stopped)
+        {
+            Some(FinishReason::ClockStopped)
+        } else if passed(self.last_activity, self.finish.inactivity) {
+            Some(FinishReason::Inactivity)
+        } else if passed(self.last_stroke, self.finish.no_strokes) {
+            Some(FinishReason::NoStrokes)
+        } else {
+            None
+        };
+        if let Some(reason) = stopped {
+            self.finish(reason);
+            return;
+        }
+
+        self.previous_time_in_seconds = iwv.time_in_seconds;
+        self.previous_distance_in_meters = iwv.distance_in_meters;
+        self.previous_stroke_count = iwv.stroke_count;
+        let reached = if completed {
+            Some(FinishReason::ProgramCompleted)
+        } else if self
+            .finish
+            .target_distance_in_meters
+            .is_some_and(|target| iwv.distance_in_meters >= target)
+        {
+            Some(FinishReason::TargetDistance)
+        } else if self
+            .finish
+            .target_time_in_seconds
+            .is_some_and(|target| iwv.time_in_seconds >= target)
+        {
+            Some(FinishReason::TargetTime)
+        } else {
+            None
+        };
+
+        // A pause is part of the workout, it is only marked in the samples
+        iwv.paused = passed(self.last_stroke, Some(self.finish.pause_after));
+        if iwv.paused != self.paused {
+            self.paused = iwv.paused;
+            self.events.push_back(if iwv.paused {
+                SessionEvent::Paused
+            } else {
+                SessionEvent::Resumed
+            });
+        }
+        self.events.push_back(SessionEvent::Sample(iwv));
+        match reached {
+            Some(reason) => self.finish(reason),
+            None => self.sample_begin(now),
+        }
+    }
+
+    fn finish(&mut self, reason: FinishReason) {
+        self.state = WorkoutState::Finished;
+        self.events.push_back(SessionEvent::Finished(reason));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        wr_program::workout_program_parse, wr_protocol::bcd_decode, wr_registers::Polling,
+    };
+
+    const INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+    // What the rower does after a first stretch of rowing
+    type Scenario = fn(&mut Session, &mut Monitor);
+
+    fn bcd(value: u32) -> u32 {
+        value / 10 * 16 + value % 10
+    }
+
+    // Answers the commands of a session like an S4, on a clock driven by the test
+    struct Monitor {
+        now: time::Instant,
+        registers: RegisterMap,
+        memory: HashMap<String, u32>,
+        // Registers whose reads get no reply
+        silent: Vec<&'static str>,
+        // Reads received per register
+        reads: HashMap<String, usize>,
+        events: Vec<SessionEvent>,
+    }
+
+    impl Monitor {
+        fn new() -> Self {
+            let mut monitor = Monitor {
+                now: time::Instant::now(),
+                registers: register_map_bundled(),
+                memory: HashMap::new(),
+                silent: Vec::new(),
+                reads: HashMap::new(),
+                events: Vec::new(),
+            };
+            for register in monitor.registers.registers.iter() {
+                monitor.memory.insert(register.name.clone(), 0);
+            }
+            monitor
+        }
+
+        fn clock_set(&mut self, seconds: u32) {
+            self.memory
+                .insert(DISPLAY_SECONDS.to_owned(), bcd(seconds % 60));
+            self.memory
+                .insert(DISPLAY_MINUTES.to_owned(), bcd(seconds / 60 % 60));
+            self.memory
+                .insert(DISPLAY_HOURS.to_owned(), bcd(seconds / 3600));
+        }
+
+        fn receive(&mut self, session: &mut Session, message: S4Message) {
+            session.handle_line(&message.encode(), self.now).unwrap();
+            self.events
+                .extend(std::iter::from_fn(|| session.poll_event()));
+        }
+
+        fn answer(&mut self, session: &mut Session, command: S4Command) {
+            match command {
+                S4Command::Start => self.receive(session, S4Message::HardwareType),
+                S4Command::Read { size, address } => {
+                    let register = self
+                        .registers
+                        .registers
+                        .iter()
+                        .find(|register| register.address == address && register.size() == size)
+                        .unwrap();
+                    *self.reads.entry(register.name.clone()).or_insert(0) += 1;
+                    if !self.silent.contains(&register.name.as_str()) {
+                        let value = self.memory[&register.name];
+                        self.receive(
+                            session,
+                            S4Message::Data {
+                                size,
+                                address,
+                                value,
+                            },
+                        );
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // Runs the session for a while, answering every command right away
+        fn advance(&mut self, session: &mut Session, duration: time::Duration) {
+            let until = self.now + duration;
+            loop {
+                while let Some(command) = session.poll_transmit(self.now) {
+                    self.answer(session, command);
+                }
+                match session.poll_timeout() {
+                    Some(timeout) if timeout <= until => {
+                        self.now = self.now.max(timeout);
+                        session.handle_timeout(self.now);
+                        self.events
+                            .extend(std::iter::from_fn(|| session.poll_event()));
+                    }
+                    _ => break,
+                }
+            }
+            self.now = until;
+        }
+
+        // Rows for some seconds at a stroke every two seconds and 4 meters per second
+        fn row(&mut self, session: &mut Session, seconds: u32) {
+            for _ in 0..seconds {
+                let time = bcd_decode(self.memory[DISPLAY_SECONDS])
+                    + 60 * bcd_decode(self.memory[DISPLAY_MINUTES]);
+                if time % 2 == 0 {
+                    self.receive(session, S4Message::StrokeStart);
+                    *self.memory.get_mut(STROKE_COUNT).unwrap() += 1;
+                }
+                *self.memory.get_mut(DISTANCE).unwrap() += 4;
+                self.clock_set(time + 1);
+                self.advance(session, time::Duration::from_secs(1));
+            }
+        }
+
+        // Sits still for some seconds, the S4 holds its clock
+        fn rest(&mut self, session: &mut Session, seconds: u32) {
+            self.advance(session, time::Duration::from_secs(u64::from(seconds)));
+        }
+
+        fn samples(&self) -> Vec<&InstantWorkoutValues> {
+            self.events
+                .iter()
+                .filter_map(|event| match event {
+                    SessionEvent::Sample(iwv) => Some(iwv),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        fn finished(&self) -> Option<FinishReason> {
+            self.events.iter().find_map(|event| match event {
+                SessionEvent::Finished(reason) => Some(*reason),
+                _ => None,
+            })
+        }
+
+        fn count(&self, matches: fn(&SessionEvent) -> bool) -> usize {
+            self.events.iter().filter(|event| matches(event)).count()
+        }
+    }
+
+    // Connects a session and takes the first stroke
+    fn session_running(finish: FinishCriteria) -> (Session, Monitor) {
+        let mut session = Session::new(INTERVAL);
+        session.finish_criteria_set(finish);
+        let mut monitor = Monitor::new();
+        session.start(monitor.now);
+        monitor.advance(&mut session, time::Duration::ZERO);
+        assert_eq!(session.state(), WorkoutState::Connected);
+        monitor.row(&mut session, 1);
+        assert_eq!(session.state(), WorkoutState::Running);
+        (session, monitor)
+    }
+
+    #[test]
+    fn first_stroke_starts_at_distance_received() {
+        let mut session = Session::new(INTERVAL);
+        let mut monitor = Monitor::new();
+        // The S4 still shows the distance of a workout rowed before
+        monitor.memory.insert(DISTANCE.to_owned(), 500);
+        session.start(monitor.now);
+        monitor.advance(&mut session, time::Duration::ZERO);
+        for _ in 0..3 {
+            monitor.receive(&mut session, S4Message::StrokeStart);
+            monitor.advance(&mut session, time::Duration::from_millis(800));
+            monitor.receive(&mut session, S4Message::StrokeEnd);
+            *monitor.memory.get_mut(DISTANCE).unwrap() += 8;
+            monitor.advance(&mut session, time::Duration::from_millis(1200));
+        }
+        let strokes: Vec<&StrokeValues> = monitor
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                SessionEvent::Stroke(stroke) => Some(stroke),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(strokes.len(), 2);
+        for stroke in strokes {
+            assert_eq!(stroke.distance_in_meters, 8);
+            assert!(stroke.distance_approximate);
+        }
+    }
+
+    #[test]
+    fn pause_kept_in_workout() {
+        let (mut session, mut monitor) = session_running(FinishCriteria::default());
+        monitor.row(&mut session, 20);
+        monitor.rest(&mut session, 60);
+        monitor.row(&mut session, 20);
+        assert_eq!(monitor.finished(), None);
+        assert_eq!(session.state(), WorkoutState::Running);
+        assert_eq!(monitor.count(|e| matches!(e, SessionEvent::Paused)), 1);
+        assert_eq!(monitor.count(|e| matches!(e, SessionEvent::Resumed)), 1);
+        let paused = monitor.samples().iter().filter(|iwv| iwv.paused).count();
+        assert!((54..=58).contains(&paused), "{} paused samples", paused);
+        assert_eq!(monitor.samples().last().unwrap().time_in_seconds, 41);
+    }
+
+    #[test]
+    fn pause_after_configured() {
+        let finish = FinishCriteria {
+            pause_after: time::Duration::from_secs(30),
+            ..FinishCriteria::default()
+        };
+        let (mut session, mut monitor) = session_running(finish);
+        monitor.row(&mut session, 10);
+        monitor.rest(&mut session, 20);
+        monitor.row(&mut session, 10);
+        assert_eq!(monitor.count(|e| matches!(e, SessionEvent::Paused)), 0);
+        monitor.rest(&mut session, 40);
+        assert_eq!(monitor.count(|e| matches!(e, SessionEvent::Paused)), 1);
+        assert_eq!(monitor.finished(), None);
+    }
+
+    #[test]
+    fn register_missing_skips_sample() {
+        let mut session = Session::new(INTERVAL);
+        let mut monitor = Monitor::new();
+        monitor.silent.push(DISTANCE);
+        session.start(monitor.now);
+        monitor.row(&mut session, 3);
+        assert_eq!(session.state(), WorkoutState::Running);
+        assert!(monitor.samples().is_empty());
+        assert!(monitor.events.iter().all(|event| matches!(
+            event,
+            SessionEvent::Connected
+                | SessionEvent::FirstStroke
+                | SessionEvent::SampleSkipped(WaterRowerError::MissingRegister(_))
+        )));
+
+        // Once received, a register missing a reply keeps its previous value
+        monitor.silent.clear();
+        monitor.row(&mut session, 2);
+        let received = monitor.samples().len();
+        assert!(received > 0);
+        monitor.silent.push(DISTANCE);
+        monitor.row(&mut session, 4);
+        let samples = monitor.samples();
+        let distance = samples[received].distance_in_meters;
+        assert!(distance > 0);
+        assert!(samples.len() >= received + 3);
+        assert!(samples[received..]
+            .iter()
+            .all(|iwv| iwv.distance_in_meters == distance));
+        assert_eq!(samples.last().unwrap().time_in_seconds, 9);
+        assert_eq!(session.state(), WorkoutState::Running);
+    }
+
+    #[test]
+    fn register_never_received_dropped() {
+        let mut session = Session::new(INTERVAL);
+        let mut monitor = Monitor::new();
+        // Firmware without the register, e.g. answers its address with an error
+        monitor.silent.push(ZONE_HEART_RATE);
+        session.start(monitor.now);
+        monitor.row(&mut session, 12);
+        let skipped = monitor.count(|e| matches!(e, SessionEvent::SampleSkipped(_)));
+        assert_eq!(skipped, MISSING_REGISTER_RETRIES as usize);
+        let dropped: Vec<&SessionEvent> = monitor
+            .events
+            .iter()
+            .filter(|e| matches!(e, SessionEvent::RegisterDropped(_)))
+            .collect();
+        assert!(matches!(
+            dropped[..],
+            [SessionEvent::RegisterDropped(WaterRowerError::MissingRegister(ref name))]
+                if name == ZONE_HEART_RATE
+        ));
+        let samples = monitor.samples();
+        assert!(samples.len() >= 4, "{} samples", samples.len());
+        assert!(samples.iter().all(|iwv| iwv.heart_rate == 0));
+        assert!(samples.last().unwrap().distance_in_meters > 0);
+
+        // The register is no longer polled
+        let reads = monitor.reads[ZONE_HEART_RATE];
+        monitor.row(&mut session, 4);
+        assert_eq!(monitor.reads[ZONE_HEART_RATE], reads);
+        assert_eq!(session.state(), WorkoutState::Running);
+    }
+
+    // Reads of a slow and a fast register while rowing 20 seconds
+    fn slow_and_fast_reads(interval: time::Duration) -> (usize, usize) {
+        let mut session = Session::new(interval);
+        let mut monitor = Monitor::new();
+        session.start(monitor.now);
+        monitor.row(&mut session, 20);
+        // Let the reads of the last sample go out
+        monitor.advance(&mut session, time::Duration::from_millis(500));
+        assert_eq!(
+            session.registers.get(ZONE_HEART_RATE).unwrap().polling,
+            Polling::Slow
+        );
+        (monitor.reads[ZONE_HEART_RATE], monitor.reads[DISTANCE])
+    }
+
+    #[test]
+    fn slow_registers_polled_every_other_second() {
+        // The reply comes in after the sample began, slow registers are due anyway
+        let (slow, fast) = slow_and_fast_reads(time::Duration::from_secs(2));
+        assert!(fast >= 9, "{} reads", fast);
+        assert_eq!(slow, fast);
+
+        let (slow, fast) = slow_and_fast_reads(time::Duration::from_secs(1));
+        assert!(fast >= 18, "{} reads", fast);
+        assert_eq!(fast - slow, fast / 2, "{} reads", fast);
+    }
+
+    #[test]
+    fn start_timeout() {
+        let mut session = Session::new(INTERVAL);
+        let now = time::Instant::now();
+        session.start(now);
+        assert_eq!(session.poll_transmit(now), Some(S4Command::Start));
+        assert_eq!(session.poll_timeout(), Some(now + RESPONSE_TIMEOUT));
+        session.handle_timeout(now + RESPONSE_TIMEOUT / 2);
+        assert!(session.poll_event().is_none());
+        session.handle_timeout(now + RESPONSE_TIMEOUT);
+        assert!(matches!(
+            session.poll_event(),
+            Some(SessionEvent::Failed(WaterRowerError::Timeout(_)))
+        ));
+        assert_eq!(session.state(), WorkoutState::Init);
+    }
+
+    #[test]
+    fn sample_complete() {
+        let mut session = Session::new(INTERVAL);
+        let now = time::Instant::now();
+        session.start(now);
+        session.poll_transmit(now);
+        session.handle_line("_WR_", now).unwrap();
+        assert!(matches!(
+            session.poll_event(),
+            Some(SessionEvent::Connected)
+        ));
+        session.handle_line("SS", now).unwrap();
+        assert!(matches!(
+            session.poll_event(),
+            Some(SessionEvent::FirstStroke)
+        ));
+
+        // Reads are spaced, every one answered, the sample completes after the interval
+        let memory: HashMap<u16, u32> = [
+            (0x055, 400),
+            (0x1E0, 0x05),
+            (0x1E1, 0x30),
+            (0x1E2, 0x01),
+            (0x140, 123),
+            (0x1A5, 0x7800),
+            (0x1A9, 24),
+            (0x088, 150),
+        ]
+        .iter()
+        .copied()
+        .collect();
+        let deadline = now + INTERVAL;
+        let mut now = now;
+        let mut reads = 0;
+        while let Some(timeout) = session.poll_timeout().filter(|&t| t < deadline) {
+            now = now.max(timeout);
+            session.handle_timeout(now);
+            while let Some(command) = session.poll_transmit(now) {
+                match command {
+                    S4Command::Read { size, address } => {
+                        reads += 1;
+                        let value = memory.get(&address).copied().unwrap_or(0);
+                        let reply = S4Message::Data {
+                            size,
+                            address,
+                            value,
+                        };
+                        session.handle_line(&reply.encode(), now).unwrap();
+                    }
+                    command => panic!("Expected a read, got {:?}", command),
+                }
+            }
+        }
+        assert_eq!(reads, session.polled.len());
+        assert!(session.poll_event().is_none());
+        session.handle_timeout(deadline);
+        let iwv = match session.poll_event() {
+            Some(SessionEvent::Sample(iwv)) => iwv,
+            event => panic!("Expected a sample, got {:?}", event),
+        };
+        assert_eq!(iwv.distance_in_meters, 400);
+        assert_eq!(iwv.time_in_seconds, 90);
+        assert_eq!(iwv.time_decimals, 5);
+        assert_eq!(iwv.stroke_count, 123);
+        assert_eq!(iwv.seconds_per_500m, 120);
+        assert_eq!(iwv.strokes_per_minute, 24);
+        assert_eq!(iwv.power_in_watts, 150);
+        assert!(!iwv.paused);
+        assert!(session.poll_timeout().is_some());
+    }
+
+    #[test]
+    fn finish_reasons() {
+        let seconds = |s| Some(time::Duration::from_secs(s));
+        let no_clock = FinishCriteria {
+            clock_stopped: None,
+            ..FinishCriteria::default()
+        };
+        let cases: [(FinishCriteria, Scenario, FinishReason); 6] = [
+            (
+                FinishCriteria {
+                    clock_stopped: seconds(10),
+                    ..FinishCriteria::default()
+                },
+                |session, monitor| monitor.rest(session, 15),
+                FinishReason::ClockStopped,
+            ),
+            (
+                FinishCriteria {
+                    inactivity: seconds(10),
+                    ..no_clock.clone()
+                },
+                |session, monitor| monitor.rest(session, 15),
+                FinishReason::Inactivity,
+            ),
+            (
+                FinishCriteria {
+                    no_strokes: seconds(10),
+                    ..no_clock.clone()
+                },
+                // The flywheel coasts, distance and clock go on without strokes
+                |session, monitor| {
+                    for time in 11..26 {
+                        *monitor.memory.get_mut(DISTANCE).unwrap() += 1;
+                        monitor.clock_set(time);
+                        monitor.rest(session, 1);
+                    }
+                },
+                FinishReason::NoStrokes,
+            ),
+            (
+                FinishCriteria {
+                    target_distance_in_meters: Some(60),
+                    ..FinishCriteria::default()
+                },
+                |session, monitor| monitor.row(session, 10),
+                FinishReason::TargetDistance,
+            ),
+            (
+                FinishCriteria {
+                    target_time_in_seconds: Some(15),
+                    ..FinishCriteria::default()
+                },
+                |session, monitor| monitor.row(session, 10),
+                FinishReason::TargetTime,
+            ),
+            (
+                FinishCriteria::default(),
+                |session, monitor| {
+                    monitor.memory.insert(DISTANCE.to_owned(), 0);
+                    monitor.clock_set(0);
+                    monitor.rest(session, 2);
+                },
+                FinishReason::Reset,
+            ),
+        ];
+        for (finish, scenario, reason) in cases.iter() {
+            let (mut session, mut monitor) = session_running(finish.clone());
+            monitor.row(&mut session, 10);
+            assert_eq!(monitor.finished(), None, "{}", reason);
+            scenario(&mut session, &mut monitor);
+            assert_eq!(monitor.finished(), Some(*reason));
+            assert_eq!(session.state(), WorkoutState::Finished);
+            assert!(session.poll_timeout().is_none(), "{}", reason);
+        }
+    }
+
+    #[test]
+    fn finish_program_completed() {
+        let mut session = Session::new(INTERVAL);
+        session.program_track(&workout_program_parse("60m").unwrap());
+        let mut monitor = Monitor::new();
+        session.start(monitor.now);
+        monitor.row(&mut session, 10);
+        assert_eq!(monitor.finished(), None);
+        monitor.row(&mut session, 10);
+        assert_eq!(monitor.finished(), Some(FinishReason::ProgramCompleted));
+        let samples = monitor.samples();
+        let rowed = samples.last().unwrap().distance_in_meters - samples[0].distance_in_meters;
+        assert!((60..=64).contains(&rowed), "{} meters", rowed);
+    }
+
+    #[test]
+    fn program_counts_from_first_sample() {
+        let mut session = Session::new(INTERVAL);
+        session.program_track(&workout_program_parse("60m").unwrap());
+        let mut monitor = Monitor::new();
+        // The S4 still shows the counters of a workout rowed before
+        monitor.memory.insert(DISTANCE.to_owned(), 500);
+        monitor.memory.insert(STROKE_COUNT.to_owned(), 40);
+        session.start(monitor.now);
+        monitor.row(&mut session, 10);
+        assert_eq!(monitor.finished(), None);
+        assert_eq!(monitor.samples().last().unwrap().interval_number, 1);
+        monitor.row(&mut session, 10);
+        assert_eq!(monitor.finished(), Some(FinishReason::ProgramCompleted));
+        let samples = monitor.samples();
+        assert!(samples[0].distance_in_meters > 500);
+        let rowed = samples.last().unwrap().distance_in_meters - samples[0].distance_in_meters;
+        assert!((60..=64).contains(&rowed), "{} meters", rowed);
+    }
+
+    // A stop requested by the user is recorded by the caller, the session closes the communication
+    #[test]
+    fn stop_drops_pending_reads() {
+        let (mut session, mut monitor) = session_running(FinishCriteria::default());
+        monitor.row(&mut session, 1);
+        monitor.now += INTERVAL;
+        session.handle_timeout(monitor.now);
+        assert!(session.poll_timeout().is_some());
+        session.stop();
+        assert_eq!(session.poll_transmit(monitor.now), Some(S4Command::Stop));
+        assert_eq!(session.poll_transmit(monitor.now + INTERVAL), None);
+        assert_eq!(session.poll_timeout(), None);
+        assert_eq!(session.state(), WorkoutState::Finished);
+    }
+}
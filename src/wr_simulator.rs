@@ -0,0 +1,530 @@
+//! Simulated S4 performance monitor on a pseudo-terminal
+
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    pty::openpty,
+    sys::termios,
+    unistd::ttyname,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time,
+};
+
+use crate::{
+    wr_codec::FrameDecoder,
+    wr_protocol::{DataSize, S4Command, S4Message},
+    wr_utils::DebugTrace,
+};
+
+const SIM_POLL_TIMEOUT_MS: i32 = 10;
+const SIM_MODEL: u8 = 4;
+const SIM_FW_MAJOR: u8 = 2;
+const SIM_FW_MINOR: u8 = 10;
+const SIM_TANK_VOLUME_IN_DECILITERS: u32 = 170;
+const SIM_DRIVE_SHARE: f64 = 1.0 / 3.0; // Share of the stroke duration spent on the drive
+const SIM_STROKE_TIME_UNIT: f64 = 0.025; // Stroke times are counted in 25 ms units
+const SIM_PULSE_INTERVAL: f64 = 0.025; // Paddle pulses are reported every 25 ms
+const SIM_PULSES_PER_METER: f64 = 4.805;
+// Boat speed relative to the average, faster on the drive and slower on the recovery
+const SIM_DRIVE_SPEED_FACTOR: f64 = 1.2;
+const SIM_RECOVERY_SPEED_FACTOR: f64 = 0.9;
+// Power from pace as P = 2.8 / pace^3 (pace in seconds per meter), energy at 4 kcal/h per watt
+// plus 300 kcal/h base metabolism, as common for rowing ergometers
+const SIM_POWER_FACTOR: f64 = 2.8;
+const SIM_KCAL_PER_HOUR_PER_WATT: f64 = 4.0;
+const SIM_KCAL_PER_HOUR_BASE: f64 = 300.0;
+
+const ADDR_DISTANCE: u16 = 0x055;
+const ADDR_DISPLAY_DISTANCE: u16 = 0x057;
+const ADDR_CLOCK_COUNT_DOWN: u16 = 0x05A;
+const ADDR_TOTAL_DISTANCE: u16 = 0x080;
+const ADDR_POWER: u16 = 0x088;
+const ADDR_TOTAL_CALORIES: u16 = 0x08A;
+const ADDR_TANK_VOLUME: u16 = 0x0A9;
+const ADDR_STROKE_COUNT: u16 = 0x140;
+const ADDR_STROKE_TIME_AVG: u16 = 0x142;
+const ADDR_STROKE_PULL_TIME_AVG: u16 = 0x143;
+const ADDR_TOTAL_CENTIMETERS_PER_SECOND: u16 = 0x148;
+const ADDR_INSTANT_CENTIMETERS_PER_SECOND: u16 = 0x14A;
+const ADDR_ZONE_CENTIMETERS_PER_SECOND: u16 = 0x1A1;
+const ADDR_ZONE_SECONDS_PER_500M: u16 = 0x1A5;
+const ADDR_ZONE_SECONDS_PER_2KM: u16 = 0x1A7;
+const ADDR_ZONE_STROKE_RATE: u16 = 0x1A9;
+const ADDR_DISPLAY_SECOND_DECIMALS: u16 = 0x1E0;
+const ADDR_DISPLAY_SECONDS: u16 = 0x1E1;
+const ADDR_DISPLAY_MINUTES: u16 = 0x1E2;
+const ADDR_DISPLAY_HOURS: u16 = 0x1E3;
+
+/// Part of a simulated workout rowed at constant pace and stroke rate
+pub struct SimulatorSegment {
+    pub duration_in_seconds: u32,
+    pub seconds_per_500m: u32,
+    pub strokes_per_minute: u32,
+}
+
+/// Parses a segment given as `<seconds>:<seconds per 500m>:<strokes per minute>`
+///
+/// A segment with pace and stroke rate zero is a pause.
+pub fn simulator_segment_parse(segment: &str) -> Result<SimulatorSegment, String> {
+    let parts: Vec<&str> = segment.split(':').collect();
+    if parts.len() != 3 {
+        return Err(format!(
+            "expected <seconds>:<seconds per 500m>:<strokes per minute>, got '{}'",
+            segment
+        ));
+    }
+    let parse = |part: &str| {
+        part.parse::<u32>()
+            .map_err(|e| format!("invalid number '{}': {}", part, e))
+    };
+    let sim_segment = SimulatorSegment {
+        duration_in_seconds: parse(parts[0])?,
+        seconds_per_500m: parse(parts[1])?,
+        strokes_per_minute: parse(parts[2])?,
+    };
+    if (sim_segment.seconds_per_500m == 0) != (sim_segment.strokes_per_minute == 0) {
+        return Err(String::from(
+            "pace and stroke rate must both be greater than zero, or both zero for a pause",
+        ));
+    }
+    Ok(sim_segment)
+}
+
+fn power(seconds_per_500m: u32) -> f64 {
+    if seconds_per_500m == 0 {
+        return 0.0;
+    }
+    SIM_POWER_FACTOR / (seconds_per_500m as f64 / 500.0).powi(3)
+}
+
+struct Simulator {
+    profile: Vec<SimulatorSegment>,
+    start_delay: time::Duration,
+    connected_since: Option<time::Instant>,
+    memory: HashMap<u16, u8>,
+    stroke_count: u32,
+    stroke_drive_end: f64,
+    stroke_drive_ended: bool,
+    next_stroke_start: f64,
+    next_pulse_report: f64,
+    pulse_remainder: f64,
+    // Seconds of a programmed duration workout, counted down instead of the profile
+    programmed_duration: Option<u32>,
+}
+
+impl Simulator {
+    fn new(profile: Vec<SimulatorSegment>, start_delay: time::Duration) -> Self {
+        let mut sim = Simulator {
+            profile,
+            start_delay,
+            connected_since: None,
+            memory: HashMap::new(),
+            stroke_count: 0,
+            stroke_drive_end: 0.0,
+            stroke_drive_ended: true,
+            next_stroke_start: 0.0,
+            next_pulse_report: 0.0,
+            pulse_remainder: 0.0,
+            programmed_duration: None,
+        };
+        sim.reset();
+        sim
+    }
+
+    fn reset(&mut self) {
+        self.memory.clear();
+        self.memory_write(ADDR_TANK_VOLUME, 1, SIM_TANK_VOLUME_IN_DECILITERS);
+        self.stroke_count = 0;
+        self.stroke_drive_end = 0.0;
+        self.stroke_drive_ended = true;
+        self.next_stroke_start = 0.0;
+        self.next_pulse_report = 0.0;
+        self.pulse_remainder = 0.0;
+        self.programmed_duration = None;
+    }
+
+    fn total_duration(&self) -> f64 {
+        self.profile
+            .iter()
+            .map(|s| s.duration_in_seconds as f64)
+            .sum()
+    }
+
+    fn segment_at(&self, elapsed: f64) -> Option<&SimulatorSegment> {
+        self.segment_end_at(elapsed).map(|(segment, _)| segment)
+    }
+
+    // Segment at the given time and the time it ends
+    fn segment_end_at(&self, elapsed: f64) -> Option<(&SimulatorSegment, f64)> {
+        let mut segment_end = 0.0;
+        for segment in self.profile.iter() {
+            segment_end += segment.duration_in_seconds as f64;
+            if elapsed < segment_end {
+                return Some((segment, segment_end));
+            }
+        }
+        None
+    }
+
+    fn distance_at(&self, elapsed: f64) -> f64 {
+        let mut remaining = elapsed;
+        let mut distance = 0.0;
+        for segment in self.profile.iter() {
+            let rowed = remaining.min(segment.duration_in_seconds as f64);
+            if segment.seconds_per_500m > 0 {
+                distance += rowed * 500.0 / segment.seconds_per_500m as f64;
+            }
+            remaining -= rowed;
+        }
+        distance
+    }
+
+    fn calories_at(&self, elapsed: f64) -> f64 {
+        let mut remaining = elapsed;
+        let mut calories = 0.0;
+        for segment in self.profile.iter() {
+            let rowed = remaining.min(segment.duration_in_seconds as f64);
+            let kcal_per_hour = SIM_KCAL_PER_HOUR_PER_WATT * power(segment.seconds_per_500m)
+                + SIM_KCAL_PER_HOUR_BASE;
+            calories += rowed * kcal_per_hour / 3.6;
+            remaining -= rowed;
+        }
+        calories
+    }
+
+    // Multi-byte values are stored with the least significant byte first
+    fn memory_write(&mut self, address: u16, size: u16, value: u32) {
+        for i in 0..size {
+            self.memory
+                .insert(address + i, ((value >> (8 * i)) & 0xFF) as u8);
+        }
+    }
+
+    fn memory_read(&self, address: u16, size: DataSize) -> u32 {
+        (0..size.bytes() as u16)
+            .map(|i| (*self.memory.get(&(address + i)).unwrap_or(&0) as u32) << (8 * i))
+            .sum()
+    }
+
+    fn handle_command(&mut self, command: &S4Command, now: time::Instant) -> Vec<S4Message> {
+        let mut responses: Vec<S4Message> = Vec::new();
+        match *command {
+            S4Command::Start => {
+                self.reset();
+                self.connected_since = Some(now);
+                responses.push(S4Message::HardwareType);
+            }
+            S4Command::Stop => {
+                self.connected_since = None;
+            }
+            S4Command::Reset => {
+                self.reset();
+                self.connected_since = self.connected_since.map(|_| now);
+            }
+            S4Command::ModelInfo => responses.push(S4Message::ModelInfo {
+                model: SIM_MODEL,
+                fw_major: SIM_FW_MAJOR,
+                fw_minor: SIM_FW_MINOR,
+            }),
+            S4Command::Read { size, address } => responses.push(S4Message::Data {
+                size,
+                address,
+                value: self.memory_read(address, size),
+            }),
+            S4Command::WorkoutDuration { seconds } => {
+                self.programmed_duration = Some(seconds.into());
+                responses.push(S4Message::Ok);
+            }
+            S4Command::WorkoutDistance { .. }
+            | S4Command::IntervalDistance { .. }
+            | S4Command::IntervalDuration { .. } => responses.push(S4Message::Ok),
+        }
+        responses
+    }
+
+    fn update(&mut self, now: time::Instant) -> Vec<S4Message> {
+        let mut events: Vec<S4Message> = Vec::new();
+        let elapsed = match self.connected_since {
+            Some(since) if now.duration_since(since) > self.start_delay => {
+                (now.duration_since(since) - self.start_delay).as_secs_f64()
+            }
+            _ => return events,
+        };
+        let total_duration = self.total_duration();
+        let elapsed = elapsed.min(total_duration);
+
+        // Generate stroke events
+        if !self.stroke_drive_ended && elapsed >= self.stroke_drive_end {
+            self.stroke_drive_ended = true;
+            events.push(S4Message::StrokeEnd);
+        }
+        if elapsed < total_duration && elapsed >= self.next_stroke_start {
+            let stroke_start = self.next_stroke_start;
+            let (segment, segment_end) = self.segment_end_at(stroke_start).unwrap();
+            if segment.strokes_per_minute == 0 {
+                // Resume rowing after the pause
+                self.next_stroke_start = segment_end;
+            } else {
+                let stroke_duration = 60.0 / segment.strokes_per_minute as f64;
+                self.stroke_count += 1;
+                self.stroke_drive_end = stroke_start + stroke_duration * SIM_DRIVE_SHARE;
+                self.stroke_drive_ended = false;
+                self.next_stroke_start = stroke_start + stroke_duration;
+                events.push(S4Message::StrokeStart);
+            }
+        }
+
+        // Generate paddle pulse events
+        while self.next_pulse_report <= elapsed && self.next_pulse_report < total_duration {
+            let report_time = self.next_pulse_report;
+            let speed_factor = if report_time < self.stroke_drive_end {
+                SIM_DRIVE_SPEED_FACTOR
+            } else {
+                SIM_RECOVERY_SPEED_FACTOR
+            };
+            let seconds_per_500m = self.segment_at(report_time).unwrap().seconds_per_500m;
+            if seconds_per_500m > 0 {
+                self.pulse_remainder += speed_factor * 500.0 / seconds_per_500m as f64
+                    * SIM_PULSE_INTERVAL
+                    * SIM_PULSES_PER_METER;
+            }
+            let pulses = self.pulse_remainder.floor();
+            self.pulse_remainder -= pulses;
+            if pulses > 0.0 {
+                events.push(S4Message::Pulse(pulses as u32));
+            }
+            self.next_pulse_report += SIM_PULSE_INTERVAL;
+        }
+
+        // Update memory map
+        let distance = self.distance_at(elapsed) as u32;
+        let time_in_seconds = elapsed as u32;
+        let bcd = |value: u32| ((value / 10 % 10) << 4) | (value % 10);
+        self.memory_write(ADDR_DISTANCE, 2, distance);
+        self.memory_write(ADDR_DISPLAY_DISTANCE, 2, distance);
+        self.memory_write(ADDR_TOTAL_DISTANCE, 3, distance);
+        self.memory_write(ADDR_STROKE_COUNT, 2, self.stroke_count);
+        // The count down holds tenths in its first byte and seconds in the following two
+        let count_down = self
+            .programmed_duration
+            .map_or(total_duration, |seconds| seconds as f64);
+        let remaining = ((count_down - elapsed).max(0.0) * 10.0) as u32;
+        self.memory_write(
+            ADDR_CLOCK_COUNT_DOWN,
+            3,
+            ((remaining / 10) << 8) | (remaining % 10),
+        );
+        self.memory_write(
+            ADDR_DISPLAY_SECOND_DECIMALS,
+            1,
+            bcd((elapsed.fract() * 10.0) as u32),
+        );
+        self.memory_write(ADDR_DISPLAY_SECONDS, 1, bcd(time_in_seconds % 60));
+        self.memory_write(ADDR_DISPLAY_MINUTES, 1, bcd(time_in_seconds / 60 % 60));
+        self.memory_write(ADDR_DISPLAY_HOURS, 1, bcd(time_in_seconds / 3600));
+        if time_in_seconds > 0 {
+            let total_speed = (100.0 * self.distance_at(elapsed) / elapsed) as u32;
+            self.memory_write(ADDR_TOTAL_CENTIMETERS_PER_SECOND, 2, total_speed);
+        }
+        let (seconds_per_500m, strokes_per_minute) = match self.segment_at(elapsed) {
+            Some(segment) => (segment.seconds_per_500m, segment.strokes_per_minute),
+            None => (0, 0),
+        };
+        let speed = 50_000_u32.checked_div(seconds_per_500m).unwrap_or(0);
+        self.memory_write(ADDR_INSTANT_CENTIMETERS_PER_SECOND, 2, speed);
+        self.memory_write(ADDR_ZONE_CENTIMETERS_PER_SECOND, 2, speed);
+        // Pace values are stored with the most significant byte first
+        self.memory_write(
+            ADDR_ZONE_SECONDS_PER_500M,
+            2,
+            seconds_per_500m.swap_bytes() >> 16,
+        );
+        self.memory_write(
+            ADDR_ZONE_SECONDS_PER_2KM,
+            2,
+            (4 * seconds_per_500m).swap_bytes() >> 16,
+        );
+        self.memory_write(ADDR_ZONE_STROKE_RATE, 1, strokes_per_minute);
+        self.memory_write(ADDR_POWER, 2, power(seconds_per_500m) as u32);
+        self.memory_write(ADDR_TOTAL_CALORIES, 3, self.calories_at(elapsed) as u32);
+        if strokes_per_minute > 0 {
+            let stroke_time = 60.0 / strokes_per_minute as f64 / SIM_STROKE_TIME_UNIT;
+            self.memory_write(ADDR_STROKE_TIME_AVG, 1, (stroke_time as u32).min(0xFF));
+            self.memory_write(
+                ADDR_STROKE_PULL_TIME_AVG,
+                1,
+                ((stroke_time * SIM_DRIVE_SHARE) as u32).min(0xFF),
+            );
+        }
+
+        events
+    }
+}
+
+fn simulator_send(
+    master: &mut File,
+    message: &S4Message,
+    debug: Option<DebugTrace>,
+) -> std::io::Result<()> {
+    let line = message.encode();
+    if let Some(trace) = debug {
+        trace(&format!("SENT: {}", line));
+    }
+    master.write_all(format!("{}\r\n", line).as_bytes())
+}
+
+// Opens a raw pseudo-terminal, returning its slave and master side
+fn simulator_pty_open() -> Result<(File, File), Box<dyn std::error::Error>> {
+    let pty = openpty(None, None)?;
+    let mut slave_termios = termios::tcgetattr(pty.slave)?;
+    termios::cfmakeraw(&mut slave_termios);
+    termios::tcsetattr(pty.slave, termios::SetArg::TCSANOW, &slave_termios)?;
+    let slave = unsafe { File::from_raw_fd(pty.slave) };
+    let master = unsafe { File::from_raw_fd(pty.master) };
+    Ok((slave, master))
+}
+
+// Answers the commands received on the pseudo-terminal until stop is requested
+fn simulator_serve(
+    mut sim: Simulator,
+    slave: &File,
+    master: &mut File,
+    debug: Option<DebugTrace>,
+    stop_requested: &AtomicBool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut decoder = FrameDecoder::new();
+    let mut buf: Vec<u8> = vec![0; 1024];
+    while !stop_requested.load(Ordering::SeqCst) {
+        let mut poll_fds = [PollFd::new(master.as_raw_fd(), PollFlags::POLLIN)];
+        if poll(&mut poll_fds, SIM_POLL_TIMEOUT_MS)? > 0 {
+            let t = master.read(buf.as_mut_slice())?;
+            decoder.push(&buf[..t]);
+            while let Some(line) = decoder.next_frame() {
+                if let Some(trace) = debug {
+                    trace(&format!("RECEIVED: {}", line));
+                }
+                let command = match S4Command::parse(&line) {
+                    Ok(command) => command,
+                    Err(_) => {
+                        simulator_send(master, &S4Message::Error, debug)?;
+                        continue;
+                    }
+                };
+                if let S4Command::Start = command {
+                    // Discard data a previous client left unread
+                    termios::tcflush(slave.as_raw_fd(), termios::FlushArg::TCIFLUSH)?;
+                }
+                for response in sim.handle_command(&command, time::Instant::now()) {
+                    simulator_send(master, &response, debug)?;
+                }
+            }
+        }
+        for event in sim.update(time::Instant::now()) {
+            simulator_send(master, &event, debug)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs a simulated S4 performance monitor on a pseudo-terminal until interrupted
+///
+/// `available` is called with the device of the pseudo-terminal, once clients
+/// can connect to it.
+pub fn simulator_run<F>(
+    profile: Vec<SimulatorSegment>,
+    start_delay: time::Duration,
+    debug: Option<DebugTrace>,
+    available: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(&Path),
+{
+    // Keep the slave side open, so the pseudo-terminal survives clients disconnecting
+    let (slave, mut master) = simulator_pty_open()?;
+    available(&ttyname(slave.as_raw_fd())?);
+    let sim = Simulator::new(profile, start_delay);
+    simulator_serve(sim, &slave, &mut master, debug, &AtomicBool::new(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{wr_columns, wr_recorder, wr_registers, wr_session::FinishCriteria, wr_utils};
+    use std::{fs, sync::Arc, thread};
+
+    #[test]
+    fn record_against_simulator() {
+        let (slave, mut master) = simulator_pty_open().unwrap();
+        let serial_dev = ttyname(slave.as_raw_fd()).unwrap();
+        let stop_simulator = Arc::new(AtomicBool::new(false));
+        let simulator = {
+            let stop_simulator = Arc::clone(&stop_simulator);
+            let profile = vec![simulator_segment_parse("60:120:30").unwrap()];
+            thread::spawn(move || {
+                let sim = Simulator::new(profile, time::Duration::from_secs(1));
+                simulator_serve(sim, &slave, &mut master, None, &stop_simulator)
+                    .map_err(|e| e.to_string())
+            })
+        };
+
+        let workout_dir = tempfile::tempdir().unwrap();
+        let config = wr_recorder::RecordConfig {
+            serial_dev: serial_dev.display().to_string(),
+            workout_dir: workout_dir.path().to_path_buf(),
+            interval: time::Duration::from_millis(500),
+            pulses: true,
+            capture: None,
+            registers: wr_registers::register_map_bundled(),
+            columns: wr_columns::workout_data_columns_default(),
+            program: None,
+            plan: None,
+            finish: FinishCriteria {
+                target_time_in_seconds: Some(4),
+                ..FinishCriteria::default()
+            },
+            debug: None,
+        };
+        let recorded =
+            wr_recorder::workout_record(&config, Arc::new(AtomicBool::new(false)), &mut []);
+        stop_simulator.store(true, Ordering::SeqCst);
+        simulator.join().unwrap().unwrap();
+        let workout_path = recorded.unwrap();
+
+        let (datapoints, damaged) = wr_utils::read_workout_data_file(&workout_path).unwrap();
+        assert!(damaged.is_none());
+        assert!(datapoints.len() >= 6, "{} datapoints", datapoints.len());
+        assert!(datapoints
+            .windows(2)
+            .all(|pair| pair[0].distance_in_meters <= pair[1].distance_in_meters));
+        // Elapsed time is a default column
+        assert!(datapoints
+            .windows(2)
+            .all(|pair| pair[0].elapsed_in_seconds < pair[1].elapsed_in_seconds));
+        let last = datapoints.last().unwrap();
+        assert!(last.time_in_seconds >= 3);
+        assert!(
+            (12..=18).contains(&last.distance_in_meters),
+            "{} m",
+            last.distance_in_meters
+        );
+        assert!(datapoints.iter().all(|iwv| iwv.seconds_per_500m == 120));
+        assert!(datapoints.iter().all(|iwv| iwv.strokes_per_minute == 30));
+
+        let gwv = wr_utils::global_workout_values_recover(&workout_path, &datapoints).unwrap();
+        assert_eq!(gwv.model, "4");
+        assert_eq!(gwv.fw_version, "02.10");
+        assert_eq!(gwv.finish_reason, "Target time reached");
+        let rows = |file: &str| {
+            let data = fs::read_to_string(workout_path.join(file)).unwrap();
+            data.lines().count() - 1
+        };
+        assert!(rows("strokes.csv") >= 1);
+        assert!(rows("pulses.csv") > 50);
+    }
+}
@@ -0,0 +1,245 @@
+//! Stroke-level data derived from the stroke start and end messages of the S4
+
+use std::time;
+
+use crate::{
+    wr_pulses::{PulseValues, SpeedEstimator, PULSES_PER_METER},
+    wr_utils::DataRow,
+};
+
+pub const STROKE_DATA_FILE: &str = "strokes.csv";
+
+pub const STROKE_DATA_HEADER: [&str; 10] = [
+    "Stroke Number",
+    "Time in Seconds",
+    "Drive Time in Seconds",
+    "Recovery Time in Seconds",
+    "Stroke Duration in Seconds",
+    "Stroke Ratio",
+    "Distance in Meters",
+    "Distance Approximate",
+    "Speed (avg) in Meters per Second",
+    "Speed (max) in Meters per Second",
+];
+
+/// Timing of a single stroke, from its start (SS) to the start of the next stroke
+#[derive(Clone, Debug)]
+pub struct StrokeValues {
+    pub stroke_number: u32,
+    /// Start of the stroke relative to the first stroke of the workout
+    pub time_in_seconds: f32,
+    pub drive_time_in_seconds: f32,
+    pub recovery_time_in_seconds: f32,
+    pub duration_in_seconds: f32,
+    /// Recovery time divided by drive time
+    pub stroke_ratio: f32,
+    pub distance_in_meters: u32,
+    /// Set if the distance is the difference of two distance readings, as the S4
+    /// sent no pulses, which is accurate to the meter at best
+    pub distance_approximate: bool,
+    /// Derived from paddle pulses, 0 if the S4 did not send any
+    pub speed_avg_in_meters_per_second: f32,
+    pub speed_max_in_meters_per_second: f32,
+}
+
+impl DataRow for StrokeValues {
+    fn data_row(&self) -> Vec<String> {
+        vec![
+            format!("{}", self.stroke_number),
+            format!("{:.2}", self.time_in_seconds),
+            format!("{:.2}", self.drive_time_in_seconds),
+            format!("{:.2}", self.recovery_time_in_seconds),
+            format!("{:.2}", self.duration_in_seconds),
+            format!("{:.2}", self.stroke_ratio),
+            format!("{}", self.distance_in_meters),
+            format!("{}", u8::from(self.distance_approximate)),
+            format!("{:.2}", self.speed_avg_in_meters_per_second),
+            format!("{:.2}", self.speed_max_in_meters_per_second),
+        ]
+    }
+}
+
+/// Turns timestamped stroke start, end and pulse messages into completed strokes
+///
+/// A stroke is complete when the next one starts. Strokes with a missing end
+/// message are dropped, as drive and recovery cannot be told apart.
+#[derive(Default)]
+pub struct StrokeTracker {
+    first_start: Option<time::Instant>,
+    start: Option<(time::Instant, Option<u32>)>,
+    end: Option<time::Instant>,
+    stroke_count: u32,
+    completed: Vec<StrokeValues>,
+    speed: SpeedEstimator,
+    stroke_pulses: u32,
+    stroke_speed_max: f32,
+    pulses: Vec<PulseValues>,
+}
+
+impl StrokeTracker {
+    /// Registers a stroke start along with the most recent distance reading
+    ///
+    /// The distance of a stroke is measured by its paddle pulses, the distance
+    /// readings are used only if the S4 did not send any. Without a reading yet,
+    /// e.g. at the first stroke, the stroke starts at the next one received.
+    pub fn stroke_start(&mut self, now: time::Instant, distance_in_meters: Option<u32>) {
+        let first_start = *self.first_start.get_or_insert(now);
+        if let (Some((start, start_distance)), Some(end)) = (self.start, self.end) {
+            let drive = end.duration_since(start).as_secs_f32();
+            let recovery = now.duration_since(end).as_secs_f32();
+            let meters_by_pulses = self.stroke_pulses as f32 / PULSES_PER_METER;
+            let distance = if self.stroke_pulses > 0 {
+                meters_by_pulses.round() as u32
+            } else {
+                match (start_distance, distance_in_meters) {
+                    (Some(start_distance), Some(distance)) => {
+                        distance.saturating_sub(start_distance)
+                    }
+                    _ => 0,
+                }
+            };
+            self.stroke_count += 1;
+            self.completed.push(StrokeValues {
+                stroke_number: self.stroke_count,
+                time_in_seconds: start.duration_since(first_start).as_secs_f32(),
+                drive_time_in_seconds: drive,
+                recovery_time_in_seconds: recovery,
+                duration_in_seconds: drive + recovery,
+                stroke_ratio: if drive > 0.0 { recovery / drive } else { 0.0 },
+                distance_in_meters: distance,
+                distance_approximate: self.stroke_pulses == 0,
+                speed_avg_in_meters_per_second: meters_by_pulses / (drive + recovery),
+                speed_max_in_meters_per_second: self.stroke_speed_max,
+            });
+        }
+        self.start = Some((now, distance_in_meters));
+        self.end = None;
+        self.stroke_pulses = 0;
+        self.stroke_speed_max = 0.0;
+    }
+
+    /// Registers the paddle pulses of a P message, assigning them to the current stroke
+    ///
+    /// Pulses before the first stroke, e.g. of a flywheel still turning from a
+    /// previous workout, are ignored, so they do not add to the speed.
+    pub fn pulse(&mut self, now: time::Instant, pulse_count: u32) {
+        let (first_start, (start, _)) = match (self.first_start, self.start) {
+            (Some(first_start), Some(start)) => (first_start, start),
+            _ => return,
+        };
+        let speed = self.speed.push(now, pulse_count);
+        self.stroke_pulses += pulse_count;
+        self.stroke_speed_max = self.stroke_speed_max.max(speed);
+        self.pulses.push(PulseValues {
+            time_in_seconds: now.duration_since(first_start).as_secs_f32(),
+            pulse_count,
+            speed_in_meters_per_second: speed,
+            stroke_number: self.stroke_count + 1,
+            time_in_stroke_in_seconds: now.duration_since(start).as_secs_f32(),
+        });
+    }
+
+    /// Registers a distance reading, the start distance of a stroke started before any
+    pub fn distance_received(&mut self, distance_in_meters: u32) {
+        if let Some((_, start_distance @ None)) = &mut self.start {
+            *start_distance = Some(distance_in_meters);
+        }
+    }
+
+    pub fn stroke_end(&mut self, now: time::Instant) {
+        if self.start.is_some() && self.end.is_none() {
+            self.end = Some(now);
+        }
+    }
+
+    /// Returns the strokes completed since the last call
+    pub fn completed(&mut self) -> Vec<StrokeValues> {
+        std::mem::take(&mut self.completed)
+    }
+
+    /// Returns the pulses received since the last call
+    pub fn pulses(&mut self) -> Vec<PulseValues> {
+        std::mem::take(&mut self.pulses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(start: time::Instant, millis: u64) -> time::Instant {
+        start + time::Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn stroke_distance_by_pulses() {
+        let t0 = time::Instant::now();
+        let mut tracker = StrokeTracker::default();
+        tracker.stroke_start(t0, Some(0));
+        tracker.stroke_end(at(t0, 800));
+        // 48 pulses make 10 meters
+        for i in 0..12 {
+            tracker.pulse(at(t0, 25 * (i + 1)), 4);
+        }
+        tracker.stroke_start(at(t0, 2500), Some(16));
+        let strokes = tracker.completed();
+        assert_eq!(strokes.len(), 1);
+        assert_eq!(strokes[0].distance_in_meters, 10);
+        assert!(!strokes[0].distance_approximate);
+        assert!((strokes[0].drive_time_in_seconds - 0.8).abs() < 1e-3);
+        assert!((strokes[0].recovery_time_in_seconds - 1.7).abs() < 1e-3);
+    }
+
+    #[test]
+    fn stroke_distance_by_readings_without_pulses() {
+        let t0 = time::Instant::now();
+        let mut tracker = StrokeTracker::default();
+        tracker.stroke_start(t0, Some(100));
+        tracker.stroke_end(at(t0, 800));
+        tracker.stroke_start(at(t0, 2500), Some(108));
+        let strokes = tracker.completed();
+        assert_eq!(strokes[0].distance_in_meters, 8);
+        assert!(strokes[0].distance_approximate);
+    }
+
+    #[test]
+    fn first_stroke_starts_at_next_distance_reading() {
+        let t0 = time::Instant::now();
+        let mut tracker = StrokeTracker::default();
+        // Rowing resumes at 250 m, the first reading arrives after the stroke start
+        tracker.stroke_start(t0, None);
+        tracker.distance_received(250);
+        tracker.stroke_end(at(t0, 800));
+        tracker.distance_received(254);
+        tracker.stroke_start(at(t0, 2500), Some(258));
+        let strokes = tracker.completed();
+        assert_eq!(strokes.len(), 1);
+        assert_eq!(strokes[0].distance_in_meters, 8);
+    }
+
+    #[test]
+    fn stroke_without_end_dropped() {
+        let t0 = time::Instant::now();
+        let mut tracker = StrokeTracker::default();
+        tracker.stroke_start(t0, Some(0));
+        tracker.stroke_start(at(t0, 2500), Some(8));
+        assert!(tracker.completed().is_empty());
+    }
+
+    #[test]
+    fn pulses_before_first_stroke_ignored() {
+        let t0 = time::Instant::now();
+        let mut tracker = StrokeTracker::default();
+        for i in 0..8 {
+            tracker.pulse(at(t0, 25 * i), 10);
+        }
+        assert!(tracker.pulses().is_empty());
+        tracker.stroke_start(at(t0, 200), Some(0));
+        tracker.pulse(at(t0, 225), 1);
+        let pulses = tracker.pulses();
+        assert_eq!(pulses.len(), 1);
+        assert_eq!(pulses[0].stroke_number, 1);
+        let speed = 1.0 / PULSES_PER_METER / 0.25;
+        assert!((pulses[0].speed_in_meters_per_second - speed).abs() < 1e-3);
+    }
+}
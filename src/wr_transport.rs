@@ -0,0 +1,320 @@
+//! Connections to an S4 over serial devices, the network or recorded traces
+
+use chrono::{DateTime, Local};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+    thread, time,
+};
+
+use crate::{
+    wr_discovery::{self, SERIAL_DEV_AUTO},
+    wr_error::WaterRowerError,
+};
+
+pub(crate) const SERIAL_BAUDRATE: u32 = 115_200;
+const SERIAL_TIMEOUT: time::Duration = time::Duration::from_millis(10);
+const TCP_TIMEOUT: time::Duration = time::Duration::from_millis(10);
+
+pub(crate) const TRANSPORT_TCP_PREFIX: &str = "tcp://";
+pub(crate) const TRANSPORT_TRACE_PREFIX: &str = "trace://";
+
+const TRACE_DIRECTION_RECEIVED: &[u8] = b"RX";
+
+/// Communication channel to a WaterRower S4 performance monitor
+///
+/// Received bytes are handed out as they arrive, the session splits them into
+/// messages. This way a capture of what was received replays exactly.
+pub trait Transport {
+    /// Sends a single command, appending the line terminator
+    fn write_command(&mut self, command: &str) -> io::Result<()>;
+    /// Returns the bytes received next, or `None` if nothing arrived before the timeout
+    fn read_bytes(&mut self) -> io::Result<Option<Vec<u8>>>;
+
+    /// Current time of the connection, replayed captures run on their recorded clock
+    fn now(&mut self) -> time::Instant {
+        time::Instant::now()
+    }
+
+    fn sleep(&mut self, duration: time::Duration) {
+        thread::sleep(duration)
+    }
+
+    /// Current date and time, for workout start and end
+    fn local_time(&mut self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// Transport over any byte stream, e.g. a serial port or a TCP socket
+pub struct StreamTransport<S: Read + Write> {
+    stream: S,
+}
+
+impl<S: Read + Write> StreamTransport<S> {
+    pub fn new(stream: S) -> Self {
+        StreamTransport { stream }
+    }
+}
+
+impl<S: Read + Write> Transport for StreamTransport<S> {
+    fn write_command(&mut self, command: &str) -> io::Result<()> {
+        self.stream.write_all(format!("{}\n", command).as_bytes())
+    }
+
+    fn read_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf: Vec<u8> = vec![0; 1024];
+        match self.stream.read(buf.as_mut_slice()) {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(t) => {
+                buf.truncate(t);
+                Ok(Some(buf))
+            }
+            Err(ref e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut
+                        | io::ErrorKind::WouldBlock
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub type SerialTransport = StreamTransport<Box<dyn serialport::SerialPort>>;
+pub type TcpTransport = StreamTransport<TcpStream>;
+
+/// Opens a local serial device the S4 is connected to
+pub fn serial_open(serial_dev: &str) -> Result<SerialTransport, WaterRowerError> {
+    let port = serialport::new(serial_dev, SERIAL_BAUDRATE)
+        .timeout(SERIAL_TIMEOUT)
+        .open()
+        .map_err(|e| WaterRowerError::PortOpen(serial_dev.to_owned(), e.into()))?;
+    Ok(StreamTransport::new(port))
+}
+
+/// Connects to a network serial bridge (e.g. ser2net) forwarding the S4
+pub fn tcp_connect(address: &str) -> Result<TcpTransport, WaterRowerError> {
+    let port_open_error = |e| WaterRowerError::PortOpen(address.to_owned(), e);
+    let stream = TcpStream::connect(address).map_err(port_open_error)?;
+    stream
+        .set_read_timeout(Some(TCP_TIMEOUT))
+        .map_err(port_open_error)?;
+    Ok(StreamTransport::new(stream))
+}
+
+/// In-memory transport returning prepared responses, e.g. for test fixtures
+#[derive(Default)]
+pub struct MemoryTransport {
+    /// Chunks of bytes handed out one per read
+    pub responses: VecDeque<Vec<u8>>,
+    pub commands: Vec<String>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a message, terminated like the S4 does
+    pub fn push_response(&mut self, line: &str) {
+        self.push_bytes(format!("{}\r\n", line).as_bytes());
+    }
+
+    /// Adds bytes as they arrive, e.g. part of a message or noise on the line
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.responses.push_back(bytes.to_vec());
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn write_command(&mut self, command: &str) -> io::Result<()> {
+        self.commands.push(command.to_owned());
+        Ok(())
+    }
+
+    fn read_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.responses.pop_front())
+    }
+}
+
+/// Transport replaying the data received in a recorded trace file
+///
+/// Trace files are CSV files with the columns time in milliseconds, direction
+/// (`TX` for commands sent, `RX` for data received) and data.
+pub struct TraceTransport {
+    received: VecDeque<Vec<u8>>,
+}
+
+impl TraceTransport {
+    pub fn open(trace_file: &Path) -> Result<Self, csv::Error> {
+        let mut received = VecDeque::new();
+        let mut csv_reader = csv::Reader::from_path(trace_file)?;
+        for row in csv_reader.byte_records() {
+            let row = row?;
+            if row.get(1) != Some(TRACE_DIRECTION_RECEIVED) {
+                continue;
+            }
+            received.push_back(row.get(2).unwrap_or(b"").to_vec());
+        }
+        Ok(TraceTransport { received })
+    }
+}
+
+impl Transport for TraceTransport {
+    fn write_command(&mut self, _command: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.received.pop_front() {
+            Some(bytes) => Ok(Some(bytes)),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        }
+    }
+}
+
+/// Opens a transport according to its specification
+///
+/// Supported are `tcp://<host>:<port>`, `trace://<file>`, serial devices and
+/// `auto` for the serial device an S4 is found on, see [`wr_discovery`].
+pub fn transport_open(spec: &str) -> Result<Box<dyn Transport>, WaterRowerError> {
+    if spec == SERIAL_DEV_AUTO {
+        Ok(Box::new(serial_open(&wr_discovery::s4_discover()?)?))
+    } else if let Some(address) = spec.strip_prefix(TRANSPORT_TCP_PREFIX) {
+        Ok(Box::new(tcp_connect(address)?))
+    } else if let Some(trace_file) = spec.strip_prefix(TRANSPORT_TRACE_PREFIX) {
+        let trace = TraceTransport::open(Path::new(trace_file))
+            .map_err(|e| WaterRowerError::PortOpen(trace_file.to_owned(), e.into()))?;
+        Ok(Box::new(trace))
+    } else {
+        Ok(Box::new(serial_open(spec)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, net::TcpListener};
+
+    // Stream handing out scripted read results, e.g. timeouts of a serial port
+    struct ScriptedStream {
+        reads: VecDeque<io::Result<Vec<u8>>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for ScriptedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                Some(Ok(bytes)) => {
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    Ok(bytes.len())
+                }
+                Some(Err(e)) => Err(e),
+                None => Ok(0),
+            }
+        }
+    }
+
+    impl Write for ScriptedStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const TRACE: &str = "Time in Milliseconds,Direction,Data
+0,TX,USB
+12,RX,\"_WR_\r\n\"
+40,TX,IV?
+52,RX,IV4
+53,RX,\"0210\r\n\"
+";
+
+    #[test]
+    fn stream_timeouts_read_nothing() {
+        let reads = vec![
+            Ok(b"_WR_\r".to_vec()),
+            Err(io::Error::from(io::ErrorKind::TimedOut)),
+            Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            Err(io::Error::from(io::ErrorKind::Interrupted)),
+            Ok(b"\n".to_vec()),
+            Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        ];
+        let mut port = StreamTransport::new(ScriptedStream {
+            reads: reads.into_iter().collect(),
+            written: Vec::new(),
+        });
+        port.write_command("USB").unwrap();
+        assert_eq!(port.stream.written, b"USB\n");
+        assert_eq!(port.read_bytes().unwrap(), Some(b"_WR_\r".to_vec()));
+        for _ in 0..3 {
+            assert_eq!(port.read_bytes().unwrap(), None);
+        }
+        assert_eq!(port.read_bytes().unwrap(), Some(b"\n".to_vec()));
+        let error = port.read_bytes().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+        // A stream closed by the other end reads nothing at all
+        let error = port.read_bytes().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn trace_replays_received() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace_file = dir.path().join("trace.csv");
+        fs::write(&trace_file, TRACE).unwrap();
+        let mut port = TraceTransport::open(&trace_file).unwrap();
+        port.write_command("USB").unwrap();
+        assert_eq!(port.read_bytes().unwrap(), Some(b"_WR_\r\n".to_vec()));
+        assert_eq!(port.read_bytes().unwrap(), Some(b"IV4".to_vec()));
+        assert_eq!(port.read_bytes().unwrap(), Some(b"0210\r\n".to_vec()));
+        // The recording ends the communication, it does not time out
+        let error = port.read_bytes().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn trace_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace_file = dir.path().join("trace.csv");
+        fs::write(&trace_file, "Time in Milliseconds,Direction,Data\n0,RX\n").unwrap();
+        assert!(TraceTransport::open(&trace_file).is_err());
+        assert!(TraceTransport::open(&dir.path().join("missing.csv")).is_err());
+    }
+
+    #[test]
+    fn open_by_prefix() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut port = transport_open(&format!("tcp://{}", address)).unwrap();
+        port.write_command("USB").unwrap();
+        let (mut bridge, _) = listener.accept().unwrap();
+        let mut received = [0; 4];
+        bridge.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"USB\n");
+
+        let dir = tempfile::tempdir().unwrap();
+        let trace_file = dir.path().join("trace.csv");
+        fs::write(&trace_file, TRACE).unwrap();
+        let mut port = transport_open(&format!("trace://{}", trace_file.display())).unwrap();
+        assert_eq!(port.read_bytes().unwrap(), Some(b"_WR_\r\n".to_vec()));
+
+        // The error names what was given after the prefix
+        let missing = dir.path().join("missing.csv").display().to_string();
+        match transport_open(&format!("trace://{}", missing)) {
+            Err(WaterRowerError::PortOpen(name, _)) => assert_eq!(name, missing),
+            _ => panic!("Expected a PortOpen error"),
+        }
+    }
+}
@@ -1,234 +1,209 @@
-use chrono::Local;
-use std::{collections::HashMap, io, path::PathBuf, str, thread, time, u32};
-
-const SERIAL_BAUDRATE: u32 = 115_200;
-const SERIAL_TIMEOUT: time::Duration = time::Duration::from_millis(10);
-const SERIAL_COMMAND_WAIT: time::Duration = time::Duration::from_millis(25);
-
-fn serial_initialize(serial_dev: &str) -> Box<dyn serialport::SerialPort> {
-    let port = serialport::new(serial_dev, SERIAL_BAUDRATE)
-        .timeout(SERIAL_TIMEOUT)
-        .open()
-        .expect("!!! Failed to open serial port!");
-    port
-}
-
-fn serial_send_command(port: &mut Box<dyn serialport::SerialPort>, command: &str, debug: bool) {
-    let serial_command = format!("{}\n", command);
-    if debug {
-        print!("COMMAND: {}", serial_command);
-    }
-    port.write_all(serial_command.as_bytes())
-        .expect("!!! Sending command to serial port failed!");
-    thread::sleep(SERIAL_COMMAND_WAIT);
-}
-
-fn serial_receive_response(port: &mut Box<dyn serialport::SerialPort>, debug: bool) -> String {
-    let mut serial_buf: Vec<u8> = vec![0; 1024];
-    let mut serial_response = "";
-    match port.read(serial_buf.as_mut_slice()) {
-        Ok(t) => serial_response = str::from_utf8(&serial_buf[..t]).unwrap(),
-        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
-        Err(e) => eprintln!("!!! Receiving response from serial port failed: {:?}", e),
-    };
-    if debug && !serial_response.is_empty() {
-        println!("--- BEGIN RESPONSE ---");
-        print!("{}", serial_response);
-        println!("--- END RESPONSE ---");
-    }
-    String::from(serial_response)
-}
-
-const CMD_START: &str = "USB";
-const CMD_STOP: &str = "EXIT";
-const _CMD_RESET: &str = "RESET";
-const CMD_MODEL_INFO: &str = "IV?";
-const _CMD_READ_1_BYTE: &str = "IRS";
-const _CMD_READ_2_BYTES: &str = "IRD";
-const _CMD_READ_3_BYTES: &str = "IRT";
-
-const _RET_OK: &str = "OK";
-const RET_ERROR: &str = "ERROR";
-const RET_HW_TYPE: &str = "_WR_";
-const RET_MODEL_INFO: &str = "IV"; // IV + Model (4/5) + Version High + Version Low
-const RET_DATA_1_BYTE: &str = "IDS"; // IDS + Memory Addr + 1st Byte
-const RET_DATA_2_BYTES: &str = "IDD"; // IDD + Memory Addr + 2nd Byte + 1st Byte
-const RET_DATA_3_BYTES: &str = "IDT"; // IDT + Memory Addr + 3rd Byte + 2nd Byte + 1st Byte
-
-const WR_STROKE_START: &str = "SS";
-const _WR_STROKE_END: &str = "SE";
-const _WR_PING: &str = "PING";
-
-const REQUESTING_INTERVAL: time::Duration = time::Duration::from_secs(2);
-
-struct WaterRowerValue {
-    name: &'static str,
-    command: &'static str,
-    response: &'static str,
-}
-
-const _SCREEN_MODE: WaterRowerValue = WaterRowerValue {
-    name: "Screen Mode",
-    command: "IRS00D",
-    response: "IDS00D",
-};
-
-const DISTANCE: WaterRowerValue = WaterRowerValue {
-    name: "Distance",
-    command: "IRD055",
-    response: "IDD055",
-};
-
-const _DISPLAY_DISTANCE: WaterRowerValue = WaterRowerValue {
-    name: "Distance (Display)",
-    command: "IRD057",
-    response: "IDD057",
-};
-
-const _CLOCK_COUNT_DOWN: WaterRowerValue = WaterRowerValue {
-    name: "Clock Count Down",
-    command: "IRT05A",
-    response: "IDT05A",
-};
-
-const _TOTAL_DISTANCE: WaterRowerValue = WaterRowerValue {
-    name: "Total Distance",
-    command: "IRT080",
-    response: "IDT080",
-};
-
-const _TANK_VOLUME: WaterRowerValue = WaterRowerValue {
-    name: "Tank Volume",
-    command: "IRS0A9",
-    response: "IDS0A9",
-};
-
-const STROKE_COUNT: WaterRowerValue = WaterRowerValue {
-    name: "Stroke Count",
-    command: "IRD140",
-    response: "IDD140",
-};
-
-const STROKE_TIME_AVG: WaterRowerValue = WaterRowerValue {
-    name: "Stroke Time Average",
-    command: "IRS142",
-    response: "IDS142",
-};
-
-const STROKE_PULL_TIME_AVG: WaterRowerValue = WaterRowerValue {
-    name: "Stroke Pull Time Average",
-    command: "IRS143",
-    response: "IDS143",
-};
-
-const _TOTAL_CENTIMETERS_PER_SECOND: WaterRowerValue = WaterRowerValue {
-    name: "Total Centimeters per Second",
-    command: "IRD148",
-    response: "IDD148",
-};
-
-const _INSTANT_CENTIMETERS_PER_SECOND: WaterRowerValue = WaterRowerValue {
-    name: "Instant Centimeters per Second",
-    command: "IRD14A",
-    response: "IDD14A",
+//! Workout sessions with an S4, the workout data model and its data files
+
+use chrono::{Duration, NaiveDateTime};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time,
 };
 
-const ZONE_HEART_RATE: WaterRowerValue = WaterRowerValue {
-    name: "Heart Rate (Zone)",
-    command: "IRS1A0",
-    response: "IDS1A0",
+pub use crate::wr_session::{FinishReason, WorkoutState};
+use crate::{
+    wr_capture::{self, CaptureWriter},
+    wr_columns::{
+        workout_data_column, workout_data_columns_default, workout_data_columns_registers,
+        WorkoutDataColumn, WorkoutDataRow,
+    },
+    wr_error::WaterRowerError,
+    wr_program::WorkoutProgram,
+    wr_protocol::S4Command,
+    wr_pulses::{PulseValues, PULSE_DATA_FILE, PULSE_DATA_HEADER},
+    wr_session::{Session, SessionEvent, RESPONSE_TIMEOUT},
+    wr_strokes::{StrokeValues, STROKE_DATA_FILE, STROKE_DATA_HEADER},
+    wr_transport::Transport,
 };
 
-const _ZONE_CENTIMETERS_PER_SECOND: WaterRowerValue = WaterRowerValue {
-    name: "Centimeters per Second (Zone)",
-    command: "IRD1A1",
-    response: "IDD1A1",
-};
+const DATE_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const WORKOUT_DIR_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+const META_DATA_FILE: &str = "meta_data.csv";
+const WORKOUT_DATA_FILE: &str = "workout_data.csv";
+const DATA_FILE_SYNC_INTERVAL: u32 = 10; // Rows written between syncs to disk
 
-const ZONE_SECONDS_PER_500M: WaterRowerValue = WaterRowerValue {
-    name: "Seconds per 500 Meters (Zone)",
-    command: "IRD1A5",
-    response: "IDD1A5",
-};
+/// Receives debug traces of the communication with the S4, e.g. to print them
+pub type DebugTrace = fn(&str);
 
-const _ZONE_SECONDS_PER_2KM: WaterRowerValue = WaterRowerValue {
-    name: "Seconds per 2 Kilometers (Zone)",
-    command: "IRD1A7",
-    response: "IDD1A7",
-};
+fn serial_send_command(
+    ctx: &mut WorkoutContext,
+    command: &S4Command,
+    now: time::Instant,
+) -> Result<(), WaterRowerError> {
+    let serial_command = command.encode();
+    if let Some(trace) = ctx.debug {
+        trace(&format!("COMMAND: {}", serial_command));
+    }
+    if let Some(capture) = ctx.capture.as_mut() {
+        capture.sent(now, &serial_command)?;
+    }
+    ctx.port.write_command(&serial_command)?;
+    Ok(())
+}
 
-const ZONE_STROKE_RATE: WaterRowerValue = WaterRowerValue {
-    name: "Stroke Rate (Zone)",
-    command: "IRS1A9",
-    response: "IDS1A9",
-};
+fn serial_receive_response(
+    port: &mut Box<dyn Transport>,
+    debug: Option<DebugTrace>,
+) -> Result<Vec<Vec<u8>>, WaterRowerError> {
+    let mut chunks: Vec<Vec<u8>> = Vec::new();
+    while let Some(chunk) = port.read_bytes()? {
+        chunks.push(chunk);
+    }
+    if let Some(trace) = debug.filter(|_| !chunks.is_empty()) {
+        trace("--- BEGIN RESPONSE ---");
+        for chunk in chunks.iter() {
+            trace(&chunk.escape_ascii().to_string());
+        }
+        trace("--- END RESPONSE ---");
+    }
+    Ok(chunks)
+}
 
-const _DISPLAY_SECOND_DECIMALS: WaterRowerValue = WaterRowerValue {
-    name: "Second Decimals (Display)",
-    command: "IRS1E0",
-    response: "IDS1E0",
-};
+/// Connection to an S4 and the state of the workout session running on it
+///
+/// The protocol itself is handled by the sans-IO [`Session`], the functions of
+/// this module drive it with blocking I/O.
+pub struct WorkoutContext {
+    pub state: WorkoutState,
+    /// Why the workout finished, if it did
+    pub finish_reason: Option<FinishReason>,
+    pub port: Box<dyn Transport>,
+    pub session: Session,
+    pub debug: Option<DebugTrace>,
+    /// Set e.g. by a signal handler to finish the workout early
+    pub stop_requested: Arc<AtomicBool>,
+    /// Records the communication for a later replay, see [`capture_start`]
+    pub capture: Option<CaptureWriter>,
+    strokes: Vec<StrokeValues>,
+    pulses: Vec<PulseValues>,
+    events: Vec<SessionEvent>,
+}
 
-const DISPLAY_SECONDS: WaterRowerValue = WaterRowerValue {
-    name: "Seconds (Display)",
-    command: "IRS1E1",
-    response: "IDS1E1",
-};
+/// Creates a workout context polling the S4 every `interval`
+pub fn workout_context_init(
+    port: Box<dyn Transport>,
+    interval: time::Duration,
+    debug: Option<DebugTrace>,
+) -> self::WorkoutContext {
+    WorkoutContext {
+        state: WorkoutState::Init,
+        finish_reason: None,
+        port,
+        session: Session::new(interval),
+        debug,
+        stop_requested: Arc::new(AtomicBool::new(false)),
+        capture: None,
+        strokes: Vec::new(),
+        pulses: Vec::new(),
+        events: Vec::new(),
+    }
+}
 
-const DISPLAY_MINUTES: WaterRowerValue = WaterRowerValue {
-    name: "Minutes (Display)",
-    command: "IRS1E2",
-    response: "IDS1E2",
-};
+/// Captures the communication with the S4 from now on to `capture_file`
+pub fn capture_start(ctx: &mut WorkoutContext, capture_file: &Path) -> Result<(), WaterRowerError> {
+    let now = ctx.port.now();
+    ctx.capture = Some(wr_capture::capture_writer_init(
+        capture_file,
+        now,
+        ctx.session.interval(),
+    )?);
+    Ok(())
+}
 
-const DISPLAY_HOURS: WaterRowerValue = WaterRowerValue {
-    name: "Hours (Display)",
-    command: "IRS1E3",
-    response: "IDS1E3",
-};
+/// Polls only the registers needed for the given workout data columns
+pub fn workout_data_columns_select(ctx: &mut WorkoutContext, columns: &[WorkoutDataColumn]) {
+    ctx.session
+        .registers_select(&workout_data_columns_registers(columns));
+}
 
-const WATER_ROWER_VALUES: [WaterRowerValue; 10] = [
-    DISPLAY_SECONDS,
-    DISPLAY_MINUTES,
-    DISPLAY_HOURS,
-    DISTANCE,
-    STROKE_COUNT,
-    STROKE_TIME_AVG,
-    STROKE_PULL_TIME_AVG,
-    ZONE_HEART_RATE,
-    ZONE_SECONDS_PER_500M,
-    ZONE_STROKE_RATE,
-];
-
-pub enum WorkoutState {
-    Init,
-    Connected,
-    Running,
-    Finished,
+fn stop_requested(ctx: &mut WorkoutContext) -> bool {
+    if ctx.stop_requested.load(Ordering::SeqCst) {
+        ctx.state = WorkoutState::Finished;
+        ctx.finish_reason = Some(FinishReason::StopRequested);
+        return true;
+    }
+    false
 }
 
-pub struct WorkoutContext {
-    pub state: WorkoutState,
-    pub port: Box<dyn serialport::SerialPort>,
-    pub debug: bool,
+// Feeds what was received into the session, advances its timers and sends the commands due
+//
+// All of it happens at the same point in time, so a capture of the received
+// bytes, due timers and commands sent can be replayed exactly. The bytes are
+// captured as received, before they are split into messages.
+fn session_step(ctx: &mut WorkoutContext) -> Result<(), WaterRowerError> {
+    let chunks = serial_receive_response(&mut ctx.port, ctx.debug)?;
+    let now = ctx.port.now();
+    for chunk in chunks {
+        if let Some(capture) = ctx.capture.as_mut() {
+            capture.received(now, &chunk)?;
+        }
+        ctx.session.handle_bytes(&chunk, now);
+    }
+    if let Some(capture) = ctx.capture.as_mut() {
+        if ctx
+            .session
+            .poll_timeout()
+            .is_some_and(|timeout| timeout <= now)
+        {
+            capture.timer(now)?;
+        }
+    }
+    ctx.session.handle_timeout(now);
+    while let Some(command) = ctx.session.poll_transmit(now) {
+        serial_send_command(ctx, &command, now)?;
+    }
+    Ok(())
 }
 
-pub fn workout_context_init(serial_dev: &str, debug: bool) -> self::WorkoutContext {
-    let ctx_init = WorkoutContext {
-        state: WorkoutState::Init,
-        port: serial_initialize(serial_dev),
-        debug,
-    };
-    ctx_init
+// Keeps events no caller is waiting for, so they can be collected later
+//
+// The first stroke may come while the S4 is still queried, e.g. for its model,
+// it is kept as the state of the workout then.
+fn session_event_keep(ctx: &mut WorkoutContext, event: SessionEvent) {
+    match event {
+        SessionEvent::FirstStroke => ctx.state = WorkoutState::Running,
+        SessionEvent::Stroke(stroke) => ctx.strokes.push(stroke),
+        SessionEvent::Pulse(pulse) => ctx.pulses.push(pulse),
+        SessionEvent::CommandRejected
+        | SessionEvent::SampleSkipped(_)
+        | SessionEvent::RegisterDropped(_)
+        | SessionEvent::Paused
+        | SessionEvent::Resumed => ctx.events.push(event),
+        _ => (),
+    }
 }
 
+/// Summary of a whole workout, as written to the meta data file
+#[derive(Clone, Debug)]
 pub struct GlobalWorkoutValues {
     pub date_time_start: String,
     pub date_time_end: String,
     pub model: String,
     pub fw_version: String,
+    /// Workout programmed onto the S4, empty if none
+    pub workout_program: String,
+    /// Why the workout finished, empty if recording was interrupted
+    pub finish_reason: String,
     pub datapoints: u32,
     pub total_time_in_seconds: u32,
+    /// Time from the first stroke to the last sample, pauses included
+    pub elapsed_time_in_seconds: f32,
+    /// Elapsed time without pauses
+    pub moving_time_in_seconds: f32,
+    pub pauses: u32,
     pub total_distance_in_meters: u32,
     pub total_stroke_count: u32,
     pub seconds_per_500m_min: u32,
@@ -243,16 +218,25 @@ pub struct GlobalWorkoutValues {
     pub heart_rate_min: u32,
     pub heart_rate_avg: f32,
     pub heart_rate_max: u32,
+    pub power_in_watts_min: u32,
+    pub power_in_watts_avg: f32,
+    pub power_in_watts_max: u32,
+    pub total_calories_in_kcal: u32,
 }
 
-pub fn global_workout_values_init(ctx: &mut WorkoutContext) -> self::GlobalWorkoutValues {
-    let mut gwv_init = GlobalWorkoutValues {
+fn global_workout_values_empty() -> self::GlobalWorkoutValues {
+    GlobalWorkoutValues {
         date_time_start: String::from(""),
         date_time_end: String::from(""),
         model: String::from(""),
         fw_version: String::from(""),
+        workout_program: String::from(""),
+        finish_reason: String::from(""),
         datapoints: 0,
         total_time_in_seconds: 0,
+        elapsed_time_in_seconds: 0.0,
+        moving_time_in_seconds: 0.0,
+        pauses: 0,
         total_distance_in_meters: 0,
         total_stroke_count: 0,
         seconds_per_500m_min: 0,
@@ -267,178 +251,297 @@ pub fn global_workout_values_init(ctx: &mut WorkoutContext) -> self::GlobalWorko
         heart_rate_min: 0,
         heart_rate_avg: 0.0,
         heart_rate_max: 0,
-    };
+        power_in_watts_min: 0,
+        power_in_watts_avg: 0.0,
+        power_in_watts_max: 0,
+        total_calories_in_kcal: 0,
+    }
+}
 
-    // Get current date and time
-    let dt = Local::now();
-    gwv_init.date_time_start = dt.format("%Y-%m-%d %H:%M:%S").to_string();
-
-    // Get WaterRower model and firmware information
-    serial_send_command(&mut ctx.port, CMD_MODEL_INFO, ctx.debug);
-    let serial_response = serial_receive_response(&mut ctx.port, ctx.debug);
-    for line in serial_response.lines() {
-        if &line[0..2] == RET_MODEL_INFO {
-            gwv_init.model = (&line[2..3]).to_owned();
-            gwv_init.fw_version = format!("{}.{}", &line[3..5], &line[5..7]);
+/// Starts the workout summary with the current time and the S4's model information
+pub fn global_workout_values_init(
+    ctx: &mut WorkoutContext,
+) -> Result<self::GlobalWorkoutValues, WaterRowerError> {
+    let mut gwv_init = global_workout_values_empty();
+    gwv_init.date_time_start = date_time_now(ctx)?;
+
+    // Get WaterRower model and firmware information, older models may not answer
+    let request_time = ctx.port.now();
+    ctx.session.model_info_request();
+    while ctx.port.now().duration_since(request_time) < RESPONSE_TIMEOUT {
+        session_step(ctx)?;
+        while let Some(event) = ctx.session.poll_event() {
+            match event {
+                SessionEvent::ModelInfo {
+                    model,
+                    fw_major,
+                    fw_minor,
+                } => {
+                    gwv_init.model = format!("{}", model);
+                    gwv_init.fw_version = format!("{:02}.{:02}", fw_major, fw_minor);
+                    return Ok(gwv_init);
+                }
+                SessionEvent::Failed(e) => return Err(e),
+                event => session_event_keep(ctx, event),
+            }
         }
     }
 
-    gwv_init
+    Ok(gwv_init)
 }
 
+/// Workout values at one point in time, as written to the workout data file
+#[derive(Clone, Debug)]
 pub struct InstantWorkoutValues {
     pub time_in_seconds: u32,
+    /// Tenths of a second of the displayed time
+    pub time_decimals: u32,
+    /// Time since the first stroke, running on when the S4's clock stops
+    pub elapsed_in_seconds: f32,
     pub distance_in_meters: u32,
+    pub display_distance_in_meters: u32,
     pub seconds_per_500m: u32,
+    pub seconds_per_2km: u32,
     pub stroke_count: u32,
     pub strokes_per_minute: u32,
     pub stroke_ratio: f32,
     pub heart_rate: u32,
+    pub power_in_watts: u32,
+    /// Energy burnt since the start of the workout
+    pub calories_in_kcal: u32,
+    pub speed_in_meters_per_second: f32,
+    /// Average speed since the start of the workout
+    pub speed_avg_in_meters_per_second: f32,
+    pub speed_zone_in_meters_per_second: f32,
+    /// Time left of a duration workout, counted down by the S4
+    pub count_down_in_seconds: u32,
+    /// Distance rowed on the machine since it was built
+    pub odometer_in_meters: u32,
+    pub tank_volume_in_liters: f32,
+    pub screen_mode: u32,
+    /// Interval of a programmed workout, counted from 1
+    pub interval_number: u32,
+    /// Meters, strokes or seconds left of the interval or its rest
+    pub interval_remaining: u32,
+    /// Resting after the interval
+    pub interval_resting: bool,
+    /// No stroke was taken for a while, see [`SessionEvent::Paused`]
+    pub paused: bool,
+    /// Registers added by a user register map, by name
+    pub register_values: HashMap<String, f64>,
 }
 
 pub fn instant_workout_values_init() -> self::InstantWorkoutValues {
     InstantWorkoutValues {
         time_in_seconds: 0,
+        time_decimals: 0,
+        elapsed_in_seconds: 0.0,
         distance_in_meters: 0,
+        display_distance_in_meters: 0,
         seconds_per_500m: 0,
+        seconds_per_2km: 0,
         stroke_count: 0,
         strokes_per_minute: 0,
         stroke_ratio: 0.0,
         heart_rate: 0,
+        power_in_watts: 0,
+        calories_in_kcal: 0,
+        speed_in_meters_per_second: 0.0,
+        speed_avg_in_meters_per_second: 0.0,
+        speed_zone_in_meters_per_second: 0.0,
+        count_down_in_seconds: 0,
+        odometer_in_meters: 0,
+        tank_volume_in_liters: 0.0,
+        screen_mode: 0,
+        interval_number: 0,
+        interval_remaining: 0,
+        interval_resting: false,
+        paused: false,
+        register_values: HashMap::new(),
     }
 }
 
-pub fn start(ctx: &mut WorkoutContext) {
-    serial_send_command(&mut ctx.port, CMD_START, ctx.debug);
-    let serial_response = serial_receive_response(&mut ctx.port, ctx.debug);
-    for line in serial_response.lines() {
-        if let RET_HW_TYPE = line {
-            ctx.state = WorkoutState::Connected
+/// Opens the communication with the S4, waiting for it to identify itself
+pub fn start(ctx: &mut WorkoutContext) -> Result<(), WaterRowerError> {
+    let now = ctx.port.now();
+    ctx.session.start(now);
+    loop {
+        session_step(ctx)?;
+        while let Some(event) = ctx.session.poll_event() {
+            match event {
+                SessionEvent::Connected => {
+                    ctx.state = WorkoutState::Connected;
+                    return Ok(());
+                }
+                SessionEvent::Failed(e) => return Err(e),
+                event => session_event_keep(ctx, event),
+            }
         }
     }
 }
 
-pub fn stop(ctx: &mut WorkoutContext) {
-    serial_send_command(&mut ctx.port, CMD_STOP, ctx.debug);
+/// Loads a workout program onto the S4, to be done before the first stroke
+pub fn program_load(
+    ctx: &mut WorkoutContext,
+    program: &WorkoutProgram,
+) -> Result<(), WaterRowerError> {
+    let now = ctx.port.now();
+    ctx.session.program_load(program, now);
+    loop {
+        session_step(ctx)?;
+        while let Some(event) = ctx.session.poll_event() {
+            match event {
+                SessionEvent::ProgramLoaded => return Ok(()),
+                SessionEvent::Failed(e) => return Err(e),
+                event => session_event_keep(ctx, event),
+            }
+        }
+    }
 }
 
-pub fn wait_for_first_stroke(ctx: &mut WorkoutContext) {
+/// Closes the communication with the S4
+pub fn stop(ctx: &mut WorkoutContext) -> Result<(), WaterRowerError> {
+    ctx.session.stop();
     loop {
-        let serial_response = serial_receive_response(&mut ctx.port, ctx.debug);
-        for line in serial_response.lines() {
-            if let WR_STROKE_START = line {
-                ctx.state = WorkoutState::Running;
-            }
+        let now = ctx.port.now();
+        if let Some(command) = ctx.session.poll_transmit(now) {
+            serial_send_command(ctx, &command, now)?;
+            continue;
         }
-        if let WorkoutState::Running = ctx.state {
-            break;
+        match ctx.session.poll_timeout() {
+            Some(timeout) if timeout > now => ctx.port.sleep(timeout - now),
+            _ => return Ok(()),
         }
     }
 }
 
+/// Blocks until the first stroke is detected or stop is requested
+pub fn wait_for_first_stroke(ctx: &mut WorkoutContext) -> Result<(), WaterRowerError> {
+    loop {
+        if ctx.state == WorkoutState::Running || stop_requested(ctx) {
+            return Ok(());
+        }
+        session_step(ctx)?;
+        while let Some(event) = ctx.session.poll_event() {
+            match event {
+                SessionEvent::FirstStroke => {
+                    ctx.state = WorkoutState::Running;
+                    return Ok(());
+                }
+                SessionEvent::Failed(e) => return Err(e),
+                event => session_event_keep(ctx, event),
+            }
+        }
+    }
+}
+
+/// Waits for the next sample of workout values, marking the workout finished when it ended
 pub fn workout_values_update(
     ctx: &mut WorkoutContext,
     iwv: &mut InstantWorkoutValues,
     gwv: &mut GlobalWorkoutValues,
-) {
-    let now = time::Instant::now();
-    let mut raw_values: HashMap<&str, String> = HashMap::new();
-
-    // Send command for every WaterRower value to obtain
-    for value in WATER_ROWER_VALUES.iter() {
-        serial_send_command(&mut ctx.port, value.command, ctx.debug);
-    }
-
-    // Receive response(s) in a loop
-    while now.elapsed() < REQUESTING_INTERVAL {
-        let serial_response = serial_receive_response(&mut ctx.port, ctx.debug);
-        for line in serial_response.lines() {
-            match line {
-                RET_ERROR => println!("!!! Error during WaterRower communication ..."),
-                _ => {
-                    if line.len() > 6
-                        && (&line[0..3] == RET_DATA_1_BYTE
-                            || &line[0..3] == RET_DATA_2_BYTES
-                            || &line[0..3] == RET_DATA_3_BYTES)
-                    {
-                        for value in WATER_ROWER_VALUES.iter() {
-                            if &line[..value.response.len()] == value.response {
-                                raw_values
-                                    .insert(value.name, (&line[value.response.len()..]).to_owned());
-                            }
-                        }
-                    }
+) -> Result<(), WaterRowerError> {
+    loop {
+        if stop_requested(ctx) {
+            return Ok(());
+        }
+        session_step(ctx)?;
+        while let Some(event) = ctx.session.poll_event() {
+            match event {
+                SessionEvent::Sample(values) => {
+                    *iwv = values;
+                    global_workout_values_update(iwv, gwv);
+                    return Ok(());
+                }
+                SessionEvent::Finished(reason) => {
+                    ctx.state = WorkoutState::Finished;
+                    ctx.finish_reason = Some(reason);
+                    return Ok(());
                 }
+                SessionEvent::Failed(e) => return Err(e),
+                event => session_event_keep(ctx, event),
             }
         }
     }
+}
 
-    instant_workout_values_update(&raw_values, iwv);
+/// Strokes completed since the last call, derived from stroke start and end messages
+pub fn completed_strokes(ctx: &mut WorkoutContext) -> Vec<StrokeValues> {
+    std::mem::take(&mut ctx.strokes)
+}
 
-    // Check if workout was ended on WaterRower device
-    if iwv.time_in_seconds > 0 && iwv.time_in_seconds == gwv.total_time_in_seconds {
-        ctx.state = WorkoutState::Finished;
-        return;
-    }
-
-    global_workout_values_update(&iwv, gwv);
-}
-
-#[rustfmt::skip]
-fn instant_workout_values_update(raw_values: &HashMap<&str, String>, iwv: &mut InstantWorkoutValues) {
-    iwv.time_in_seconds =
-        u32::from_str_radix(raw_values.get(DISPLAY_SECONDS.name).unwrap(), 10).unwrap()
-        + 60 * u32::from_str_radix(raw_values.get(DISPLAY_MINUTES.name).unwrap(), 10).unwrap()
-        + 3600 * u32::from_str_radix(raw_values.get(DISPLAY_HOURS.name).unwrap(), 10).unwrap();
-    iwv.distance_in_meters =
-        u32::from_str_radix(raw_values.get(DISTANCE.name).unwrap(), 16).unwrap();
-    iwv.seconds_per_500m =
-        u32::from_str_radix(&(raw_values.get(ZONE_SECONDS_PER_500M.name).unwrap())[0..2], 16).unwrap()
-        + 256 * u32::from_str_radix(&(raw_values.get(ZONE_SECONDS_PER_500M.name).unwrap())[2..4], 16).unwrap();
-    iwv.stroke_count =
-        u32::from_str_radix(raw_values.get(STROKE_COUNT.name).unwrap(), 16).unwrap();
-    iwv.strokes_per_minute =
-        u32::from_str_radix(raw_values.get(ZONE_STROKE_RATE.name).unwrap(), 16).unwrap();
-    
-    // Somewhat vague note for stroke ratio calculation from WaterRower docs:
-    //   Stroke_pull is first subtracted from stroke_average
-    //   then a modifier of 1.25 multiplied by the result to generate the ratio value for display.
-    let stroke_time_avg: f32 =
-        u32::from_str_radix(raw_values.get(STROKE_TIME_AVG.name).unwrap(), 16).unwrap() as f32;
-    let pull_time_avg: f32 =
-        u32::from_str_radix(raw_values.get(STROKE_PULL_TIME_AVG.name).unwrap(), 16).unwrap() as f32;
-    if pull_time_avg > 0.0 {
-        iwv.stroke_ratio = (stroke_time_avg - pull_time_avg) / (pull_time_avg * 1.25);
-    }
-    else {
-        iwv.stroke_ratio = 0.0;
-    }
-    
-    iwv.heart_rate =
-        u32::from_str_radix(raw_values.get(ZONE_HEART_RATE.name).unwrap(), 16).unwrap();
-}
-
-fn global_workout_values_update(iwv: &InstantWorkoutValues, gwv: &mut GlobalWorkoutValues) {
+/// Paddle pulses received since the last call, with the boat speed derived from them
+pub fn received_pulses(ctx: &mut WorkoutContext) -> Vec<PulseValues> {
+    std::mem::take(&mut ctx.pulses)
+}
+
+/// Pauses, skipped samples, dropped registers and rejected commands reported since the last call
+pub fn session_events(ctx: &mut WorkoutContext) -> Vec<SessionEvent> {
+    std::mem::take(&mut ctx.events)
+}
+
+/// Updates the workout totals with a recorded sample
+pub fn global_workout_values_update(iwv: &InstantWorkoutValues, gwv: &mut GlobalWorkoutValues) {
     gwv.datapoints += 1;
     gwv.total_time_in_seconds = iwv.time_in_seconds;
     gwv.total_distance_in_meters = iwv.distance_in_meters;
     gwv.total_stroke_count = iwv.stroke_count;
+    gwv.total_calories_in_kcal = iwv.calories_in_kcal;
 }
 
+/// Current date and time as written to the meta data file
+pub fn date_time_now(ctx: &mut WorkoutContext) -> Result<String, WaterRowerError> {
+    let local_time = ctx.port.local_time();
+    if let Some(capture) = ctx.capture.as_mut() {
+        let now = ctx.port.now();
+        capture.clock(now, &local_time)?;
+    }
+    Ok(local_time.format(DATE_TIME_FORMAT).to_string())
+}
+
+/// Completes the workout summary with elapsed and moving time and min/avg/max of all samples
+///
+/// Paused samples count for the elapsed time only, so a break does not drag
+/// down the averages. Without elapsed times, e.g. of a recovered workout
+/// recorded without them, the S4's clock is used instead.
 pub fn global_workout_values_finalize(
     datapoints: &[InstantWorkoutValues],
     gwv: &mut GlobalWorkoutValues,
 ) {
-    // Get current date and time
-    let dt = Local::now();
-    gwv.date_time_end = dt.format("%Y-%m-%d %H:%M:%S").to_string();
+    let elapsed = datapoints.iter().any(|iwv| iwv.elapsed_in_seconds > 0.0);
+    let sample_time = |iwv: &InstantWorkoutValues| {
+        if elapsed {
+            iwv.elapsed_in_seconds
+        } else {
+            iwv.time_in_seconds as f32
+        }
+    };
+    // Computed from the samples alone, so finalizing again gives the same summary
+    let mut moving_time = 0.0;
+    let mut pauses = 0;
+    let mut previous_time = 0.0;
+    let mut previous_paused = false;
+    for values in datapoints.iter() {
+        let time = sample_time(values);
+        if !values.paused {
+            moving_time += (time - previous_time).max(0.0);
+        } else if !previous_paused {
+            pauses += 1;
+        }
+        previous_time = time;
+        previous_paused = values.paused;
+    }
+    gwv.elapsed_time_in_seconds = previous_time;
+    gwv.moving_time_in_seconds = moving_time;
+    gwv.pauses = pauses;
 
-    // Get valid values out of all datapoints
+    // Get valid values out of all datapoints rowed
     let mut seconds_per_500m_valid_values: Vec<u32> = Vec::new();
     let mut strokes_per_minute_valid_values: Vec<u32> = Vec::new();
     let mut stroke_ratio_valid_values: Vec<f32> = Vec::new();
     let mut heart_rate_valid_values: Vec<u32> = Vec::new();
-    for values in datapoints.iter() {
+    let mut power_valid_values: Vec<u32> = Vec::new();
+    for values in datapoints.iter().filter(|values| !values.paused) {
         if values.seconds_per_500m > 0 {
             seconds_per_500m_valid_values.push(values.seconds_per_500m);
         }
@@ -451,6 +554,9 @@ pub fn global_workout_values_finalize(
         if values.heart_rate > 0 {
             heart_rate_valid_values.push(values.heart_rate);
         }
+        if values.power_in_watts > 0 {
+            power_valid_values.push(values.power_in_watts);
+        }
     }
 
     // Calculate min, max, average
@@ -484,109 +590,383 @@ pub fn global_workout_values_finalize(
         gwv.heart_rate_avg = heart_rate_valid_values.iter().sum::<u32>() as f32
             / heart_rate_valid_values.iter().len() as f32;
     }
+    if !power_valid_values.is_empty() {
+        gwv.power_in_watts_min = *power_valid_values.iter().min().unwrap();
+        gwv.power_in_watts_max = *power_valid_values.iter().max().unwrap();
+        gwv.power_in_watts_avg =
+            power_valid_values.iter().sum::<u32>() as f32 / power_valid_values.len() as f32;
+    }
 }
 
+/// Writes the workout summary to the meta data file of a workout directory
 pub fn write_meta_data_file(
-    workout_dir: &PathBuf,
+    workout_dir: &Path,
     gwv: &GlobalWorkoutValues,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let csv_file = PathBuf::from(format!(
-        "{}{}",
-        &workout_dir.to_str().unwrap(),
-        "/meta_data.csv",
-    ));
-    let mut csv_writer = csv::Writer::from_path(csv_file).unwrap();
-
-    csv_writer.write_record(&["Date and Time of Start", &gwv.date_time_start])?;
-    csv_writer.write_record(&["Date and Time of End", &gwv.date_time_end])?;
-    csv_writer.write_record(&["WaterRower Model", &gwv.model])?;
-    csv_writer.write_record(&["Firmware Version", &gwv.fw_version])?;
-    csv_writer.write_record(&["Number of Data Points", &format!("{}", gwv.datapoints)])?;
-    csv_writer.write_record(&[
+) -> Result<(), WaterRowerError> {
+    meta_data_file_write(workout_dir, gwv).map_err(WaterRowerError::FileWrite)
+}
+
+fn meta_data_file_write(workout_dir: &Path, gwv: &GlobalWorkoutValues) -> csv::Result<()> {
+    let mut csv_writer = csv::Writer::from_path(workout_dir.join(META_DATA_FILE))?;
+
+    csv_writer.write_record(["Date and Time of Start", &gwv.date_time_start])?;
+    csv_writer.write_record(["Date and Time of End", &gwv.date_time_end])?;
+    csv_writer.write_record(["WaterRower Model", &gwv.model])?;
+    csv_writer.write_record(["Firmware Version", &gwv.fw_version])?;
+    csv_writer.write_record(["Workout Program", &gwv.workout_program])?;
+    csv_writer.write_record(["Finish Reason", &gwv.finish_reason])?;
+    csv_writer.write_record(["Number of Data Points", &format!("{}", gwv.datapoints)])?;
+    csv_writer.write_record([
         "Total Time in Seconds",
         &format!("{}", gwv.total_time_in_seconds),
     ])?;
-    csv_writer.write_record(&[
+    csv_writer.write_record([
+        "Elapsed Time in Seconds",
+        &format!("{:.1}", gwv.elapsed_time_in_seconds),
+    ])?;
+    csv_writer.write_record([
+        "Moving Time in Seconds",
+        &format!("{:.1}", gwv.moving_time_in_seconds),
+    ])?;
+    csv_writer.write_record(["Number of Pauses", &format!("{}", gwv.pauses)])?;
+    csv_writer.write_record([
         "Total Distance in Meters",
         &format!("{}", gwv.total_distance_in_meters),
     ])?;
-    csv_writer.write_record(&["Total Stroke Count", &format!("{}", gwv.total_stroke_count)])?;
-    csv_writer.write_record(&[
+    csv_writer.write_record(["Total Stroke Count", &format!("{}", gwv.total_stroke_count)])?;
+    csv_writer.write_record([
         "Seconds per 500 Meters (min)",
         &format!("{}", gwv.seconds_per_500m_min),
     ])?;
-    csv_writer.write_record(&[
+    csv_writer.write_record([
         "Seconds per 500 Meters (avg)",
         &format!("{:.2}", gwv.seconds_per_500m_avg),
     ])?;
-    csv_writer.write_record(&[
+    csv_writer.write_record([
         "Seconds per 500 Meters (max)",
         &format!("{}", gwv.seconds_per_500m_max),
     ])?;
-    csv_writer.write_record(&[
+    csv_writer.write_record([
         "Strokes per Minute (min)",
         &format!("{}", gwv.strokes_per_minute_min),
     ])?;
-    csv_writer.write_record(&[
+    csv_writer.write_record([
         "Strokes per Minute (avg)",
         &format!("{:.2}", gwv.strokes_per_minute_avg),
     ])?;
-    csv_writer.write_record(&[
+    csv_writer.write_record([
         "Strokes per Minute (max)",
         &format!("{}", gwv.strokes_per_minute_max),
     ])?;
-    csv_writer.write_record(&[
+    csv_writer.write_record([
         "Stroke Ratio (min)",
         &format!("{:.2}", gwv.stroke_ratio_min),
     ])?;
-    csv_writer.write_record(&[
+    csv_writer.write_record([
         "Stroke Ratio (avg)",
         &format!("{:.2}", gwv.stroke_ratio_avg),
     ])?;
-    csv_writer.write_record(&[
+    csv_writer.write_record([
         "Stroke Ratio (max)",
         &format!("{:.2}", gwv.stroke_ratio_max),
     ])?;
-    csv_writer.write_record(&["Heart Rate (min)", &format!("{}", gwv.heart_rate_min)])?;
-    csv_writer.write_record(&["Heart Rate (avg)", &format!("{:.2}", gwv.heart_rate_avg)])?;
-    csv_writer.write_record(&["Heart Rate (max)", &format!("{}", gwv.heart_rate_max)])?;
+    csv_writer.write_record(["Heart Rate (min)", &format!("{}", gwv.heart_rate_min)])?;
+    csv_writer.write_record(["Heart Rate (avg)", &format!("{:.2}", gwv.heart_rate_avg)])?;
+    csv_writer.write_record(["Heart Rate (max)", &format!("{}", gwv.heart_rate_max)])?;
+    csv_writer.write_record([
+        "Power in Watts (min)",
+        &format!("{}", gwv.power_in_watts_min),
+    ])?;
+    csv_writer.write_record([
+        "Power in Watts (avg)",
+        &format!("{:.2}", gwv.power_in_watts_avg),
+    ])?;
+    csv_writer.write_record([
+        "Power in Watts (max)",
+        &format!("{}", gwv.power_in_watts_max),
+    ])?;
+    csv_writer.write_record([
+        "Total Calories in kcal",
+        &format!("{}", gwv.total_calories_in_kcal),
+    ])?;
     csv_writer.flush()?;
     Ok(())
 }
 
-pub fn write_workout_data_file(
-    workout_dir: &PathBuf,
+/// Name of the directory a workout is stored in, derived from its start
+pub fn workout_dir_name(gwv: &GlobalWorkoutValues) -> String {
+    match NaiveDateTime::parse_from_str(&gwv.date_time_start, DATE_TIME_FORMAT) {
+        Ok(dt) => dt.format(WORKOUT_DIR_FORMAT).to_string(),
+        Err(_) => gwv.date_time_start.replace(' ', "_").replace(':', "-"),
+    }
+}
+
+/// Values that are recorded as one row of a data file
+pub trait DataRow {
+    fn data_row(&self) -> Vec<String>;
+}
+
+/// Row of the default workout data columns
+impl DataRow for InstantWorkoutValues {
+    fn data_row(&self) -> Vec<String> {
+        WorkoutDataRow {
+            values: self,
+            columns: &workout_data_columns_default(),
+        }
+        .data_row()
+    }
+}
+
+// Flushing and syncing a data file fails with a plain I/O error
+pub(crate) fn file_write_error(e: io::Error) -> WaterRowerError {
+    WaterRowerError::FileWrite(csv::Error::from(e))
+}
+
+/// Writer appending rows to a data file as they are recorded
+pub struct DataFileWriter {
+    csv_writer: csv::Writer<fs::File>,
+    rows_since_sync: u32,
+}
+
+/// Creates a data file with the given header row
+pub fn data_file_writer_init(
+    file_path: &Path,
+    header: &[&str],
+) -> Result<DataFileWriter, WaterRowerError> {
+    let mut csv_writer = csv::Writer::from_path(file_path).map_err(WaterRowerError::FileWrite)?;
+    csv_writer
+        .write_record(header)
+        .map_err(WaterRowerError::FileWrite)?;
+    csv_writer.flush().map_err(file_write_error)?;
+    Ok(DataFileWriter {
+        csv_writer,
+        rows_since_sync: 0,
+    })
+}
+
+pub fn workout_data_writer_init(
+    workout_dir: &Path,
+    columns: &[WorkoutDataColumn],
+) -> Result<DataFileWriter, WaterRowerError> {
+    let header: Vec<&str> = columns
+        .iter()
+        .map(|column| column.header.as_str())
+        .collect();
+    data_file_writer_init(&workout_dir.join(WORKOUT_DATA_FILE), &header)
+}
+
+pub fn stroke_data_writer_init(workout_dir: &Path) -> Result<DataFileWriter, WaterRowerError> {
+    data_file_writer_init(&workout_dir.join(STROKE_DATA_FILE), &STROKE_DATA_HEADER)
+}
+
+pub fn pulse_data_writer_init(workout_dir: &Path) -> Result<DataFileWriter, WaterRowerError> {
+    data_file_writer_init(&workout_dir.join(PULSE_DATA_FILE), &PULSE_DATA_HEADER)
+}
+
+impl DataFileWriter {
+    pub fn write<T: DataRow>(&mut self, values: &T) -> Result<(), WaterRowerError> {
+        self.csv_writer
+            .write_record(values.data_row())
+            .map_err(WaterRowerError::FileWrite)?;
+
+        // Hand every row to the OS, so only a power cut can lose the last few
+        self.csv_writer.flush().map_err(file_write_error)?;
+        self.rows_since_sync += 1;
+        if self.rows_since_sync >= DATA_FILE_SYNC_INTERVAL {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<(), WaterRowerError> {
+        self.csv_writer.flush().map_err(file_write_error)?;
+        self.csv_writer
+            .get_ref()
+            .sync_data()
+            .map_err(file_write_error)?;
+        self.rows_since_sync = 0;
+        Ok(())
+    }
+}
+
+/// Reads the samples of a workout data file, stopping at the first damaged row
+///
+/// Besides the samples, the error of a damaged row is returned, if reading
+/// stopped at one.
+pub fn read_workout_data_file(
+    workout_dir: &Path,
+) -> Result<(Vec<InstantWorkoutValues>, Option<WaterRowerError>), WaterRowerError> {
+    let mut csv_reader = csv::Reader::from_path(workout_dir.join(WORKOUT_DATA_FILE))
+        .map_err(WaterRowerError::FileRead)?;
+    let header = csv_reader
+        .headers()
+        .map_err(WaterRowerError::FileRead)?
+        .clone();
+
+    let mut datapoints: Vec<InstantWorkoutValues> = Vec::new();
+    for row in csv_reader.records() {
+        // A crash may leave an incomplete last row behind
+        let iwv = row
+            .map_err(WaterRowerError::FileRead)
+            .and_then(|row| workout_data_row_parse(&header, &row));
+        match iwv {
+            Ok(iwv) => datapoints.push(iwv),
+            Err(e) => return Ok((datapoints, Some(e))),
+        }
+    }
+    Ok((datapoints, None))
+}
+
+// Values of a workout data row, columns that were not recorded read as zero
+fn workout_data_row_parse(
+    header: &csv::StringRecord,
+    row: &csv::StringRecord,
+) -> Result<InstantWorkoutValues, WaterRowerError> {
+    fn field<T: FromStr + Default>(
+        header: &csv::StringRecord,
+        row: &csv::StringRecord,
+        key: &str,
+    ) -> Result<T, WaterRowerError> {
+        let name = workout_data_column(key).map_or(String::new(), |column| column.header);
+        let value = match header.iter().position(|h| h == name) {
+            Some(i) => row.get(i).unwrap_or(""),
+            None => return Ok(T::default()),
+        };
+        value.parse().map_err(|_| {
+            let line = row.position().map_or(0, |position| position.line());
+            let message = format!("invalid {} '{}' in line {}", name, value, line);
+            WaterRowerError::FileRead(io::Error::new(io::ErrorKind::InvalidData, message).into())
+        })
+    }
+
+    let mut iwv = instant_workout_values_init();
+    iwv.time_in_seconds = field(header, row, "time")?;
+    iwv.elapsed_in_seconds = field(header, row, "elapsed")?;
+    iwv.distance_in_meters = field(header, row, "distance")?;
+    iwv.seconds_per_500m = field(header, row, "pace")?;
+    iwv.stroke_count = field(header, row, "strokes")?;
+    iwv.strokes_per_minute = field(header, row, "stroke-rate")?;
+    iwv.stroke_ratio = field(header, row, "ratio")?;
+    iwv.heart_rate = field(header, row, "heart-rate")?;
+    iwv.power_in_watts = field(header, row, "power")?;
+    iwv.calories_in_kcal = field(header, row, "calories")?;
+    iwv.paused = field::<u8>(header, row, "paused")? == 1;
+    Ok(iwv)
+}
+
+/// Rebuilds the global workout values of a workout from the datapoints of its (partial) data file
+///
+/// Start time, model and firmware version are taken from a previously written
+/// meta data file if present, otherwise the start is derived from the directory name.
+pub fn global_workout_values_recover(
+    workout_dir: &Path,
     datapoints: &[InstantWorkoutValues],
-) -> Result<(), Box<dyn std::error::Error>> {
-    let csv_file = PathBuf::from(format!(
-        "{}{}",
-        &workout_dir.to_str().unwrap(),
-        "/workout_data.csv",
-    ));
-    let mut csv_writer = csv::Writer::from_path(csv_file).unwrap();
-
-    let csv_header = [
-        "Time in Seconds",
-        "Distance in Meters",
-        "Seconds per 500 Meters",
-        "Stroke Count",
-        "Strokes per Minute",
-        "Stroke Ratio",
-        "Heart Rate",
-    ];
-    csv_writer.write_record(&csv_header)?;
-    for values in datapoints.iter() {
-        let csv_row = [
-            &format!("{}", values.time_in_seconds),
-            &format!("{}", values.distance_in_meters),
-            &format!("{}", values.seconds_per_500m),
-            &format!("{}", values.stroke_count),
-            &format!("{}", values.strokes_per_minute),
-            &format!("{:.2}", values.stroke_ratio),
-            &format!("{}", values.heart_rate),
-        ];
-        csv_writer.write_record(&csv_row)?;
+) -> Result<GlobalWorkoutValues, WaterRowerError> {
+    let mut gwv = global_workout_values_empty();
+    if let Ok(mut csv_reader) = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(workout_dir.join(META_DATA_FILE))
+    {
+        for row in csv_reader.records().flatten() {
+            match (row.get(0), row.get(1)) {
+                (Some("Date and Time of Start"), Some(value)) => {
+                    gwv.date_time_start = value.to_owned()
+                }
+                (Some("WaterRower Model"), Some(value)) => gwv.model = value.to_owned(),
+                (Some("Firmware Version"), Some(value)) => gwv.fw_version = value.to_owned(),
+                (Some("Workout Program"), Some(value)) => gwv.workout_program = value.to_owned(),
+                (Some("Finish Reason"), Some(value)) => gwv.finish_reason = value.to_owned(),
+                _ => (),
+            }
+        }
+    }
+    if gwv.date_time_start.is_empty() {
+        let dir_name = workout_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        if let Ok(dt) = NaiveDateTime::parse_from_str(dir_name, WORKOUT_DIR_FORMAT) {
+            gwv.date_time_start = dt.format(DATE_TIME_FORMAT).to_string();
+        }
+    }
+
+    for iwv in datapoints.iter() {
+        global_workout_values_update(iwv, &mut gwv);
+    }
+    global_workout_values_finalize(datapoints, &mut gwv);
+
+    // End of workout is the start plus its duration, not the time of recovery
+    gwv.date_time_end = match NaiveDateTime::parse_from_str(&gwv.date_time_start, DATE_TIME_FORMAT)
+    {
+        Ok(dt) => (dt + Duration::seconds(gwv.total_time_in_seconds as i64))
+            .format(DATE_TIME_FORMAT)
+            .to_string(),
+        Err(_) => String::from(""),
+    };
+    Ok(gwv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finalize_moving_time_and_pauses() {
+        // Rowing 10 s, a 6 s pause, rowing 4 s, a 2 s pause
+        let datapoints: Vec<InstantWorkoutValues> = (1..=22)
+            .map(|second| {
+                let mut iwv = instant_workout_values_init();
+                iwv.elapsed_in_seconds = second as f32;
+                iwv.paused = (11..=16).contains(&second) || second > 20;
+                iwv.seconds_per_500m = if iwv.paused { 600 } else { 120 };
+                iwv
+            })
+            .collect();
+        let mut gwv = global_workout_values_empty();
+        for _ in 0..2 {
+            global_workout_values_finalize(&datapoints, &mut gwv);
+            assert_eq!(gwv.elapsed_time_in_seconds, 22.0);
+            assert_eq!(gwv.moving_time_in_seconds, 14.0);
+            assert_eq!(gwv.pauses, 2);
+            assert_eq!(gwv.seconds_per_500m_max, 120);
+        }
+    }
+
+    #[test]
+    fn recover_truncated_workout() {
+        let dir = tempfile::tempdir().unwrap();
+        // Recorded before a crash, without meta data
+        let workout_dir = dir.path().join("2024-05-01_08-15-00");
+        fs::create_dir(&workout_dir).unwrap();
+        let header: Vec<String> = ["time", "elapsed", "distance", "strokes", "paused"]
+            .iter()
+            .map(|key| workout_data_column(key).unwrap().header)
+            .collect();
+        let rows = "1,1.0,4,0,0\n2,2.0,8,1,0\n3,3.0,12,1,0\n4,4.0,1";
+        let data = format!("{}\n{}", header.join(","), rows);
+        fs::write(workout_dir.join(WORKOUT_DATA_FILE), &data).unwrap();
+
+        let (datapoints, damaged) = read_workout_data_file(&workout_dir).unwrap();
+        assert_eq!(datapoints.len(), 3);
+        assert!(matches!(damaged, Some(WaterRowerError::FileRead(_))));
+        let gwv = global_workout_values_recover(&workout_dir, &datapoints).unwrap();
+        assert_eq!(gwv.date_time_start, "2024-05-01 08:15:00");
+        assert_eq!(gwv.date_time_end, "2024-05-01 08:15:03");
+        assert_eq!(gwv.datapoints, 3);
+        assert_eq!(gwv.total_distance_in_meters, 12);
+        assert_eq!(gwv.total_stroke_count, 1);
+        assert_eq!(gwv.finish_reason, "");
+
+        // A value that does not parse damages its row like a cut off one
+        let data = data.replace("3,3.0,12", "3,3.0,1?");
+        fs::write(workout_dir.join(WORKOUT_DATA_FILE), data).unwrap();
+        let (datapoints, damaged) = read_workout_data_file(&workout_dir).unwrap();
+        assert_eq!(datapoints.len(), 2);
+        let damaged = damaged.unwrap().to_string();
+        assert!(
+            damaged.contains("Distance in Meters '1?' in line 4"),
+            "{}",
+            damaged
+        );
     }
-    csv_writer.flush()?;
-    Ok(())
 }